@@ -0,0 +1,553 @@
+pub use self::resampler::{Resampler, APU_CLOCK_RATE};
+
+use self::{noise::NoiseChannel, square::SquareChannel, wave::WaveChannel};
+
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+use crate::Model;
+
+mod noise;
+mod resampler;
+mod square;
+mod wave;
+
+pub struct Apu {
+    cgb: bool,
+    enabled: bool,
+    ch1: SquareChannel,
+    ch2: SquareChannel,
+    ch3: WaveChannel,
+    ch4: NoiseChannel,
+    nr50: u8,
+    nr51: u8,
+    frame_sequencer: u8,
+    div_bit: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Amplitudes {
+    pub channels: [Option<u8>; 4],
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self {
+            cgb: false,
+            enabled: false,
+            ch1: SquareChannel::with_sweep(),
+            ch2: SquareChannel::default(),
+            ch3: WaveChannel::default(),
+            ch4: NoiseChannel::default(),
+            nr50: 0x00,
+            nr51: 0x00,
+            frame_sequencer: 0,
+            div_bit: false,
+        }
+    }
+}
+
+impl Apu {
+    pub fn new(model: Model) -> Self {
+        Self {
+            cgb: model.is_cgb(),
+            ..Default::default()
+        }
+    }
+
+    pub fn step(&mut self, div_bit: bool) -> Amplitudes {
+        if self.enabled {
+            if self.div_bit && !div_bit {
+                self.clock_frame_sequencer()
+            }
+            self.ch1.step();
+            self.ch2.step();
+            self.ch3.step();
+            self.ch4.step();
+        }
+        self.div_bit = div_bit;
+        Amplitudes {
+            channels: [
+                self.ch1.output(),
+                self.ch2.output(),
+                self.ch3.output(),
+                self.ch4.output(),
+            ],
+        }
+    }
+
+    pub fn nr50(&self) -> u8 {
+        self.nr50
+    }
+
+    pub fn nr51(&self) -> u8 {
+        self.nr51
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xff10..=0xff14 => self.ch1.read(addr - 0xff10),
+            0xff15..=0xff19 => self.ch2.read(addr - 0xff15),
+            0xff1a..=0xff1e => self.ch3.read(addr - 0xff1a),
+            0xff1f..=0xff23 => self.ch4.read(addr - 0xff1f),
+            0xff24 => self.nr50,
+            0xff25 => self.nr51,
+            0xff26 => self.nr52(),
+            0xff30..=0xff3f => self.ch3.read_wave_ram(addr - 0xff30),
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xff26 => self.write_nr52(data),
+            0xff30..=0xff3f => self.ch3.write_wave_ram(addr - 0xff30, data),
+            // Pre-CGB models keep the length counters writable while powered off.
+            0xff11 if !self.enabled && !self.cgb => self.ch1.load_length(data),
+            0xff16 if !self.enabled && !self.cgb => self.ch2.load_length(data),
+            0xff1b if !self.enabled && !self.cgb => self.ch3.load_length(data),
+            0xff20 if !self.enabled && !self.cgb => self.ch4.load_length(data),
+            _ if !self.enabled => (),
+            0xff10..=0xff14 => self.ch1.write(addr - 0xff10, data),
+            0xff15..=0xff19 => self.ch2.write(addr - 0xff15, data),
+            0xff1a..=0xff1e => self.ch3.write(addr - 0xff1a, data),
+            0xff1f..=0xff23 => self.ch4.write(addr - 0xff1f, data),
+            0xff24 => self.nr50 = data,
+            0xff25 => self.nr51 = data,
+            _ => (),
+        }
+    }
+
+    // Like `write`, but reaches registers while powered off and never powers the
+    // APU on or off, triggers a channel or disables one.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xff26 => self.enabled = data & 0x80 != 0,
+            0xff30..=0xff3f => self.ch3.write_wave_ram(addr - 0xff30, data),
+            0xff10..=0xff14 => self.ch1.poke(addr - 0xff10, data),
+            0xff15..=0xff19 => self.ch2.poke(addr - 0xff15, data),
+            0xff1a..=0xff1e => self.ch3.poke(addr - 0xff1a, data),
+            0xff1f..=0xff23 => self.ch4.poke(addr - 0xff1f, data),
+            0xff24 => self.nr50 = data,
+            0xff25 => self.nr51 = data,
+            _ => (),
+        }
+    }
+
+    fn nr52(&self) -> u8 {
+        (if self.enabled { 0x80 } else { 0x00 })
+            | 0x70
+            | if self.ch4.enabled { 0x08 } else { 0x00 }
+            | if self.ch3.enabled { 0x04 } else { 0x00 }
+            | if self.ch2.enabled { 0x02 } else { 0x00 }
+            | if self.ch1.enabled { 0x01 } else { 0x00 }
+    }
+
+    fn write_nr52(&mut self, data: u8) {
+        let enabled = data & 0x80 != 0;
+        if self.enabled && !enabled {
+            let mut apu = Self {
+                cgb: self.cgb,
+                div_bit: self.div_bit,
+                ..Default::default()
+            };
+            apu.ch3.wave_ram = self.ch3.wave_ram;
+            if !self.cgb {
+                apu.ch1.length.counter = self.ch1.length.counter;
+                apu.ch2.length.counter = self.ch2.length.counter;
+                apu.ch3.length.counter = self.ch3.length.counter;
+                apu.ch4.length.counter = self.ch4.length.counter
+            }
+            *self = apu
+        } else if !self.enabled && enabled {
+            self.frame_sequencer = 0
+        }
+        self.enabled = enabled
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        if self.frame_sequencer % 2 == 0 {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+        if self.frame_sequencer % 4 == 2 {
+            self.ch1.clock_sweep()
+        }
+        if self.frame_sequencer == 7 {
+            self.ch1.clock_envelope();
+            self.ch2.clock_envelope();
+            self.ch4.clock_envelope();
+        }
+        self.frame_sequencer = (self.frame_sequencer + 1) % 8
+    }
+}
+
+#[derive(Default)]
+struct LengthCounter {
+    enabled: bool,
+    counter: u16,
+}
+
+impl LengthCounter {
+    fn load(&mut self, max: u16, data: u8) {
+        self.counter = max - u16::from(data)
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max
+        }
+    }
+
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Default)]
+struct Envelope {
+    initial: u8,
+    add: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn read(&self) -> u8 {
+        self.initial << 4 | if self.add { 0x08 } else { 0x00 } | self.period
+    }
+
+    fn write(&mut self, data: u8) {
+        self.initial = data >> 4;
+        self.add = data & 0x08 != 0;
+        self.period = data & 0x07
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.add
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.add && self.volume < 0x0f {
+                self.volume += 1
+            } else if !self.add && self.volume > 0x00 {
+                self.volume -= 1
+            }
+        }
+    }
+}
+
+impl SaveState for Apu {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        self.ch1.save(writer);
+        self.ch2.save(writer);
+        self.ch3.save(writer);
+        self.ch4.save(writer);
+        writer.u8(self.nr50);
+        writer.u8(self.nr51);
+        writer.u8(self.frame_sequencer);
+        writer.bool(self.div_bit)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.ch1.load(reader)?;
+        self.ch2.load(reader)?;
+        self.ch3.load(reader)?;
+        self.ch4.load(reader)?;
+        self.nr50 = reader.u8()?;
+        self.nr51 = reader.u8()?;
+        self.frame_sequencer = reader.u8()?;
+        self.div_bit = reader.bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.u16(self.counter)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.counter = reader.u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Envelope {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.initial);
+        writer.bool(self.add);
+        writer.u8(self.period);
+        writer.u8(self.volume);
+        writer.u8(self.timer)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.initial = reader.u8()?;
+        self.add = reader.bool()?;
+        self.period = reader.u8()?;
+        self.volume = reader.u8()?;
+        self.timer = reader.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Apu {
+        fn powered_on() -> Self {
+            let mut apu = Apu::default();
+            apu.write(0xff26, 0x80);
+            apu
+        }
+
+        fn clock_frame_sequencer_steps(&mut self, steps: usize) {
+            for _ in 0..steps {
+                self.step(true);
+                self.step(false);
+            }
+        }
+    }
+
+    fn load_patched(
+        component: &mut impl SaveState,
+        offset: usize,
+        patch: &[u8],
+    ) -> Result<(), StateError> {
+        let mut writer = StateWriter::default();
+        component.save(&mut writer);
+        let mut state = writer.into_bytes();
+        state[offset..offset + patch.len()].copy_from_slice(patch);
+        component.load(&mut StateReader::new(&state))
+    }
+
+    #[test]
+    fn registers_are_read_only_while_powered_off() {
+        let mut apu = Apu::default();
+        apu.write(0xff24, 0x77);
+        assert_eq!(apu.read(0xff24), 0x00)
+    }
+
+    #[test]
+    fn wave_ram_is_writable_while_powered_off() {
+        let mut apu = Apu::default();
+        apu.write(0xff30, 0x12);
+        assert_eq!(apu.read(0xff30), 0x12)
+    }
+
+    #[test]
+    fn powering_off_clears_registers() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff25, 0xf3);
+        apu.write(0xff26, 0x00);
+        apu.write(0xff26, 0x80);
+        assert_eq!(apu.read(0xff25), 0x00)
+    }
+
+    #[test]
+    fn only_pre_cgb_models_keep_length_counters_while_powered_off() {
+        for &(model, ch1, ch4) in &[(Model::Dmg, 0x30, 0x01), (Model::Cgb, 0x00, 0x00)] {
+            let mut apu = Apu::new(model);
+            apu.write(0xff26, 0x80);
+            apu.write(0xff11, 0x10);
+            apu.write(0xff26, 0x00);
+            assert_eq!(apu.ch1.length.counter, ch1);
+            apu.write(0xff20, 0x3f);
+            assert_eq!(apu.ch4.length.counter, ch4);
+            assert_eq!(apu.read(0xff11), 0x3f)
+        }
+    }
+
+    #[test]
+    fn unused_bits_read_as_set() {
+        let apu = Apu::powered_on();
+        assert_eq!(apu.read(0xff10), 0x80);
+        assert_eq!(apu.read(0xff11), 0x3f);
+        assert_eq!(apu.read(0xff13), 0xff);
+        assert_eq!(apu.read(0xff1a), 0x7f);
+        assert_eq!(apu.read(0xff1c), 0x9f);
+        assert_eq!(apu.read(0xff26), 0xf0)
+    }
+
+    #[test]
+    fn trigger_enables_channel_with_dac_on() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0x80);
+        assert_eq!(apu.read(0xff26), 0xf2)
+    }
+
+    #[test]
+    fn trigger_does_not_enable_channel_with_dac_off() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff17, 0x00);
+        apu.write(0xff19, 0x80);
+        assert_eq!(apu.read(0xff26), 0xf0)
+    }
+
+    #[test]
+    fn length_counter_disables_channel() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff16, 0x3e);
+        apu.write(0xff19, 0xc0);
+        apu.clock_frame_sequencer_steps(2);
+        assert_eq!(apu.read(0xff26), 0xf2);
+        apu.clock_frame_sequencer_steps(1);
+        assert_eq!(apu.read(0xff26), 0xf0)
+    }
+
+    #[test]
+    fn square_channel_outputs_duty_cycle() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff16, 0x80);
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff18, 0xff);
+        apu.write(0xff19, 0x87);
+        let waveform: Vec<_> = (0..16).map(|_| apu.step(false).channels[1]).collect();
+        assert_eq!(
+            waveform,
+            [
+                0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x0f, 0x0f, 0x0f, 0x0f,
+                0x0f, 0x0f
+            ]
+            .iter()
+            .map(|&amplitude| Some(amplitude))
+            .collect::<Vec<_>>()
+        )
+    }
+
+    #[test]
+    fn envelope_decreases_volume() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff16, 0xc0);
+        apu.write(0xff17, 0xf1);
+        apu.write(0xff19, 0x80);
+        apu.clock_frame_sequencer_steps(8);
+        assert_eq!(apu.ch2.envelope.volume, 0x0e)
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel_1() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff10, 0x11);
+        apu.write(0xff13, 0xff);
+        apu.write(0xff14, 0x87);
+        assert_eq!(apu.read(0xff26), 0xf0)
+    }
+
+    #[test]
+    fn sweep_updates_frequency() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff12, 0xf0);
+        apu.write(0xff10, 0x11);
+        apu.write(0xff13, 0x00);
+        apu.write(0xff14, 0x82);
+        apu.clock_frame_sequencer_steps(3);
+        assert_eq!(apu.ch1.frequency, 0x0300);
+        assert_eq!(apu.read(0xff26), 0xf1)
+    }
+
+    #[test]
+    fn wave_channel_plays_wave_ram() {
+        let mut apu = Apu::powered_on();
+        for (i, addr) in (0xff30..=0xff3f).enumerate() {
+            apu.write(addr, (2 * i as u8) << 4 | (2 * i as u8 + 1));
+        }
+        apu.write(0xff1a, 0x80);
+        apu.write(0xff1c, 0x20);
+        apu.write(0xff1d, 0xff);
+        apu.write(0xff1e, 0x87);
+        let waveform: Vec<_> = (0..4).map(|_| apu.step(false).channels[2]).collect();
+        assert_eq!(waveform, [Some(1), Some(2), Some(3), Some(4)])
+    }
+
+    #[test]
+    fn noise_channel_in_7_bit_mode_repeats_every_127_clocks() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff21, 0xf0);
+        apu.write(0xff22, 0x08);
+        apu.write(0xff23, 0x80);
+        let waveform: Vec<_> = (0..4 * 254).map(|_| apu.step(false).channels[3]).collect();
+        assert_eq!(waveform[..4 * 127], waveform[4 * 127..])
+    }
+
+    #[test]
+    fn dac_off_channel_outputs_nothing() {
+        let mut apu = Apu::powered_on();
+        assert_eq!(apu.step(false).channels, [None; 4])
+    }
+
+    #[test]
+    fn loading_out_of_range_square_state_fails() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff17, 0xf0);
+        apu.write(0xff19, 0x80);
+        assert_eq!(
+            load_patched(&mut apu.ch2, 1, &[0x00, 0x08]),
+            Err(StateError::InvalidValue("square frequency", 0x0800))
+        );
+        assert_eq!(
+            load_patched(&mut apu.ch2, 12, &[0x00, 0x00]),
+            Err(StateError::InvalidValue("square timer", 0))
+        )
+    }
+
+    #[test]
+    fn loading_out_of_range_wave_state_fails() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff1a, 0x80);
+        apu.write(0xff1e, 0x80);
+        assert_eq!(
+            load_patched(&mut apu.ch3, 21, &[0x09]),
+            Err(StateError::InvalidValue("wave volume", 0x09))
+        );
+        assert_eq!(
+            load_patched(&mut apu.ch3, 22, &[0xff, 0xff]),
+            Err(StateError::InvalidValue("wave frequency", 0xffff))
+        );
+        assert_eq!(
+            load_patched(&mut apu.ch3, 24, &[0x00, 0x00]),
+            Err(StateError::InvalidValue("wave timer", 0))
+        )
+    }
+
+    #[test]
+    fn loading_out_of_range_noise_state_fails() {
+        let mut apu = Apu::powered_on();
+        apu.write(0xff21, 0xf0);
+        apu.write(0xff23, 0x80);
+        assert_eq!(
+            load_patched(&mut apu.ch4, 9, &[0x20]),
+            Err(StateError::InvalidValue("noise shift", 0x20))
+        );
+        assert_eq!(
+            load_patched(&mut apu.ch4, 12, &[0x00; 4]),
+            Err(StateError::InvalidValue("noise timer", 0))
+        )
+    }
+}
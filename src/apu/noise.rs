@@ -0,0 +1,148 @@
+use super::{Envelope, LengthCounter};
+
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const LENGTH: u16 = 64;
+
+#[derive(Default)]
+pub(super) struct NoiseChannel {
+    pub(super) enabled: bool,
+    envelope: Envelope,
+    pub(super) length: LengthCounter,
+    shift: u8,
+    narrow: bool,
+    divisor_code: u8,
+    timer: u32,
+    lfsr: u16,
+}
+
+impl NoiseChannel {
+    pub(super) fn read(&self, reg: u16) -> u8 {
+        match reg {
+            2 => self.envelope.read(),
+            3 => self.shift << 4 | if self.narrow { 0x08 } else { 0x00 } | self.divisor_code,
+            4 => 0xbf | if self.length.enabled { 0x40 } else { 0x00 },
+            _ => 0xff,
+        }
+    }
+
+    pub(super) fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            1 => self.load_length(data),
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false
+                }
+            }
+            3 => {
+                self.shift = data >> 4;
+                self.narrow = data & 0x08 != 0;
+                self.divisor_code = data & 0x07
+            }
+            4 => {
+                self.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger()
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub(super) fn poke(&mut self, reg: u16, data: u8) {
+        match reg {
+            2 => self.envelope.write(data),
+            4 => self.length.enabled = data & 0x40 != 0,
+            _ => self.write(reg, data),
+        }
+    }
+
+    pub(super) fn load_length(&mut self, data: u8) {
+        self.length.load(LENGTH, data & 0x3f)
+    }
+
+    pub(super) fn step(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period();
+            if self.shift < 14 {
+                let feedback = (self.lfsr ^ self.lfsr >> 1) & 0x0001;
+                self.lfsr = self.lfsr >> 1 | feedback << 14;
+                if self.narrow {
+                    self.lfsr = self.lfsr & !0x0040 | feedback << 6
+                }
+            }
+        }
+    }
+
+    pub(super) fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        Some(if self.enabled && self.lfsr & 0x0001 == 0 {
+            self.envelope.volume
+        } else {
+            0x00
+        })
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        if self.enabled {
+            self.envelope.clock()
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(LENGTH);
+        self.envelope.trigger();
+        self.timer = self.period();
+        self.lfsr = 0x7fff
+    }
+
+    fn period(&self) -> u32 {
+        let divisor = match self.divisor_code {
+            0 => 4,
+            code => 8 * u32::from(code),
+        };
+        divisor << self.shift
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        self.envelope.save(writer);
+        self.length.save(writer);
+        writer.u8(self.shift);
+        writer.bool(self.narrow);
+        writer.u8(self.divisor_code);
+        writer.u32(self.timer);
+        writer.u16(self.lfsr)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.envelope.load(reader)?;
+        SaveState::load(&mut self.length, reader)?;
+        self.shift = reader.u8_below("noise shift", 16)?;
+        self.narrow = reader.bool()?;
+        self.divisor_code = reader.u8_below("noise divisor", 8)?;
+        self.timer = reader.u32()?;
+        if self.enabled && self.timer == 0 {
+            return Err(StateError::InvalidValue("noise timer", 0));
+        }
+        self.lfsr = reader.u16()?;
+        Ok(())
+    }
+}
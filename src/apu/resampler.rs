@@ -0,0 +1,230 @@
+use super::Amplitudes;
+
+use std::f64::consts::PI;
+
+pub const APU_CLOCK_RATE: u32 = 2_097_152;
+
+const KERNEL_WIDTH: usize = 16;
+const KERNEL_PHASES: usize = 64;
+const CUTOFF: f64 = 0.45;
+const CHANNEL_SCALE: f32 = 8192.0;
+
+pub struct Resampler {
+    sample_rate: u64,
+    kernel: Vec<[f32; KERNEL_WIDTH]>,
+    time: u64,
+    level: [f32; 2],
+    deltas: [Vec<f32>; 2],
+    integrator: [f32; 2],
+    capacitor: [f32; 2],
+    charge_factor: f32,
+}
+
+impl Resampler {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate.into(),
+            kernel: (0..KERNEL_PHASES).map(kernel_phase).collect(),
+            time: 0,
+            level: [0.0; 2],
+            deltas: [vec![0.0; KERNEL_WIDTH], vec![0.0; KERNEL_WIDTH]],
+            integrator: [0.0; 2],
+            capacitor: [0.0; 2],
+            charge_factor: 0.999958f32.powf(4_194_304.0 / sample_rate as f32),
+        }
+    }
+
+    pub fn push(&mut self, amplitudes: &Amplitudes, nr50: u8, nr51: u8) {
+        let level = mix(amplitudes, nr50, nr51);
+        for (side, &level) in level.iter().enumerate() {
+            let delta = level - self.level[side];
+            if delta != 0.0 {
+                self.add_delta(side, delta);
+                self.level[side] = level
+            }
+        }
+        self.time += self.sample_rate;
+        let len = self.available() + KERNEL_WIDTH;
+        for deltas in &mut self.deltas {
+            if deltas.len() < len {
+                deltas.resize(len, 0.0)
+            }
+        }
+    }
+
+    pub fn available(&self) -> usize {
+        (self.time / u64::from(APU_CLOCK_RATE)) as usize
+    }
+
+    pub fn fill_samples(&mut self, samples: &mut [i16]) -> usize {
+        let frames = self.available().min(samples.len() / 2);
+        for (i, frame) in samples[..2 * frames].chunks_exact_mut(2).enumerate() {
+            for (side, sample) in frame.iter_mut().enumerate() {
+                self.integrator[side] += self.deltas[side][i];
+                let output = self.integrator[side] - self.capacitor[side];
+                self.capacitor[side] = self.integrator[side] - output * self.charge_factor;
+                *sample = output.round().max(i16::MIN.into()).min(i16::MAX.into()) as i16
+            }
+        }
+        for deltas in &mut self.deltas {
+            deltas.drain(..frames);
+        }
+        self.time -= frames as u64 * u64::from(APU_CLOCK_RATE);
+        2 * frames
+    }
+
+    fn add_delta(&mut self, side: usize, delta: f32) {
+        let index = self.available();
+        let fraction = self.time % u64::from(APU_CLOCK_RATE);
+        let phase = (fraction * KERNEL_PHASES as u64 / u64::from(APU_CLOCK_RATE)) as usize;
+        let deltas = &mut self.deltas[side][index..index + KERNEL_WIDTH];
+        for (sample, tap) in deltas.iter_mut().zip(self.kernel[phase].iter()) {
+            *sample += delta * tap
+        }
+    }
+}
+
+fn mix(amplitudes: &Amplitudes, nr50: u8, nr51: u8) -> [f32; 2] {
+    let mut level = [0.0; 2];
+    for (n, amplitude) in amplitudes.channels.iter().enumerate() {
+        let analog = amplitude.map_or(0.0, |digital| f32::from(digital) / 7.5 - 1.0);
+        if nr51 & 0x10 << n != 0 {
+            level[0] += analog
+        }
+        if nr51 & 0x01 << n != 0 {
+            level[1] += analog
+        }
+    }
+    let volume = [(nr50 >> 4) & 0x07, nr50 & 0x07];
+    for (level, volume) in level.iter_mut().zip(volume.iter()) {
+        *level *= CHANNEL_SCALE * f32::from(volume + 1) / 8.0
+    }
+    level
+}
+
+fn kernel_phase(phase: usize) -> [f32; KERNEL_WIDTH] {
+    let half = (KERNEL_WIDTH / 2) as f64;
+    let offset = phase as f64 / KERNEL_PHASES as f64;
+    let mut taps = [0.0; KERNEL_WIDTH];
+    for (k, tap) in taps.iter_mut().enumerate() {
+        let x = k as f64 - offset - half;
+        let sinc = if x == 0.0 {
+            2.0 * CUTOFF
+        } else {
+            (2.0 * PI * CUTOFF * x).sin() / (PI * x)
+        };
+        let window = 0.42 + 0.5 * (PI * x / half).cos() + 0.08 * (2.0 * PI * x / half).cos();
+        *tap = sinc * window.max(0.0)
+    }
+    let sum: f64 = taps.iter().sum();
+    let mut kernel = [0.0; KERNEL_WIDTH];
+    for (dest, tap) in kernel.iter_mut().zip(taps.iter()) {
+        *dest = (tap / sum) as f32
+    }
+    kernel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SQUARE_WAVE_PERIOD: u32 = 4096;
+
+    fn square_wave(cycle: u32) -> Amplitudes {
+        let digital = if cycle % SQUARE_WAVE_PERIOD < SQUARE_WAVE_PERIOD / 2 {
+            0x0f
+        } else {
+            0x00
+        };
+        Amplitudes {
+            channels: [Some(digital), None, None, None],
+        }
+    }
+
+    fn render(resampler: &mut Resampler, cycles: u32, nr50: u8, nr51: u8) -> Vec<i16> {
+        let mut samples = Vec::new();
+        let mut buffer = [0; 256];
+        for cycle in 0..cycles {
+            resampler.push(&square_wave(cycle), nr50, nr51);
+            let len = resampler.fill_samples(&mut buffer);
+            samples.extend_from_slice(&buffer[..len])
+        }
+        samples
+    }
+
+    fn peak(samples: &[i16], side: usize) -> i16 {
+        samples
+            .chunks(2)
+            .map(|frame| frame[side].saturating_abs())
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn silence_produces_zero_samples() {
+        let mut resampler = Resampler::new(48_000);
+        for _ in 0..10_000 {
+            resampler.push(&Amplitudes::default(), 0x77, 0xff);
+        }
+        let mut samples = [1; 64];
+        let len = resampler.fill_samples(&mut samples);
+        assert!(len > 0);
+        assert!(samples[..len].iter().all(|&sample| sample == 0))
+    }
+
+    #[test]
+    fn output_rate_matches_configured_sample_rate() {
+        let mut resampler = Resampler::new(44_100);
+        for _ in 0..APU_CLOCK_RATE {
+            resampler.push(&Amplitudes::default(), 0x77, 0xff);
+        }
+        let mut samples = vec![0; 100_000];
+        assert_eq!(resampler.fill_samples(&mut samples), 2 * 44_100)
+    }
+
+    #[test]
+    fn fill_samples_is_limited_by_buffer_size() {
+        let mut resampler = Resampler::new(48_000);
+        for _ in 0..10_000 {
+            resampler.push(&Amplitudes::default(), 0x77, 0xff);
+        }
+        let mut samples = [0; 8];
+        assert_eq!(resampler.fill_samples(&mut samples), 8);
+        assert_eq!(
+            resampler.available(),
+            10_000 * 48_000 / APU_CLOCK_RATE as usize - 4
+        )
+    }
+
+    #[test]
+    fn channel_panned_left_is_silent_on_right() {
+        let mut resampler = Resampler::new(48_000);
+        let samples = render(&mut resampler, 50_000, 0x77, 0x10);
+        assert!(peak(&samples, 0) > 10_000);
+        assert_eq!(peak(&samples, 1), 0)
+    }
+
+    #[test]
+    fn master_volume_scales_output() {
+        let mut resampler = Resampler::new(48_000);
+        let samples = render(&mut resampler, 50_000, 0x70, 0x11);
+        let (left, right) = (i32::from(peak(&samples, 0)), i32::from(peak(&samples, 1)));
+        assert!(left > 10_000);
+        assert!((left - 8 * right).abs() < 16)
+    }
+
+    #[test]
+    fn high_pass_filter_removes_dc_offset() {
+        let mut resampler = Resampler::new(48_000);
+        let constant = Amplitudes {
+            channels: [Some(0x0f), None, None, None],
+        };
+        for _ in 0..APU_CLOCK_RATE {
+            resampler.push(&constant, 0x77, 0x11);
+        }
+        let mut samples = vec![0; 2 * 48_000];
+        let len = resampler.fill_samples(&mut samples);
+        assert!(samples[2 * KERNEL_WIDTH] > 7_500);
+        assert!(samples[len - 2].abs() < 10)
+    }
+}
@@ -0,0 +1,272 @@
+use super::{Envelope, LengthCounter};
+
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const LENGTH: u16 = 64;
+
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+#[derive(Default)]
+pub(super) struct SquareChannel {
+    pub(super) enabled: bool,
+    pub(super) frequency: u16,
+    pub(super) envelope: Envelope,
+    sweep: Option<Sweep>,
+    duty: u8,
+    pub(super) length: LengthCounter,
+    timer: u16,
+    position: u8,
+}
+
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    enabled: bool,
+    shadow: u16,
+    timer: u8,
+    negated: bool,
+}
+
+impl SquareChannel {
+    pub(super) fn with_sweep() -> Self {
+        Self {
+            sweep: Some(Default::default()),
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => self.sweep.as_ref().map_or(0xff, |sweep| sweep.read()),
+            1 => self.duty << 6 | 0x3f,
+            2 => self.envelope.read(),
+            4 => 0xbf | if self.length.enabled { 0x40 } else { 0x00 },
+            _ => 0xff,
+        }
+    }
+
+    pub(super) fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    if sweep.write(data) {
+                        self.enabled = false
+                    }
+                }
+            }
+            1 => {
+                self.duty = data >> 6;
+                self.load_length(data)
+            }
+            2 => {
+                self.envelope.write(data);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false
+                }
+            }
+            3 => self.frequency = self.frequency & 0x0700 | u16::from(data),
+            4 => {
+                self.frequency = self.frequency & 0x00ff | u16::from(data & 0x07) << 8;
+                self.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger()
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub(super) fn poke(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.write(data);
+                }
+            }
+            2 => self.envelope.write(data),
+            4 => {
+                self.frequency = self.frequency & 0x00ff | u16::from(data & 0x07) << 8;
+                self.length.enabled = data & 0x40 != 0
+            }
+            _ => self.write(reg, data),
+        }
+    }
+
+    pub(super) fn load_length(&mut self, data: u8) {
+        self.length.load(LENGTH, data & 0x3f)
+    }
+
+    pub(super) fn step(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) % 8
+        }
+    }
+
+    pub(super) fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        let high = DUTY_PATTERNS[self.duty as usize] << self.position & 0x80 != 0;
+        Some(if self.enabled && high {
+            self.envelope.volume
+        } else {
+            0x00
+        })
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        if self.enabled {
+            self.envelope.clock()
+        }
+    }
+
+    pub(super) fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.timer = sweep.reload_value();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        match sweep.calculate() {
+            Some(frequency) if sweep.shift != 0 => {
+                sweep.shadow = frequency;
+                self.frequency = frequency;
+                if sweep.calculate().is_none() {
+                    self.enabled = false
+                }
+            }
+            Some(_) => (),
+            None => self.enabled = false,
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger(LENGTH);
+        self.timer = self.period();
+        self.envelope.trigger();
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.frequency;
+            sweep.timer = sweep.reload_value();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            sweep.negated = false;
+            if sweep.shift != 0 && sweep.calculate().is_none() {
+                self.enabled = false
+            }
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+}
+
+impl Sweep {
+    fn read(&self) -> u8 {
+        0x80 | self.period << 4 | if self.negate { 0x08 } else { 0x00 } | self.shift
+    }
+
+    fn write(&mut self, data: u8) -> bool {
+        self.period = (data >> 4) & 0x07;
+        self.negate = data & 0x08 != 0;
+        self.shift = data & 0x07;
+        self.negated && !self.negate
+    }
+
+    fn reload_value(&self) -> u8 {
+        if self.period == 0 {
+            8
+        } else {
+            self.period
+        }
+    }
+
+    fn calculate(&mut self) -> Option<u16> {
+        let delta = self.shadow >> self.shift;
+        let frequency = if self.negate {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        };
+        if frequency <= 0x07ff {
+            Some(frequency)
+        } else {
+            None
+        }
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.u16(self.frequency);
+        self.envelope.save(writer);
+        if let Some(sweep) = &self.sweep {
+            sweep.save(writer)
+        }
+        writer.u8(self.duty);
+        self.length.save(writer);
+        writer.u16(self.timer);
+        writer.u8(self.position)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        self.frequency = reader.u16_below("square frequency", 0x800)?;
+        self.envelope.load(reader)?;
+        if let Some(sweep) = &mut self.sweep {
+            sweep.load(reader)?
+        }
+        self.duty = reader.u8_below("duty", DUTY_PATTERNS.len() as u8)?;
+        SaveState::load(&mut self.length, reader)?;
+        self.timer = reader.u16()?;
+        if self.enabled && self.timer == 0 {
+            return Err(StateError::InvalidValue("square timer", 0));
+        }
+        self.position = reader.u8_below("duty position", 8)?;
+        Ok(())
+    }
+}
+
+impl SaveState for Sweep {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.period);
+        writer.bool(self.negate);
+        writer.u8(self.shift);
+        writer.bool(self.enabled);
+        writer.u16(self.shadow);
+        writer.u8(self.timer);
+        writer.bool(self.negated)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.period = reader.u8()?;
+        self.negate = reader.bool()?;
+        self.shift = reader.u8_below("sweep shift", 8)?;
+        self.enabled = reader.bool()?;
+        self.shadow = reader.u16()?;
+        self.timer = reader.u8()?;
+        self.negated = reader.bool()?;
+        Ok(())
+    }
+}
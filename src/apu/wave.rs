@@ -0,0 +1,148 @@
+use super::LengthCounter;
+
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+const LENGTH: u16 = 256;
+
+#[derive(Default)]
+pub(super) struct WaveChannel {
+    pub(super) enabled: bool,
+    pub(super) wave_ram: [u8; 16],
+    dac_enabled: bool,
+    pub(super) length: LengthCounter,
+    volume_code: u8,
+    frequency: u16,
+    timer: u16,
+    position: u8,
+    sample: u8,
+}
+
+impl WaveChannel {
+    pub(super) fn read(&self, reg: u16) -> u8 {
+        match reg {
+            0 => 0x7f | if self.dac_enabled { 0x80 } else { 0x00 },
+            2 => self.volume_code << 5 | 0x9f,
+            4 => 0xbf | if self.length.enabled { 0x40 } else { 0x00 },
+            _ => 0xff,
+        }
+    }
+
+    pub(super) fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = data & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false
+                }
+            }
+            1 => self.load_length(data),
+            2 => self.volume_code = (data >> 5) & 0x03,
+            3 => self.frequency = self.frequency & 0x0700 | u16::from(data),
+            4 => {
+                self.frequency = self.frequency & 0x00ff | u16::from(data & 0x07) << 8;
+                self.length.enabled = data & 0x40 != 0;
+                if data & 0x80 != 0 {
+                    self.trigger()
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub(super) fn poke(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => self.dac_enabled = data & 0x80 != 0,
+            4 => {
+                self.frequency = self.frequency & 0x00ff | u16::from(data & 0x07) << 8;
+                self.length.enabled = data & 0x40 != 0
+            }
+            _ => self.write(reg, data),
+        }
+    }
+
+    pub(super) fn load_length(&mut self, data: u8) {
+        self.length.load(LENGTH, data)
+    }
+
+    pub(super) fn read_wave_ram(&self, index: u16) -> u8 {
+        self.wave_ram[index as usize]
+    }
+
+    pub(super) fn write_wave_ram(&mut self, index: u16, data: u8) {
+        self.wave_ram[index as usize] = data
+    }
+
+    pub(super) fn step(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        self.timer -= 1;
+        if self.timer == 0 {
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+            let byte = self.wave_ram[self.position as usize / 2];
+            self.sample = if self.position % 2 == 0 {
+                byte >> 4
+            } else {
+                byte & 0x0f
+            }
+        }
+    }
+
+    pub(super) fn output(&self) -> Option<u8> {
+        if !self.dac_enabled {
+            return None;
+        }
+        Some(match self.volume_code {
+            0 => 0x00,
+            code => self.sample >> (code - 1),
+        })
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.length.trigger(LENGTH);
+        self.timer = self.period();
+        self.position = 0
+    }
+
+    fn period(&self) -> u16 {
+        2048 - self.frequency
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bytes(&self.wave_ram);
+        writer.bool(self.dac_enabled);
+        self.length.save(writer);
+        writer.u8(self.volume_code);
+        writer.u16(self.frequency);
+        writer.u16(self.timer);
+        writer.u8(self.position);
+        writer.u8(self.sample)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.bool()?;
+        reader.fill(&mut self.wave_ram)?;
+        self.dac_enabled = reader.bool()?;
+        SaveState::load(&mut self.length, reader)?;
+        self.volume_code = reader.u8_below("wave volume", 4)?;
+        self.frequency = reader.u16_below("wave frequency", 0x800)?;
+        self.timer = reader.u16()?;
+        if self.enabled && self.timer == 0 {
+            return Err(StateError::InvalidValue("wave timer", 0));
+        }
+        self.position = reader.u8_below("wave position", 32)?;
+        self.sample = reader.u8()?;
+        Ok(())
+    }
+}
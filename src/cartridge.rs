@@ -0,0 +1,477 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+use std::fmt;
+
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+const RTC_REGISTERS: usize = 5;
+
+const TITLE: usize = 0x0134;
+const CGB_FLAG: usize = 0x0143;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const HEADER_CHECKSUM: usize = 0x014d;
+const GLOBAL_CHECKSUM: usize = 0x014e;
+const HEADER_END: usize = 0x0150;
+
+pub struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: Mbc,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    advanced_banking: bool,
+    rtc: [u8; RTC_REGISTERS],
+    revision: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mbc {
+    None,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CartridgeError {
+    TooSmall(usize),
+    UnsupportedType(u8),
+    UnsupportedRamSize(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::TooSmall(len) => write!(f, "ROM is too small ({} bytes)", len),
+            CartridgeError::UnsupportedType(code) => {
+                write!(f, "unsupported cartridge type {:#04x}", code)
+            }
+            CartridgeError::UnsupportedRamSize(code) => {
+                write!(f, "unsupported RAM size {:#04x}", code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl Cartridge {
+    pub fn new(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+        let mbc = match rom[CARTRIDGE_TYPE] {
+            0x00 | 0x08 | 0x09 => Mbc::None,
+            0x01..=0x03 => Mbc::Mbc1,
+            0x05 | 0x06 => Mbc::Mbc2,
+            0x0f..=0x13 => Mbc::Mbc3,
+            0x19..=0x1e => Mbc::Mbc5,
+            code => return Err(CartridgeError::UnsupportedType(code)),
+        };
+        let ram_size = match (mbc, rom[RAM_SIZE]) {
+            (Mbc::Mbc2, _) => 0x200,
+            (_, 0x00) => 0,
+            (_, 0x01) => 0x800,
+            (_, 0x02) => RAM_BANK_SIZE,
+            (_, 0x03) => 4 * RAM_BANK_SIZE,
+            (_, 0x04) => 16 * RAM_BANK_SIZE,
+            (_, 0x05) => 8 * RAM_BANK_SIZE,
+            (_, code) => return Err(CartridgeError::UnsupportedRamSize(code)),
+        };
+        let mut rom = rom;
+        let rom_size = (2 * ROM_BANK_SIZE) << rom[ROM_SIZE].min(8);
+        if rom.len() < rom_size {
+            rom.resize(rom_size, 0xff)
+        }
+        Ok(Self {
+            rom,
+            ram: vec![0x00; ram_size],
+            mbc,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            advanced_banking: false,
+            rtc: [0x00; RTC_REGISTERS],
+            revision: 0,
+        })
+    }
+
+    pub fn title(&self) -> String {
+        let len = if self.supports_cgb() { 15 } else { 16 };
+        self.rom[TITLE..TITLE + len]
+            .iter()
+            .take_while(|&&byte| byte != 0x00)
+            .map(|&byte| byte as char)
+            .collect()
+    }
+
+    pub fn supports_cgb(&self) -> bool {
+        self.rom[CGB_FLAG] & 0x80 != 0
+    }
+
+    pub fn header_checksum(&self) -> u8 {
+        self.rom[HEADER_CHECKSUM]
+    }
+
+    pub fn global_checksum(&self) -> u16 {
+        u16::from_be_bytes([self.rom[GLOBAL_CHECKSUM], self.rom[GLOBAL_CHECKSUM + 1]])
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    // Changes whenever the saved state of the cartridge may have.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    pub fn load_ram(&mut self, ram: &[u8]) {
+        self.revision += 1;
+        let len = ram.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&ram[..len])
+    }
+
+    pub fn bank(&self, addr: u16) -> u16 {
+        let bank = match addr {
+            0x0000..=0x3fff => self.rom_offset(self.low_rom_bank(), addr) / ROM_BANK_SIZE,
+            0x4000..=0x7fff => self.rom_offset(self.high_rom_bank(), addr) / ROM_BANK_SIZE,
+            0xa000..=0xbfff => self
+                .ram_offset(addr)
+                .map_or(0, |offset| offset / RAM_BANK_SIZE),
+            _ => 0,
+        };
+        bank as u16
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3fff => self.rom[self.rom_offset(self.low_rom_bank(), addr)],
+            0x4000..=0x7fff => self.rom[self.rom_offset(self.high_rom_bank(), addr)],
+            0xa000..=0xbfff => match self.rtc_register() {
+                Some(register) if self.ram_enabled => self.rtc[register],
+                _ => self
+                    .ram_offset(addr)
+                    .map_or(0xff, |offset| self.read_ram(offset)),
+            },
+            _ => 0xff,
+        }
+    }
+
+    // Like `read`, but sees cartridge RAM even while it is disabled.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match (addr, self.banked_ram_offset(addr)) {
+            (0xa000..=0xbfff, Some(offset)) => self.read_ram(offset),
+            _ => self.read(addr),
+        }
+    }
+
+    // Writes ROM and RAM contents directly instead of driving the MBC.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        self.revision += 1;
+        match addr {
+            0x0000..=0x3fff => {
+                let offset = self.rom_offset(self.low_rom_bank(), addr);
+                self.rom[offset] = data
+            }
+            0x4000..=0x7fff => {
+                let offset = self.rom_offset(self.high_rom_bank(), addr);
+                self.rom[offset] = data
+            }
+            0xa000..=0xbfff => {
+                if let Some(offset) = self.banked_ram_offset(addr) {
+                    self.ram[offset] = data
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        self.revision += 1;
+        match (self.mbc, addr) {
+            (Mbc::None, 0x0000..=0x7fff) => (),
+            (Mbc::Mbc2, 0x0000..=0x3fff) => {
+                if addr & 0x0100 == 0 {
+                    self.ram_enabled = data & 0x0f == 0x0a
+                } else {
+                    self.rom_bank = u16::from(data & 0x0f).max(1)
+                }
+            }
+            (_, 0x0000..=0x1fff) => self.ram_enabled = data & 0x0f == 0x0a,
+            (Mbc::Mbc1, 0x2000..=0x3fff) => {
+                self.rom_bank = self.rom_bank & 0x60 | u16::from(data & 0x1f).max(1)
+            }
+            (Mbc::Mbc3, 0x2000..=0x3fff) => self.rom_bank = u16::from(data & 0x7f).max(1),
+            (Mbc::Mbc5, 0x2000..=0x2fff) => self.rom_bank = self.rom_bank & 0x100 | u16::from(data),
+            (Mbc::Mbc5, 0x3000..=0x3fff) => {
+                self.rom_bank = self.rom_bank & 0xff | u16::from(data & 0x01) << 8
+            }
+            (Mbc::Mbc1, 0x4000..=0x5fff) => {
+                self.ram_bank = data & 0x03;
+                self.rom_bank = self.rom_bank & 0x1f | u16::from(data & 0x03) << 5
+            }
+            (Mbc::Mbc3, 0x4000..=0x5fff) => match data {
+                0x00..=0x03 | 0x08..=0x0c => self.ram_bank = data,
+                _ => (),
+            },
+            (Mbc::Mbc5, 0x4000..=0x5fff) => self.ram_bank = data & 0x0f,
+            (Mbc::Mbc1, 0x6000..=0x7fff) => self.advanced_banking = data & 0x01 != 0,
+            (_, 0xa000..=0xbfff) => {
+                if let Some(register) = self.rtc_register().filter(|_| self.ram_enabled) {
+                    self.rtc[register] = data
+                } else if let Some(offset) = self.ram_offset(addr) {
+                    self.ram[offset] = if self.mbc == Mbc::Mbc2 {
+                        data & 0x0f
+                    } else {
+                        data
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // MBC3 maps the clock registers into the RAM window instead of a RAM bank.
+    // They hold whatever was written; the clock itself does not run.
+    fn rtc_register(&self) -> Option<usize> {
+        match (self.mbc, self.ram_bank) {
+            (Mbc::Mbc3, 0x08..=0x0c) => Some(usize::from(self.ram_bank - 0x08)),
+            _ => None,
+        }
+    }
+
+    fn read_ram(&self, offset: usize) -> u8 {
+        if self.mbc == Mbc::Mbc2 {
+            0xf0 | self.ram[offset]
+        } else {
+            self.ram[offset]
+        }
+    }
+
+    fn low_rom_bank(&self) -> u16 {
+        if self.mbc == Mbc::Mbc1 && self.advanced_banking {
+            self.rom_bank & 0x60
+        } else {
+            0
+        }
+    }
+
+    fn high_rom_bank(&self) -> u16 {
+        match self.mbc {
+            Mbc::None => 1,
+            _ => self.rom_bank,
+        }
+    }
+
+    fn rom_offset(&self, bank: u16, addr: u16) -> usize {
+        let banks = self.rom.len() / ROM_BANK_SIZE;
+        (usize::from(bank) % banks) * ROM_BANK_SIZE + usize::from(addr) % ROM_BANK_SIZE
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled && self.mbc != Mbc::None {
+            return None;
+        }
+        self.banked_ram_offset(addr)
+    }
+
+    fn banked_ram_offset(&self, addr: u16) -> Option<usize> {
+        if self.ram.is_empty() || self.rtc_register().is_some() {
+            return None;
+        }
+        let offset = usize::from(addr - 0xa000);
+        Some(match self.mbc {
+            Mbc::Mbc2 => offset % self.ram.len(),
+            Mbc::Mbc1 if !self.advanced_banking => offset % self.ram.len(),
+            _ => (usize::from(self.ram_bank) * RAM_BANK_SIZE + offset) % self.ram.len(),
+        })
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.ram);
+        writer.bool(self.ram_enabled);
+        writer.u16(self.rom_bank);
+        writer.u8(self.ram_bank);
+        writer.bool(self.advanced_banking);
+        writer.bytes(&self.rtc)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.revision += 1;
+        reader.fill(&mut self.ram)?;
+        self.ram_enabled = reader.bool()?;
+        self.rom_bank = reader.u16()?;
+        self.ram_bank = reader.u8()?;
+        self.advanced_banking = reader.bool()?;
+        reader.fill(&mut self.rtc)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn rom(cartridge_type: u8, banks: usize, ram_size: u8) -> Vec<u8> {
+        let mut rom: Vec<_> = (0..banks)
+            .flat_map(|bank| vec![bank as u8; ROM_BANK_SIZE])
+            .collect();
+        rom[CARTRIDGE_TYPE] = cartridge_type;
+        rom[ROM_SIZE] = (banks / 2).trailing_zeros() as u8;
+        rom[RAM_SIZE] = ram_size;
+        rom
+    }
+
+    #[test]
+    fn rom_without_header_is_rejected() {
+        assert_eq!(
+            Cartridge::new(vec![0x00; 0x100]).err(),
+            Some(CartridgeError::TooSmall(0x100))
+        )
+    }
+
+    #[test]
+    fn unsupported_type_is_rejected() {
+        assert_eq!(
+            Cartridge::new(rom(0xfc, 2, 0)).err(),
+            Some(CartridgeError::UnsupportedType(0xfc))
+        )
+    }
+
+    #[test]
+    fn title_is_read_from_header() {
+        let mut rom = rom(0x00, 2, 0);
+        rom[TITLE..TITLE + 5].copy_from_slice(b"TETRI");
+        rom[TITLE + 5] = 0x00;
+        assert_eq!(Cartridge::new(rom).unwrap().title(), "TETRI")
+    }
+
+    #[test]
+    fn cgb_flag_is_not_part_of_title() {
+        let mut rom = rom(0x00, 2, 0);
+        rom[TITLE..TITLE + 15].copy_from_slice(b"ABCDEFGHIJKLMNO");
+        rom[CGB_FLAG] = 0xc0;
+        let cartridge = Cartridge::new(rom).unwrap();
+        assert!(cartridge.supports_cgb());
+        assert_eq!(cartridge.title(), "ABCDEFGHIJKLMNO")
+    }
+
+    #[test]
+    fn rom_only_cartridge_maps_both_banks() {
+        let cartridge = Cartridge::new(rom(0x00, 2, 0)).unwrap();
+        assert_eq!(cartridge.read(0x0200), 0x00);
+        assert_eq!(cartridge.read(0x4000), 0x01)
+    }
+
+    #[test]
+    fn poke_patches_rom_and_disabled_ram() {
+        let mut cartridge = Cartridge::new(rom(0x03, 4, 0x02)).unwrap();
+        cartridge.write(0x2000, 0x02);
+        cartridge.poke(0x4000, 0xaa);
+        cartridge.poke(0xa000, 0x55);
+        assert_eq!(cartridge.read(0x4000), 0xaa);
+        assert_eq!(cartridge.read(0xa000), 0xff);
+        assert_eq!(cartridge.peek(0xa000), 0x55);
+        cartridge.write(0x2000, 0x01);
+        assert_eq!(cartridge.read(0x4000), 0x01)
+    }
+
+    #[test]
+    fn mbc1_switches_rom_bank() {
+        let mut cartridge = Cartridge::new(rom(0x01, 8, 0)).unwrap();
+        cartridge.write(0x2000, 0x05);
+        assert_eq!(cartridge.read(0x4000), 0x05)
+    }
+
+    #[test]
+    fn mbc1_bank_0_selects_bank_1() {
+        let mut cartridge = Cartridge::new(rom(0x01, 8, 0)).unwrap();
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(cartridge.read(0x4000), 0x01)
+    }
+
+    #[test]
+    fn mbc1_upper_bits_select_high_rom_banks() {
+        let mut cartridge = Cartridge::new(rom(0x01, 64, 0)).unwrap();
+        cartridge.write(0x2000, 0x02);
+        cartridge.write(0x4000, 0x01);
+        assert_eq!(cartridge.read(0x4000), 0x22);
+        assert_eq!(cartridge.read(0x0000), 0x00);
+        cartridge.write(0x6000, 0x01);
+        assert_eq!(cartridge.read(0x0000), 0x20)
+    }
+
+    #[test]
+    fn ram_is_disabled_by_default() {
+        let mut cartridge = Cartridge::new(rom(0x03, 2, 0x02)).unwrap();
+        cartridge.write(0xa000, 0x42);
+        assert_eq!(cartridge.read(0xa000), 0xff)
+    }
+
+    #[test]
+    fn enabled_ram_is_writable() {
+        let mut cartridge = Cartridge::new(rom(0x03, 2, 0x02)).unwrap();
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0xa000, 0x42);
+        assert_eq!(cartridge.read(0xa000), 0x42)
+    }
+
+    #[test]
+    fn mbc3_switches_ram_bank() {
+        let mut cartridge = Cartridge::new(rom(0x13, 4, 0x03)).unwrap();
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0xa000, 0x11);
+        cartridge.write(0x4000, 0x02);
+        cartridge.write(0xa000, 0x22);
+        assert_eq!(cartridge.read(0xa000), 0x22);
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xa000), 0x11)
+    }
+
+    #[test]
+    fn mbc3_rtc_registers_do_not_alias_ram() {
+        let mut cartridge = Cartridge::new(rom(0x10, 4, 0x03)).unwrap();
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0xa000, 0x11);
+        cartridge.write(0x4000, 0x08);
+        cartridge.write(0xa000, 0x3b);
+        assert_eq!(cartridge.read(0xa000), 0x3b);
+        cartridge.write(0x4000, 0x0c);
+        assert_eq!(cartridge.read(0xa000), 0x00);
+        cartridge.write(0x4000, 0x05);
+        assert_eq!(cartridge.read(0xa000), 0x00);
+        cartridge.write(0x4000, 0x00);
+        assert_eq!(cartridge.read(0xa000), 0x11);
+        cartridge.write(0x4000, 0x08);
+        assert_eq!(cartridge.read(0xa000), 0x3b)
+    }
+
+    #[test]
+    fn mbc5_supports_bank_0_and_9_bit_banks() {
+        let mut cartridge = Cartridge::new(rom(0x19, 512, 0)).unwrap();
+        cartridge.write(0x2000, 0x00);
+        assert_eq!(cartridge.read(0x4000), 0x00);
+        cartridge.write(0x2000, 0x03);
+        cartridge.write(0x3000, 0x01);
+        assert_eq!(cartridge.read(0x4000), 0x03);
+        assert_eq!(
+            cartridge.rom_offset(cartridge.high_rom_bank(), 0x4000),
+            0x103 * ROM_BANK_SIZE
+        )
+    }
+
+    #[test]
+    fn mbc2_ram_stores_nibbles() {
+        let mut cartridge = Cartridge::new(rom(0x06, 4, 0)).unwrap();
+        cartridge.write(0x0000, 0x0a);
+        cartridge.write(0xa123, 0x5a);
+        assert_eq!(cartridge.read(0xa323), 0xfa)
+    }
+}
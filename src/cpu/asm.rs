@@ -0,0 +1,837 @@
+use super::decode::{encode, encode_cb, CbOp, Instruction};
+use super::*;
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    let mut symbols = HashMap::new();
+    let mut statements = Vec::new();
+    let mut scope = String::new();
+    let mut addr = origin;
+    for (index, line) in source.lines().enumerate() {
+        let error = |message: String| AsmError {
+            line: index + 1,
+            message,
+        };
+        let mut text = strip_comment(line).trim();
+        while let Some((label, rest)) = split_label(text) {
+            let label = if label.starts_with('.') {
+                format!("{}{}", scope, label)
+            } else {
+                scope = label.to_string();
+                label.to_string()
+            };
+            if symbols.insert(label.clone(), i64::from(addr)).is_some() {
+                return Err(error(format!("duplicate label {}", label)));
+            }
+            text = rest.trim_start()
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let item = parse_item(text, &scope).map_err(error)?;
+        let size = item.size();
+        statements.push(Statement {
+            line: index + 1,
+            addr,
+            item,
+        });
+        addr = addr.wrapping_add(size)
+    }
+    let mut bytes = Vec::new();
+    for statement in &statements {
+        statement
+            .emit(&symbols, &mut bytes)
+            .map_err(|message| AsmError {
+                line: statement.line,
+                message,
+            })?
+    }
+    Ok(bytes)
+}
+
+struct Statement {
+    line: usize,
+    addr: u16,
+    item: Item,
+}
+
+enum Item {
+    Bytes(Vec<Data>),
+    Words(Vec<Expr>),
+    Instruction(Instruction, Arg),
+    Cb(CbOp, Option<R>),
+    CbBit(fn(u8) -> CbOp, Expr, Option<R>),
+}
+
+enum Data {
+    Expr(Expr),
+    String(Vec<u8>),
+}
+
+enum Arg {
+    None,
+    N(Expr),
+    High(Expr),
+    E(Expr),
+    Nn(Expr),
+    Rel(Expr),
+    Rst(Expr),
+}
+
+impl Item {
+    fn size(&self) -> u16 {
+        match self {
+            Item::Bytes(data) => data
+                .iter()
+                .map(|data| match data {
+                    Data::Expr(_) => 1,
+                    Data::String(bytes) => bytes.len() as u16,
+                })
+                .sum(),
+            Item::Words(words) => 2 * words.len() as u16,
+            Item::Instruction(_, arg) => match arg {
+                Arg::None | Arg::Rst(_) => 1,
+                Arg::N(_) | Arg::High(_) | Arg::E(_) | Arg::Rel(_) => 2,
+                Arg::Nn(_) => 3,
+            },
+            Item::Cb(..) | Item::CbBit(..) => 2,
+        }
+    }
+}
+
+impl Statement {
+    fn emit(&self, symbols: &HashMap<String, i64>, bytes: &mut Vec<u8>) -> Result<(), String> {
+        let eval = |expr: &Expr| expr.eval(symbols, self.addr);
+        match &self.item {
+            Item::Bytes(data) => {
+                for data in data {
+                    match data {
+                        Data::Expr(expr) => bytes.push(byte(eval(expr)?)?),
+                        Data::String(string) => bytes.extend_from_slice(string),
+                    }
+                }
+            }
+            Item::Words(words) => {
+                for expr in words {
+                    bytes.extend_from_slice(&word(eval(expr)?)?.to_le_bytes())
+                }
+            }
+            Item::Instruction(Instruction::Rst(_), Arg::Rst(expr)) => {
+                let addr = eval(expr)?;
+                if addr & !0x38 != 0 {
+                    return Err(format!("invalid RST target {:#x}", addr));
+                }
+                bytes.extend(encode(Instruction::Rst(addr as u8)))
+            }
+            Item::Instruction(instruction, arg) => {
+                bytes.extend(encode(*instruction));
+                match arg {
+                    Arg::None | Arg::Rst(_) => (),
+                    Arg::N(expr) => bytes.push(byte(eval(expr)?)?),
+                    Arg::High(expr) => match eval(expr)? {
+                        value @ 0x00..=0xff => bytes.push(value as u8),
+                        value @ 0xff00..=0xffff => bytes.push(value as u8),
+                        value => return Err(format!("{:#x} is not a high page address", value)),
+                    },
+                    Arg::E(expr) => bytes.push(signed_byte(eval(expr)?)?),
+                    Arg::Nn(expr) => bytes.extend_from_slice(&word(eval(expr)?)?.to_le_bytes()),
+                    Arg::Rel(expr) => {
+                        let offset = eval(expr)? - i64::from(self.addr) - 2;
+                        let offset = signed_byte(offset)
+                            .map_err(|_| format!("jump target is {} bytes away", offset))?;
+                        bytes.push(offset)
+                    }
+                }
+            }
+            Item::Cb(op, operand) => bytes.extend_from_slice(&[0xcb, encode_cb(*op, *operand)]),
+            Item::CbBit(op, bit, operand) => {
+                let bit = eval(bit)?;
+                if !(0..=7).contains(&bit) {
+                    return Err(format!("invalid bit {}", bit));
+                }
+                bytes.extend_from_slice(&[0xcb, encode_cb(op(bit as u8), *operand)])
+            }
+        }
+        Ok(())
+    }
+}
+
+fn byte(value: i64) -> Result<u8, String> {
+    if (-0x80..=0xff).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("{} does not fit in a byte", value))
+    }
+}
+
+fn signed_byte(value: i64) -> Result<u8, String> {
+    if (-0x80..=0x7f).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("{} does not fit in a signed byte", value))
+    }
+}
+
+fn word(value: i64) -> Result<u16, String> {
+    if (-0x8000..=0xffff).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(format!("{} does not fit in a word", value))
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &line[..i],
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            _ => (),
+        }
+    }
+    line
+}
+
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let end = text
+        .find(|c: char| !is_identifier_char(c))
+        .unwrap_or(text.len());
+    if end > 0 && text[end..].starts_with(':') && !text.as_bytes()[0].is_ascii_digit() {
+        let rest = &text[end + 1..];
+        Some((&text[..end], rest.strip_prefix(':').unwrap_or(rest)))
+    } else {
+        None
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+fn split_operands(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    let mut operands = Vec::new();
+    let (mut depth, mut quote, mut start) = (0, None, 0);
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, '(') | (None, '[') => depth += 1,
+            (None, ')') | (None, ']') => depth -= 1,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if q == c => quote = None,
+            (None, ',') if depth == 0 => {
+                operands.push(text[start..i].trim());
+                start = i + 1
+            }
+            _ => (),
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+enum Operand {
+    R(R),
+    Pair(Dd),
+    Af,
+    Deref(Deref),
+    SpOffset(Expr),
+    Expr(Expr),
+}
+
+enum Deref {
+    Bc,
+    De,
+    Hl,
+    Hli,
+    Hld,
+    C,
+    Expr(Expr),
+}
+
+fn parse_operand(text: &str, scope: &str) -> Result<Operand, String> {
+    let upper = text.to_ascii_uppercase();
+    let r = match upper.as_str() {
+        "A" => Some(R::A),
+        "B" => Some(R::B),
+        "C" => Some(R::C),
+        "D" => Some(R::D),
+        "E" => Some(R::E),
+        "H" => Some(R::H),
+        "L" => Some(R::L),
+        _ => None,
+    };
+    if let Some(r) = r {
+        return Ok(Operand::R(r));
+    }
+    match upper.as_str() {
+        "BC" => return Ok(Operand::Pair(Dd::Bc)),
+        "DE" => return Ok(Operand::Pair(Dd::De)),
+        "HL" => return Ok(Operand::Pair(Dd::Hl)),
+        "SP" => return Ok(Operand::Pair(Dd::Sp)),
+        "AF" => return Ok(Operand::Af),
+        _ => (),
+    }
+    if let Some(inner) = dereferenced(text) {
+        let inner = inner.trim();
+        let deref = match inner.to_ascii_uppercase().replace(' ', "").as_str() {
+            "BC" => Deref::Bc,
+            "DE" => Deref::De,
+            "HL" => Deref::Hl,
+            "HL+" | "HLI" => Deref::Hli,
+            "HL-" | "HLD" => Deref::Hld,
+            "C" | "$FF00+C" | "0XFF00+C" => Deref::C,
+            _ => Deref::Expr(parse_expr(inner, scope)?),
+        };
+        return Ok(Operand::Deref(deref));
+    }
+    if upper.starts_with("SP") && upper[2..].trim_start().starts_with(['+', '-']) {
+        return Ok(Operand::SpOffset(parse_expr(&text[2..], scope)?));
+    }
+    Ok(Operand::Expr(parse_expr(text, scope)?))
+}
+
+fn dereferenced(text: &str) -> Option<&str> {
+    let close = match text.chars().next()? {
+        '(' => ')',
+        '[' => ']',
+        _ => return None,
+    };
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    return if i == text.len() - 1 && c == close {
+                        Some(&text[1..i])
+                    } else {
+                        None
+                    };
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+fn condition(text: &str) -> Option<Cc> {
+    match text.to_ascii_uppercase().as_str() {
+        "NZ" => Some(Cc::Nz),
+        "Z" => Some(Cc::Z),
+        "NC" => Some(Cc::Nc),
+        "C" => Some(Cc::C),
+        _ => None,
+    }
+}
+
+fn parse_item(text: &str, scope: &str) -> Result<Item, String> {
+    let (mnemonic, rest) = match text.find(char::is_whitespace) {
+        Some(i) => (&text[..i], text[i..].trim()),
+        None => (text, ""),
+    };
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let texts = split_operands(rest);
+    match mnemonic.as_str() {
+        "DB" => {
+            return texts
+                .iter()
+                .map(|text| match text.strip_prefix('"') {
+                    Some(string) => match string.strip_suffix('"') {
+                        Some(string) => Ok(Data::String(string.as_bytes().to_vec())),
+                        None => Err("unterminated string".to_string()),
+                    },
+                    None => Ok(Data::Expr(parse_expr(text, scope)?)),
+                })
+                .collect::<Result<_, _>>()
+                .map(Item::Bytes)
+        }
+        "DW" => {
+            return texts
+                .iter()
+                .map(|text| parse_expr(text, scope))
+                .collect::<Result<_, _>>()
+                .map(Item::Words)
+        }
+        "JP" | "JR" | "CALL" | "RET" if !texts.is_empty() => {
+            if let Some(cc) = condition(texts[0]) {
+                if texts.len() == 1 || mnemonic != "RET" {
+                    let target = texts[1..]
+                        .first()
+                        .map(|text| parse_expr(text, scope))
+                        .transpose()?;
+                    return Ok(match (mnemonic.as_str(), target) {
+                        ("RET", None) => Item::Instruction(Instruction::Ret(Some(cc)), Arg::None),
+                        ("JP", Some(nn)) => {
+                            Item::Instruction(Instruction::Jp(Some(cc)), Arg::Nn(nn))
+                        }
+                        ("JR", Some(e)) => {
+                            Item::Instruction(Instruction::Jr(Some(cc)), Arg::Rel(e))
+                        }
+                        ("CALL", Some(nn)) => {
+                            Item::Instruction(Instruction::Call(Some(cc)), Arg::Nn(nn))
+                        }
+                        _ => return Err(format!("invalid operands for {}", mnemonic)),
+                    });
+                }
+            }
+        }
+        _ => (),
+    }
+    let operands = texts
+        .iter()
+        .map(|text| parse_operand(text, scope))
+        .collect::<Result<Vec<_>, _>>()?;
+    let invalid = || format!("invalid operands for {}", mnemonic);
+    let cb_op = |op| match operands.as_slice() {
+        [Operand::R(r)] => Ok(Item::Cb(op, Some(*r))),
+        [Operand::Deref(Deref::Hl)] => Ok(Item::Cb(op, None)),
+        _ => Err(invalid()),
+    };
+    let cb_bit = |op: fn(u8) -> CbOp| match operands.as_slice() {
+        [Operand::Expr(bit), Operand::R(r)] => Ok(Item::CbBit(op, bit.clone(), Some(*r))),
+        [Operand::Expr(bit), Operand::Deref(Deref::Hl)] => Ok(Item::CbBit(op, bit.clone(), None)),
+        _ => Err(invalid()),
+    };
+    use self::Instruction as I;
+    let (instruction, arg) = match (mnemonic.as_str(), operands.as_slice()) {
+        ("NOP", []) => (I::Nop, Arg::None),
+        ("STOP", []) => (I::Stop, Arg::N(Expr::Number(0))),
+        ("STOP", [Operand::Expr(n)]) => (I::Stop, Arg::N(n.clone())),
+        ("HALT", []) => (I::Halt, Arg::None),
+        ("DI", []) => (I::Di, Arg::None),
+        ("EI", []) => (I::Ei, Arg::None),
+        ("RLCA", []) => (I::Rlca, Arg::None),
+        ("RRCA", []) => (I::Rrca, Arg::None),
+        ("RLA", []) => (I::Rla, Arg::None),
+        ("RRA", []) => (I::Rra, Arg::None),
+        ("DAA", []) => (I::Daa, Arg::None),
+        ("CPL", []) => (I::Cpl, Arg::None),
+        ("SCF", []) => (I::Scf, Arg::None),
+        ("CCF", []) => (I::Ccf, Arg::None),
+        ("RETI", []) => (I::Reti, Arg::None),
+        ("RET", []) => (I::Ret(None), Arg::None),
+        ("JP", [Operand::Pair(Dd::Hl)]) | ("JP", [Operand::Deref(Deref::Hl)]) => {
+            (I::JpDerefHl, Arg::None)
+        }
+        ("JP", [Operand::Expr(nn)]) => (I::Jp(None), Arg::Nn(nn.clone())),
+        ("JR", [Operand::Expr(e)]) => (I::Jr(None), Arg::Rel(e.clone())),
+        ("CALL", [Operand::Expr(nn)]) => (I::Call(None), Arg::Nn(nn.clone())),
+        ("RST", [Operand::Expr(addr)]) => (I::Rst(0), Arg::Rst(addr.clone())),
+        ("PUSH", [Operand::Pair(dd)]) | ("POP", [Operand::Pair(dd)]) if dd.is_stackable() => {
+            let qq = dd.to_qq();
+            (
+                if mnemonic == "PUSH" {
+                    I::PushQq(qq)
+                } else {
+                    I::PopQq(qq)
+                },
+                Arg::None,
+            )
+        }
+        ("PUSH", [Operand::Af]) => (I::PushQq(Qq::Af), Arg::None),
+        ("POP", [Operand::Af]) => (I::PopQq(Qq::Af), Arg::None),
+        ("LD", [Operand::R(dest), Operand::R(src)]) => (I::LdRR(*dest, *src), Arg::None),
+        ("LD", [Operand::R(dest), Operand::Expr(n)]) => (I::LdRN(*dest), Arg::N(n.clone())),
+        ("LD", [Operand::R(dest), Operand::Deref(Deref::Hl)]) => (I::LdRDerefHl(*dest), Arg::None),
+        ("LD", [Operand::Deref(Deref::Hl), Operand::R(src)]) => (I::LdDerefHlR(*src), Arg::None),
+        ("LD", [Operand::Deref(Deref::Hl), Operand::Expr(n)]) => (I::LdDerefHlN, Arg::N(n.clone())),
+        ("LD", [Operand::R(R::A), Operand::Deref(deref)]) => match deref {
+            Deref::Bc => (I::LdADerefBc, Arg::None),
+            Deref::De => (I::LdADerefDe, Arg::None),
+            Deref::Hli => (I::LdADerefHli, Arg::None),
+            Deref::Hld => (I::LdADerefHld, Arg::None),
+            Deref::C => (I::LdADerefC, Arg::None),
+            Deref::Expr(nn) => (I::LdADerefNn, Arg::Nn(nn.clone())),
+            Deref::Hl => unreachable!(),
+        },
+        ("LD", [Operand::Deref(deref), Operand::R(R::A)]) => match deref {
+            Deref::Bc => (I::LdDerefBcA, Arg::None),
+            Deref::De => (I::LdDerefDeA, Arg::None),
+            Deref::Hli => (I::LdDerefHliA, Arg::None),
+            Deref::Hld => (I::LdDerefHldA, Arg::None),
+            Deref::C => (I::LdDerefCA, Arg::None),
+            Deref::Expr(nn) => (I::LdDerefNnA, Arg::Nn(nn.clone())),
+            Deref::Hl => unreachable!(),
+        },
+        ("LD", [Operand::Pair(Dd::Sp), Operand::Pair(Dd::Hl)]) => (I::LdSpHl, Arg::None),
+        ("LD", [Operand::Pair(dd), Operand::Expr(nn)]) => (I::LdDdNn(*dd), Arg::Nn(nn.clone())),
+        ("LD", [Operand::Deref(Deref::Expr(nn)), Operand::Pair(Dd::Sp)]) => {
+            (I::LdDerefNnSp, Arg::Nn(nn.clone()))
+        }
+        ("LD", [Operand::Pair(Dd::Hl), Operand::SpOffset(e)]) => (I::LdhlSpE, Arg::E(e.clone())),
+        ("LDH", [Operand::R(R::A), Operand::Deref(Deref::Expr(n))]) => {
+            (I::LdADerefN, Arg::High(n.clone()))
+        }
+        ("LDH", [Operand::Deref(Deref::Expr(n)), Operand::R(R::A)]) => {
+            (I::LdDerefNA, Arg::High(n.clone()))
+        }
+        ("LDH", [Operand::R(R::A), Operand::Deref(Deref::C)]) => (I::LdADerefC, Arg::None),
+        ("LDH", [Operand::Deref(Deref::C), Operand::R(R::A)]) => (I::LdDerefCA, Arg::None),
+        ("ADD", [Operand::Pair(Dd::Hl), Operand::Pair(dd)]) => (I::AddHlDd(*dd), Arg::None),
+        ("ADD", [Operand::Pair(Dd::Sp), Operand::Expr(e)]) => (I::AddSpE, Arg::E(e.clone())),
+        ("INC", [Operand::R(r)]) => (I::IncR(*r), Arg::None),
+        ("INC", [Operand::Deref(Deref::Hl)]) => (I::IncDerefHl, Arg::None),
+        ("INC", [Operand::Pair(dd)]) => (I::IncDd(*dd), Arg::None),
+        ("DEC", [Operand::R(r)]) => (I::DecR(*r), Arg::None),
+        ("DEC", [Operand::Deref(Deref::Hl)]) => (I::DecDerefHl, Arg::None),
+        ("DEC", [Operand::Pair(dd)]) => (I::DecDd(*dd), Arg::None),
+        ("RLC", _) => return cb_op(CbOp::Rlc),
+        ("RRC", _) => return cb_op(CbOp::Rrc),
+        ("RL", _) => return cb_op(CbOp::Rl),
+        ("RR", _) => return cb_op(CbOp::Rr),
+        ("SLA", _) => return cb_op(CbOp::Sla),
+        ("SRA", _) => return cb_op(CbOp::Sra),
+        ("SWAP", _) => return cb_op(CbOp::Swap),
+        ("SRL", _) => return cb_op(CbOp::Srl),
+        ("BIT", _) => return cb_bit(CbOp::Bit),
+        ("RES", _) => return cb_bit(CbOp::Res),
+        ("SET", _) => return cb_bit(CbOp::Set),
+        (name, operands) => match alu_op(name) {
+            Some(op) => {
+                let operand = match (op, operands) {
+                    (_, [Operand::R(R::A), operand]) => operand,
+                    (AluOp::Add, _) | (AluOp::Adc, _) | (AluOp::Sbc, _) => return Err(invalid()),
+                    (_, [operand]) => operand,
+                    _ => return Err(invalid()),
+                };
+                match operand {
+                    Operand::R(r) => (I::AluOpR(op, *r), Arg::None),
+                    Operand::Deref(Deref::Hl) => (I::AluOpDerefHl(op), Arg::None),
+                    Operand::Expr(n) => (I::AluOpN(op), Arg::N(n.clone())),
+                    _ => return Err(invalid()),
+                }
+            }
+            None if is_instruction(name) => return Err(invalid()),
+            None => return Err(format!("unknown instruction {}", name)),
+        },
+    };
+    Ok(Item::Instruction(instruction, arg))
+}
+
+fn alu_op(mnemonic: &str) -> Option<AluOp> {
+    Some(match mnemonic {
+        "ADD" => AluOp::Add,
+        "ADC" => AluOp::Adc,
+        "SUB" => AluOp::Sub,
+        "SBC" => AluOp::Sbc,
+        "AND" => AluOp::And,
+        "XOR" => AluOp::Xor,
+        "OR" => AluOp::Or,
+        "CP" => AluOp::Cp,
+        _ => return None,
+    })
+}
+
+fn is_instruction(mnemonic: &str) -> bool {
+    [
+        "NOP", "STOP", "HALT", "DI", "EI", "RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF",
+        "CCF", "RETI", "RET", "JP", "JR", "CALL", "RST", "PUSH", "POP", "LD", "LDH", "INC", "DEC",
+    ]
+    .contains(&mnemonic)
+}
+
+impl Dd {
+    fn is_stackable(self) -> bool {
+        !matches!(self, Dd::Sp)
+    }
+
+    fn to_qq(self) -> Qq {
+        match self {
+            Dd::Bc => Qq::Bc,
+            Dd::De => Qq::De,
+            Dd::Hl => Qq::Hl,
+            Dd::Sp => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(i64),
+    Symbol(String),
+    Here,
+    Unary(char, Box<Expr>),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, symbols: &HashMap<String, i64>, here: u16) -> Result<i64, String> {
+        Ok(match self {
+            Expr::Number(value) => *value,
+            Expr::Symbol(name) => *symbols
+                .get(name)
+                .ok_or_else(|| format!("undefined symbol {}", name))?,
+            Expr::Here => i64::from(here),
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(symbols, here)?;
+                match op {
+                    '-' => value.wrapping_neg(),
+                    '~' => !value,
+                    _ => value,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(symbols, here)?;
+                let rhs = rhs.eval(symbols, here)?;
+                match *op {
+                    "+" => lhs.wrapping_add(rhs),
+                    "-" => lhs.wrapping_sub(rhs),
+                    "*" => lhs.wrapping_mul(rhs),
+                    "/" | "%" if rhs == 0 => return Err("division by zero".to_string()),
+                    "/" => lhs.wrapping_div(rhs),
+                    "%" => lhs.wrapping_rem(rhs),
+                    "&" => lhs & rhs,
+                    "|" => lhs | rhs,
+                    "^" => lhs ^ rhs,
+                    "<<" => lhs.wrapping_shl(rhs as u32),
+                    ">>" => lhs.wrapping_shr(rhs as u32),
+                    _ => unreachable!(),
+                }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Op(&'static str),
+}
+
+const OPERATORS: &[&str] = &[
+    "<<", ">>", "+", "-", "*", "/", "%", "&", "|", "^", "~", "(", ")", "[", "]", "@",
+];
+
+// Binary operators from lowest to highest precedence.
+const PRECEDENCE: &[&[&str]] = &[
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+fn tokenize(text: &str, scope: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        let expects_operand = match tokens.last() {
+            None => true,
+            Some(Token::Op(op)) => ![")", "]", "@"].contains(op),
+            Some(_) => false,
+        };
+        let len = if c == '$' || c.is_ascii_digit() || (c == '%' && expects_operand) {
+            let (radix, digits) = if let Some(digits) = rest.strip_prefix('$') {
+                (16, digits)
+            } else if let Some(digits) = rest.strip_prefix('%') {
+                (2, digits)
+            } else if let Some(digits) = rest.strip_prefix("0x").or(rest.strip_prefix("0X")) {
+                (16, digits)
+            } else if let Some(digits) = rest.strip_prefix("0b").or(rest.strip_prefix("0B")) {
+                (2, digits)
+            } else {
+                (10, rest)
+            };
+            let end = digits
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(digits.len());
+            let len = rest.len() - digits.len() + end;
+            let value = i64::from_str_radix(&digits[..end], radix)
+                .map_err(|_| format!("invalid number {}", &rest[..len]))?;
+            tokens.push(Token::Number(value));
+            len
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            op.len()
+        } else if c == '\'' {
+            let mut chars = rest[1..].chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), Some('\'')) if c.is_ascii() => tokens.push(Token::Number(c as i64)),
+                _ => return Err(format!("invalid character literal in {}", text)),
+            }
+            3
+        } else if is_identifier_char(c) {
+            let end = rest
+                .find(|c: char| !is_identifier_char(c))
+                .unwrap_or(rest.len());
+            let name = &rest[..end];
+            tokens.push(Token::Symbol(if name.starts_with('.') {
+                format!("{}{}", scope, name)
+            } else {
+                name.to_string()
+            }));
+            end
+        } else {
+            return Err(format!("unexpected character {:?}", c));
+        };
+        rest = rest[len..].trim_start()
+    }
+    Ok(tokens)
+}
+
+fn parse_expr(text: &str, scope: &str) -> Result<Expr, String> {
+    let mut tokens = tokenize(text, scope)?;
+    tokens.reverse();
+    let mut parser = Parser { tokens };
+    let expr = parser.binary(0)?;
+    match parser.tokens.pop() {
+        None => Ok(expr),
+        Some(token) => Err(format!("unexpected {:?} in {}", token, text)),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+}
+
+impl Parser {
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(&Token::Op(op)) = self.tokens.last() {
+            if !PRECEDENCE[level].contains(&op) {
+                break;
+            }
+            self.tokens.pop();
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.tokens.pop() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Symbol(name)) => Ok(Expr::Symbol(name)),
+            Some(Token::Op("@")) => Ok(Expr::Here),
+            Some(Token::Op(op @ "-")) | Some(Token::Op(op @ "+")) | Some(Token::Op(op @ "~")) => {
+                let c = op.chars().next().unwrap();
+                Ok(Expr::Unary(c, Box::new(self.unary()?)))
+            }
+            Some(Token::Op(open @ "(")) | Some(Token::Op(open @ "[")) => {
+                let expr = self.binary(0)?;
+                let close = if open == "(" { ")" } else { "]" };
+                match self.tokens.pop() {
+                    Some(Token::Op(op)) if op == close => Ok(expr),
+                    _ => Err(format!("expected {}", close)),
+                }
+            }
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("missing operand".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cpu::disasm::disassemble;
+
+    #[test]
+    fn labels_and_local_labels_resolve() {
+        let source = "
+            main:
+                ld b, 3
+            .loop:
+                dec b
+                jr nz, .loop
+                call sub
+                jp main
+            sub:
+            .loop:
+                ret
+        ";
+        assert_eq!(
+            assemble(source, 0x0150).unwrap(),
+            [0x06, 0x03, 0x05, 0x20, 0xfd, 0xcd, 0x5b, 0x01, 0xc3, 0x50, 0x01, 0xc9]
+        )
+    }
+
+    #[test]
+    fn data_directives_and_expressions() {
+        let source = "
+            start: db 1, -1, 'A', \"hi\", %101 ; comment
+            dw start + 2 * 3, (1 << 8) | $ff, end - start
+            ld a, [end & $ff]
+            ldh [$ff40], a
+            ld hl, sp - 2
+            end:
+        ";
+        assert_eq!(
+            assemble(source, 0xc000).unwrap(),
+            [
+                0x01, 0xff, 0x41, 0x68, 0x69, 0x05, 0x06, 0xc0, 0xff, 0x01, 0x13, 0x00, 0xfa, 0x13,
+                0x00, 0xe0, 0x40, 0xf8, 0xfe
+            ]
+        )
+    }
+
+    #[test]
+    fn errors_report_line() {
+        assert_eq!(
+            assemble("nop\njp nowhere", 0),
+            Err(AsmError {
+                line: 2,
+                message: "undefined symbol nowhere".to_string(),
+            })
+        );
+        assert_eq!(assemble("nop\nfoo a", 0).unwrap_err().line, 2);
+        assert_eq!(assemble("ld (bc), b", 0).unwrap_err().line, 1);
+        assert!(assemble("jr $1000", 0).is_err());
+        assert!(assemble("x:\nx:", 0).is_err());
+        assert!(assemble("db 1 / 0", 0).is_err())
+    }
+
+    #[test]
+    fn overflowing_division_wraps() {
+        assert_eq!(
+            assemble("db ((1 << 63) / -1) & $ff, ((1 << 63) % -1) & $ff", 0),
+            Ok(vec![0x00, 0x00])
+        )
+    }
+
+    #[test]
+    fn disassembly_reassembles_to_same_bytes() {
+        let pc = 0x4000;
+        let pages = (0x00..=0xff)
+            .map(|opcode| vec![opcode, 0x12, 0x34])
+            .chain((0x00..=0xff).map(|opcode| vec![0xcb, opcode]));
+        for bytes in pages {
+            let disassembly = disassemble(&bytes, pc).unwrap();
+            let len = usize::from(disassembly.len);
+            assert_eq!(
+                assemble(&disassembly.mnemonic, pc).unwrap(),
+                bytes[..len],
+                "{}",
+                disassembly.mnemonic
+            )
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use super::{BusActivity, BusOp, Cpu, CpuTrace, Input, Output};
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+use std::io::{self, Write};
+
+pub const MAGIC: [u8; 4] = *b"GBBT";
+pub const FORMAT_VERSION: u16 = 1;
+
+const DATA: u8 = 0x01;
+const BUS: u8 = 0x02;
+const READ: u8 = 0x04;
+const WRITE: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+// Streams every CPU half-cycle to the writer as it happens, so a session of any
+// length can be recorded. Read it back with BusTrace::from_bytes.
+pub struct BusTraceWriter {
+    writer: Box<dyn Write>,
+    error: Option<io::Error>,
+}
+
+pub struct BusTrace {
+    initial_state: Vec<u8>,
+    steps: CpuTrace,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Divergence {
+    pub step: usize,
+    pub input: Input,
+    pub expected: Output,
+    pub actual: Output,
+}
+
+impl BusTraceWriter {
+    pub fn new(writer: impl Write + 'static, cpu: &Cpu) -> Self {
+        let initial_state = cpu.save_state();
+        let mut header = StateWriter::default();
+        header.bytes(&MAGIC);
+        header.u16(FORMAT_VERSION);
+        header.u32(initial_state.len() as u32);
+        header.bytes(&initial_state);
+        let mut trace = Self {
+            writer: Box::new(writer),
+            error: None,
+        };
+        trace.write(&header.into_bytes());
+        trace
+    }
+
+    pub fn record(&mut self, input: &Input, output: &Output) {
+        let mut step = StateWriter::default();
+        write_step(&mut step, input, output);
+        self.write(&step.into_bytes())
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        if self.error.is_none() {
+            self.error = self.writer.write_all(bytes).err()
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+impl BusTrace {
+    pub fn steps(&self) -> &[(Input, Output)] {
+        &self.steps
+    }
+
+    pub fn replay(&self) -> Result<(), Divergence> {
+        let mut cpu = Cpu::default();
+        cpu.load_state(&self.initial_state)
+            .expect("trace holds a valid CPU state");
+        for (step, (input, expected)) in self.steps.iter().enumerate() {
+            let actual = cpu.step(input);
+            if actual != *expected {
+                return Err(Divergence {
+                    step,
+                    input: input.clone(),
+                    expected: expected.clone(),
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        let mut reader = StateReader::new(bytes);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        match reader.u16()? {
+            FORMAT_VERSION => (),
+            version => return Err(StateError::UnsupportedVersion(version)),
+        }
+        let len = reader.u32()? as usize;
+        let initial_state = reader.bytes(len)?.to_vec();
+        Cpu::default().load_state(&initial_state)?;
+        let mut steps = Vec::new();
+        while !reader.is_empty() {
+            steps.push(read_step(&mut reader)?)
+        }
+        Ok(Self {
+            initial_state,
+            steps,
+        })
+    }
+}
+
+fn write_step(writer: &mut StateWriter, input: &Input, output: &Output) {
+    let mut flags = 0;
+    if input.data.is_some() {
+        flags |= DATA
+    }
+    if let Some(bus) = &output.bus {
+        flags |= BUS;
+        match bus.op {
+            Some(BusOp::Read) => flags |= READ,
+            Some(BusOp::Write(_)) => flags |= WRITE,
+            None => (),
+        }
+    }
+    if output.ack != 0x00 {
+        flags |= ACK
+    }
+    writer.u8(flags);
+    writer.u8(input.r#if);
+    if let Some(data) = input.data {
+        writer.u8(data)
+    }
+    if let Some(bus) = &output.bus {
+        writer.u16(bus.addr);
+        if let Some(BusOp::Write(data)) = bus.op {
+            writer.u8(data)
+        }
+    }
+    if output.ack != 0x00 {
+        writer.u8(output.ack)
+    }
+}
+
+fn read_step(reader: &mut StateReader) -> Result<(Input, Output), StateError> {
+    let flags = reader.u8()?;
+    if flags & !(DATA | BUS | READ | WRITE | ACK) != 0
+        || flags & BUS == 0 && flags & (READ | WRITE) != 0
+        || flags & (READ | WRITE) == READ | WRITE
+    {
+        return Err(StateError::InvalidValue("bus trace step", flags.into()));
+    }
+    let r#if = reader.u8()?;
+    let data = if flags & DATA != 0 {
+        Some(reader.u8()?)
+    } else {
+        None
+    };
+    let bus = if flags & BUS != 0 {
+        let addr = reader.u16()?;
+        let op = if flags & READ != 0 {
+            Some(BusOp::Read)
+        } else if flags & WRITE != 0 {
+            Some(BusOp::Write(reader.u8()?))
+        } else {
+            None
+        };
+        Some(BusActivity { addr, op })
+    } else {
+        None
+    };
+    let ack = if flags & ACK != 0 { reader.u8()? } else { 0x00 };
+    Ok((Input { data, r#if }, Output { bus, ack }))
+}
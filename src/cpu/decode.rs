@@ -0,0 +1,349 @@
+use super::*;
+
+pub(super) fn split_opcode(opcode: u8) -> (u8, u8, u8) {
+    (opcode >> 6, (opcode >> 3) & 0b111, opcode & 0b111)
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum Instruction {
+    Nop,
+    Stop,
+    Halt,
+    Di,
+    Ei,
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    LdRR(R, R),
+    LdRN(R),
+    LdRDerefHl(R),
+    LdDerefHlR(R),
+    LdDerefHlN,
+    LdADerefBc,
+    LdADerefDe,
+    LdDerefBcA,
+    LdDerefDeA,
+    LdADerefHli,
+    LdADerefHld,
+    LdDerefHliA,
+    LdDerefHldA,
+    LdADerefNn,
+    LdDerefNnA,
+    LdADerefN,
+    LdDerefNA,
+    LdADerefC,
+    LdDerefCA,
+    LdDdNn(Dd),
+    LdDerefNnSp,
+    LdSpHl,
+    LdhlSpE,
+    AddSpE,
+    AddHlDd(Dd),
+    PushQq(Qq),
+    PopQq(Qq),
+    AluOpR(AluOp, R),
+    AluOpDerefHl(AluOp),
+    AluOpN(AluOp),
+    IncR(R),
+    IncDerefHl,
+    DecR(R),
+    DecDerefHl,
+    IncDd(Dd),
+    DecDd(Dd),
+    Jr(Option<Cc>),
+    Jp(Option<Cc>),
+    JpDerefHl,
+    Call(Option<Cc>),
+    Ret(Option<Cc>),
+    Reti,
+    Rst(u8),
+    CbPrefix,
+    Illegal,
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum CbOp {
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Swap,
+    Srl,
+    Bit(u8),
+    Res(u8),
+    Set(u8),
+}
+
+pub(super) fn decode(opcode: u8) -> Instruction {
+    use self::Instruction::*;
+    match split_opcode(opcode) {
+        (0b00, 0b000, 0b000) => Nop,
+        (0b00, 0b001, 0b000) => LdDerefNnSp,
+        (0b00, 0b010, 0b000) => Stop,
+        (0b00, 0b011, 0b000) => Jr(None),
+        (0b00, cc, 0b000) => Jr(Some((cc & 0b011).into())),
+        (0b00, dd, 0b001) if dd & 0b001 == 0 => LdDdNn((dd >> 1).into()),
+        (0b00, dd, 0b001) => AddHlDd((dd >> 1).into()),
+        (0b00, 0b000, 0b010) => LdDerefBcA,
+        (0b00, 0b001, 0b010) => LdADerefBc,
+        (0b00, 0b010, 0b010) => LdDerefDeA,
+        (0b00, 0b011, 0b010) => LdADerefDe,
+        (0b00, 0b100, 0b010) => LdDerefHliA,
+        (0b00, 0b101, 0b010) => LdADerefHli,
+        (0b00, 0b110, 0b010) => LdDerefHldA,
+        (0b00, 0b111, 0b010) => LdADerefHld,
+        (0b00, dd, 0b011) if dd & 0b001 == 0 => IncDd((dd >> 1).into()),
+        (0b00, dd, 0b011) => DecDd((dd >> 1).into()),
+        (0b00, 0b110, 0b100) => IncDerefHl,
+        (0b00, operand, 0b100) => IncR(operand.into()),
+        (0b00, 0b110, 0b101) => DecDerefHl,
+        (0b00, operand, 0b101) => DecR(operand.into()),
+        (0b00, 0b110, 0b110) => LdDerefHlN,
+        (0b00, dest, 0b110) => LdRN(dest.into()),
+        (0b00, 0b000, 0b111) => Rlca,
+        (0b00, 0b001, 0b111) => Rrca,
+        (0b00, 0b010, 0b111) => Rla,
+        (0b00, 0b011, 0b111) => Rra,
+        (0b00, 0b100, 0b111) => Daa,
+        (0b00, 0b101, 0b111) => Cpl,
+        (0b00, 0b110, 0b111) => Scf,
+        (0b00, _, 0b111) => Ccf,
+        (0b01, 0b110, 0b110) => Halt,
+        (0b01, dest, 0b110) => LdRDerefHl(dest.into()),
+        (0b01, 0b110, src) => LdDerefHlR(src.into()),
+        (0b01, dest, src) => LdRR(dest.into(), src.into()),
+        (0b10, op, 0b110) => AluOpDerefHl(op.into()),
+        (0b10, op, src) => AluOpR(op.into(), src.into()),
+        (0b11, cc, 0b000) if cc <= 0b011 => Ret(Some(cc.into())),
+        (0b11, 0b100, 0b000) => LdDerefNA,
+        (0b11, 0b101, 0b000) => AddSpE,
+        (0b11, 0b110, 0b000) => LdADerefN,
+        (0b11, _, 0b000) => LdhlSpE,
+        (0b11, qq, 0b001) if qq & 0b001 == 0 => PopQq((qq >> 1).into()),
+        (0b11, 0b001, 0b001) => Ret(None),
+        (0b11, 0b011, 0b001) => Reti,
+        (0b11, 0b101, 0b001) => JpDerefHl,
+        (0b11, _, 0b001) => LdSpHl,
+        (0b11, cc, 0b010) if cc <= 0b011 => Jp(Some(cc.into())),
+        (0b11, 0b100, 0b010) => LdDerefCA,
+        (0b11, 0b101, 0b010) => LdDerefNnA,
+        (0b11, 0b110, 0b010) => LdADerefC,
+        (0b11, _, 0b010) => LdADerefNn,
+        (0b11, 0b000, 0b011) => Jp(None),
+        (0b11, 0b001, 0b011) => CbPrefix,
+        (0b11, 0b110, 0b011) => Di,
+        (0b11, 0b111, 0b011) => Ei,
+        (0b11, cc, 0b100) if cc <= 0b011 => Call(Some(cc.into())),
+        (0b11, qq, 0b101) if qq & 0b001 == 0 => PushQq((qq >> 1).into()),
+        (0b11, 0b001, 0b101) => Call(None),
+        (0b11, op, 0b110) => AluOpN(op.into()),
+        (0b11, n, 0b111) => Rst(n << 3),
+        _ => Illegal,
+    }
+}
+
+pub(super) fn decode_cb(opcode: u8) -> (CbOp, Option<R>) {
+    let (x, y, z) = split_opcode(opcode);
+    let op = match (x, y) {
+        (0b00, 0b000) => CbOp::Rlc,
+        (0b00, 0b001) => CbOp::Rrc,
+        (0b00, 0b010) => CbOp::Rl,
+        (0b00, 0b011) => CbOp::Rr,
+        (0b00, 0b100) => CbOp::Sla,
+        (0b00, 0b101) => CbOp::Sra,
+        (0b00, 0b110) => CbOp::Swap,
+        (0b00, _) => CbOp::Srl,
+        (0b01, bit) => CbOp::Bit(bit),
+        (0b10, bit) => CbOp::Res(bit),
+        (_, bit) => CbOp::Set(bit),
+    };
+    let operand = if z == 0b110 { None } else { Some(z.into()) };
+    (op, operand)
+}
+
+pub(super) fn encode(instruction: Instruction) -> Option<u8> {
+    use self::Instruction::*;
+    Some(match instruction {
+        Nop => 0x00,
+        Stop => 0x10,
+        Halt => 0x76,
+        Di => 0xf3,
+        Ei => 0xfb,
+        Rlca => 0x07,
+        Rrca => 0x0f,
+        Rla => 0x17,
+        Rra => 0x1f,
+        Daa => 0x27,
+        Cpl => 0x2f,
+        Scf => 0x37,
+        Ccf => 0x3f,
+        LdRR(dest, src) => 0b01_000_000 | dest.code() << 3 | src.code(),
+        LdRN(dest) => 0b00_000_110 | dest.code() << 3,
+        LdRDerefHl(dest) => 0b01_000_110 | dest.code() << 3,
+        LdDerefHlR(src) => 0b01_110_000 | src.code(),
+        LdDerefHlN => 0x36,
+        LdADerefBc => 0x0a,
+        LdADerefDe => 0x1a,
+        LdDerefBcA => 0x02,
+        LdDerefDeA => 0x12,
+        LdADerefHli => 0x2a,
+        LdADerefHld => 0x3a,
+        LdDerefHliA => 0x22,
+        LdDerefHldA => 0x32,
+        LdADerefNn => 0xfa,
+        LdDerefNnA => 0xea,
+        LdADerefN => 0xf0,
+        LdDerefNA => 0xe0,
+        LdADerefC => 0xf2,
+        LdDerefCA => 0xe2,
+        LdDdNn(dd) => 0b00_000_001 | dd.encode() << 4,
+        LdDerefNnSp => 0x08,
+        LdSpHl => 0xf9,
+        LdhlSpE => 0xf8,
+        AddSpE => 0xe8,
+        AddHlDd(dd) => 0b00_001_001 | dd.encode() << 4,
+        PushQq(qq) => 0b11_000_101 | qq.encode() << 4,
+        PopQq(qq) => 0b11_000_001 | qq.encode() << 4,
+        AluOpR(op, src) => 0b10_000_000 | op.encode() << 3 | src.code(),
+        AluOpDerefHl(op) => 0b10_000_110 | op.encode() << 3,
+        AluOpN(op) => 0b11_000_110 | op.encode() << 3,
+        IncR(r) => 0b00_000_100 | r.code() << 3,
+        IncDerefHl => 0x34,
+        DecR(r) => 0b00_000_101 | r.code() << 3,
+        DecDerefHl => 0x35,
+        IncDd(dd) => 0b00_000_011 | dd.encode() << 4,
+        DecDd(dd) => 0b00_001_011 | dd.encode() << 4,
+        Jr(None) => 0x18,
+        Jr(Some(cc)) => 0b00_100_000 | cc.encode() << 3,
+        Jp(None) => 0xc3,
+        Jp(Some(cc)) => 0b11_000_010 | cc.encode() << 3,
+        JpDerefHl => 0xe9,
+        Call(None) => 0xcd,
+        Call(Some(cc)) => 0b11_000_100 | cc.encode() << 3,
+        Ret(None) => 0xc9,
+        Ret(Some(cc)) => 0b11_000_000 | cc.encode() << 3,
+        Reti => 0xd9,
+        Rst(addr) => 0b11_000_111 | addr & 0b00_111_000,
+        CbPrefix => 0xcb,
+        Illegal => return None,
+    })
+}
+
+pub(super) fn encode_cb(op: CbOp, operand: Option<R>) -> u8 {
+    let (x, y) = match op {
+        CbOp::Rlc => (0b00, 0b000),
+        CbOp::Rrc => (0b00, 0b001),
+        CbOp::Rl => (0b00, 0b010),
+        CbOp::Rr => (0b00, 0b011),
+        CbOp::Sla => (0b00, 0b100),
+        CbOp::Sra => (0b00, 0b101),
+        CbOp::Swap => (0b00, 0b110),
+        CbOp::Srl => (0b00, 0b111),
+        CbOp::Bit(bit) => (0b01, bit),
+        CbOp::Res(bit) => (0b10, bit),
+        CbOp::Set(bit) => (0b11, bit),
+    };
+    x << 6 | (y & 0b111) << 3 | operand.map_or(0b110, R::code)
+}
+
+impl R {
+    pub(super) fn code(self) -> u8 {
+        match self {
+            R::A => 0b111,
+            R::B => 0b000,
+            R::C => 0b001,
+            R::D => 0b010,
+            R::E => 0b011,
+            R::H => 0b100,
+            R::L => 0b101,
+        }
+    }
+}
+
+impl Dd {
+    pub(super) fn encode(self) -> u8 {
+        match self {
+            Dd::Bc => 0b00,
+            Dd::De => 0b01,
+            Dd::Hl => 0b10,
+            Dd::Sp => 0b11,
+        }
+    }
+}
+
+impl Qq {
+    pub(super) fn encode(self) -> u8 {
+        match self {
+            Qq::Bc => 0b00,
+            Qq::De => 0b01,
+            Qq::Hl => 0b10,
+            Qq::Af => 0b11,
+        }
+    }
+}
+
+impl Cc {
+    fn encode(self) -> u8 {
+        match self {
+            Cc::Nz => 0b00,
+            Cc::Z => 0b01,
+            Cc::Nc => 0b10,
+            Cc::C => 0b11,
+        }
+    }
+}
+
+impl AluOp {
+    fn encode(self) -> u8 {
+        match self {
+            AluOp::Add => 0b000,
+            AluOp::Adc => 0b001,
+            AluOp::Sub => 0b010,
+            AluOp::Sbc => 0b011,
+            AluOp::And => 0b100,
+            AluOp::Xor => 0b101,
+            AluOp::Or => 0b110,
+            AluOp::Cp => 0b111,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_inverts_decode() {
+        for opcode in 0x00..=0xff {
+            if let Some(encoding) = encode(decode(opcode)) {
+                assert_eq!(encoding, opcode)
+            }
+        }
+    }
+
+    #[test]
+    fn encode_cb_inverts_decode_cb() {
+        for opcode in 0x00..=0xff {
+            let (op, operand) = decode_cb(opcode);
+            assert_eq!(encode_cb(op, operand), opcode)
+        }
+    }
+
+    #[test]
+    fn only_unused_opcodes_are_illegal() {
+        let illegal: Vec<u8> = (0x00..=0xff)
+            .filter(|&opcode| encode(decode(opcode)).is_none())
+            .collect();
+        assert_eq!(
+            illegal,
+            [0xd3, 0xdb, 0xdd, 0xe3, 0xe4, 0xeb, 0xec, 0xed, 0xf4, 0xfc, 0xfd]
+        )
+    }
+}
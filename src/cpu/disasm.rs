@@ -0,0 +1,482 @@
+use super::decode::{decode, decode_cb, CbOp, Instruction};
+use super::*;
+
+use crate::symbols::{Location, Symbols};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Disassembly {
+    pub mnemonic: String,
+    pub len: u8,
+    pub m_cycles: u8,
+    pub m_cycles_taken: Option<u8>,
+}
+
+pub fn disassemble(bytes: &[u8], pc: u16) -> Option<Disassembly> {
+    disassemble_named(bytes, pc, &hex_addr)
+}
+
+// Names a 16-bit operand after the closest label at or before that address,
+// e.g. `CALL $4000` becomes `CALL UpdateSprites` and `LD HL,$C001` becomes
+// `LD HL,wCounter+$1`. `bank` gives the bank mapped at an address.
+pub fn disassemble_with_symbols(
+    bytes: &[u8],
+    pc: u16,
+    symbols: &Symbols,
+    bank: impl Fn(u16) -> u16,
+) -> Option<Disassembly> {
+    disassemble_named(bytes, pc, &|addr| {
+        symbols
+            .symbolize(Location {
+                bank: bank(addr),
+                addr,
+            })
+            .unwrap_or_else(|| hex_addr(addr))
+    })
+}
+
+fn hex_addr(addr: u16) -> String {
+    format!("${:04X}", addr)
+}
+
+fn disassemble_named(bytes: &[u8], pc: u16, name: &dyn Fn(u16) -> String) -> Option<Disassembly> {
+    let opcode = *bytes.first()?;
+    let instruction = decode(opcode);
+    let len = instruction_len(instruction);
+    let bytes = bytes.get(..usize::from(len))?;
+    let n = bytes.get(1).copied().unwrap_or(0x00);
+    let nn = u16::from_le_bytes([n, bytes.get(2).copied().unwrap_or(0x00)]);
+    let e = n as i8;
+    let (m_cycles, m_cycles_taken) = m_cycles(instruction, n);
+    let mnemonic = match instruction {
+        Instruction::Nop => "NOP".to_string(),
+        Instruction::Stop if n == 0x00 => "STOP".to_string(),
+        Instruction::Stop => format!("STOP ${:02X}", n),
+        Instruction::Halt => "HALT".to_string(),
+        Instruction::Di => "DI".to_string(),
+        Instruction::Ei => "EI".to_string(),
+        Instruction::Rlca => "RLCA".to_string(),
+        Instruction::Rrca => "RRCA".to_string(),
+        Instruction::Rla => "RLA".to_string(),
+        Instruction::Rra => "RRA".to_string(),
+        Instruction::Daa => "DAA".to_string(),
+        Instruction::Cpl => "CPL".to_string(),
+        Instruction::Scf => "SCF".to_string(),
+        Instruction::Ccf => "CCF".to_string(),
+        Instruction::LdRR(dest, src) => format!("LD {},{}", dest.name(), src.name()),
+        Instruction::LdRN(dest) => format!("LD {},${:02X}", dest.name(), n),
+        Instruction::LdRDerefHl(dest) => format!("LD {},(HL)", dest.name()),
+        Instruction::LdDerefHlR(src) => format!("LD (HL),{}", src.name()),
+        Instruction::LdDerefHlN => format!("LD (HL),${:02X}", n),
+        Instruction::LdADerefBc => "LD A,(BC)".to_string(),
+        Instruction::LdADerefDe => "LD A,(DE)".to_string(),
+        Instruction::LdDerefBcA => "LD (BC),A".to_string(),
+        Instruction::LdDerefDeA => "LD (DE),A".to_string(),
+        Instruction::LdADerefHli => "LD A,(HL+)".to_string(),
+        Instruction::LdADerefHld => "LD A,(HL-)".to_string(),
+        Instruction::LdDerefHliA => "LD (HL+),A".to_string(),
+        Instruction::LdDerefHldA => "LD (HL-),A".to_string(),
+        Instruction::LdADerefNn => format!("LD A,({})", name(nn)),
+        Instruction::LdDerefNnA => format!("LD ({}),A", name(nn)),
+        Instruction::LdADerefN => format!("LDH A,(${:02X})", n),
+        Instruction::LdDerefNA => format!("LDH (${:02X}),A", n),
+        Instruction::LdADerefC => "LD A,(C)".to_string(),
+        Instruction::LdDerefCA => "LD (C),A".to_string(),
+        Instruction::LdDdNn(dd) => format!("LD {},{}", dd.name(), name(nn)),
+        Instruction::LdDerefNnSp => format!("LD ({}),SP", name(nn)),
+        Instruction::LdSpHl => "LD SP,HL".to_string(),
+        Instruction::LdhlSpE => format!("LD HL,SP{:+}", e),
+        Instruction::AddSpE => format!("ADD SP,{}", e),
+        Instruction::AddHlDd(dd) => format!("ADD HL,{}", dd.name()),
+        Instruction::PushQq(qq) => format!("PUSH {}", qq.name()),
+        Instruction::PopQq(qq) => format!("POP {}", qq.name()),
+        Instruction::AluOpR(op, src) => format!("{}{}", op.prefix(), src.name()),
+        Instruction::AluOpDerefHl(op) => format!("{}(HL)", op.prefix()),
+        Instruction::AluOpN(op) => format!("{}${:02X}", op.prefix(), n),
+        Instruction::IncR(r) => format!("INC {}", r.name()),
+        Instruction::IncDerefHl => "INC (HL)".to_string(),
+        Instruction::DecR(r) => format!("DEC {}", r.name()),
+        Instruction::DecDerefHl => "DEC (HL)".to_string(),
+        Instruction::IncDd(dd) => format!("INC {}", dd.name()),
+        Instruction::DecDd(dd) => format!("DEC {}", dd.name()),
+        Instruction::Jr(cc) => {
+            let target = pc.wrapping_add(2).wrapping_add(e as u16);
+            format!("JR {}{}", condition(cc), name(target))
+        }
+        Instruction::Jp(cc) => format!("JP {}{}", condition(cc), name(nn)),
+        Instruction::JpDerefHl => "JP HL".to_string(),
+        Instruction::Call(cc) => format!("CALL {}{}", condition(cc), name(nn)),
+        Instruction::Ret(None) => "RET".to_string(),
+        Instruction::Ret(Some(cc)) => format!("RET {}", cc.name()),
+        Instruction::Reti => "RETI".to_string(),
+        Instruction::Rst(addr) => format!("RST ${:02X}", addr),
+        Instruction::CbPrefix => {
+            let (op, operand) = decode_cb(n);
+            let operand = operand.map_or("(HL)", R::name);
+            match op {
+                CbOp::Bit(bit) => format!("BIT {},{}", bit, operand),
+                CbOp::Res(bit) => format!("RES {},{}", bit, operand),
+                CbOp::Set(bit) => format!("SET {},{}", bit, operand),
+                op => format!("{} {}", op.name(), operand),
+            }
+        }
+        Instruction::Illegal => format!("DB ${:02X}", opcode),
+    };
+    Some(Disassembly {
+        mnemonic,
+        len,
+        m_cycles,
+        m_cycles_taken,
+    })
+}
+
+fn instruction_len(instruction: Instruction) -> u8 {
+    match instruction {
+        Instruction::Stop
+        | Instruction::LdRN(_)
+        | Instruction::LdDerefHlN
+        | Instruction::LdADerefN
+        | Instruction::LdDerefNA
+        | Instruction::LdhlSpE
+        | Instruction::AddSpE
+        | Instruction::AluOpN(_)
+        | Instruction::Jr(_)
+        | Instruction::CbPrefix => 2,
+        Instruction::LdADerefNn
+        | Instruction::LdDerefNnA
+        | Instruction::LdDdNn(_)
+        | Instruction::LdDerefNnSp
+        | Instruction::Jp(_)
+        | Instruction::Call(_) => 3,
+        _ => 1,
+    }
+}
+
+fn m_cycles(instruction: Instruction, cb_opcode: u8) -> (u8, Option<u8>) {
+    let m_cycles = match instruction {
+        Instruction::LdRN(_)
+        | Instruction::LdRDerefHl(_)
+        | Instruction::LdDerefHlR(_)
+        | Instruction::LdADerefBc
+        | Instruction::LdADerefDe
+        | Instruction::LdDerefBcA
+        | Instruction::LdDerefDeA
+        | Instruction::LdADerefHli
+        | Instruction::LdADerefHld
+        | Instruction::LdDerefHliA
+        | Instruction::LdDerefHldA
+        | Instruction::LdADerefC
+        | Instruction::LdDerefCA
+        | Instruction::LdSpHl
+        | Instruction::AddHlDd(_)
+        | Instruction::AluOpDerefHl(_)
+        | Instruction::AluOpN(_)
+        | Instruction::IncDd(_)
+        | Instruction::DecDd(_) => 2,
+        Instruction::LdDerefHlN
+        | Instruction::LdADerefN
+        | Instruction::LdDerefNA
+        | Instruction::LdDdNn(_)
+        | Instruction::LdhlSpE
+        | Instruction::PopQq(_)
+        | Instruction::IncDerefHl
+        | Instruction::DecDerefHl => 3,
+        Instruction::LdADerefNn
+        | Instruction::LdDerefNnA
+        | Instruction::AddSpE
+        | Instruction::PushQq(_)
+        | Instruction::Ret(None)
+        | Instruction::Reti
+        | Instruction::Rst(_) => 4,
+        Instruction::LdDerefNnSp => 5,
+        Instruction::Jr(None) => 3,
+        Instruction::Jp(None) => 4,
+        Instruction::Call(None) => 6,
+        Instruction::Jr(Some(_)) => return (2, Some(3)),
+        Instruction::Jp(Some(_)) => return (3, Some(4)),
+        Instruction::Call(Some(_)) => return (3, Some(6)),
+        Instruction::Ret(Some(_)) => return (2, Some(5)),
+        Instruction::CbPrefix => match decode_cb(cb_opcode) {
+            (_, Some(_)) => 2,
+            (CbOp::Bit(_), None) => 3,
+            (_, None) => 4,
+        },
+        _ => 1,
+    };
+    (m_cycles, None)
+}
+
+fn condition(cc: Option<Cc>) -> String {
+    cc.map_or(String::new(), |cc| format!("{},", cc.name()))
+}
+
+impl R {
+    fn name(self) -> &'static str {
+        match self {
+            R::A => "A",
+            R::B => "B",
+            R::C => "C",
+            R::D => "D",
+            R::E => "E",
+            R::H => "H",
+            R::L => "L",
+        }
+    }
+}
+
+impl Dd {
+    fn name(self) -> &'static str {
+        match self {
+            Dd::Bc => "BC",
+            Dd::De => "DE",
+            Dd::Hl => "HL",
+            Dd::Sp => "SP",
+        }
+    }
+}
+
+impl Qq {
+    fn name(self) -> &'static str {
+        match self {
+            Qq::Bc => "BC",
+            Qq::De => "DE",
+            Qq::Hl => "HL",
+            Qq::Af => "AF",
+        }
+    }
+}
+
+impl Cc {
+    fn name(self) -> &'static str {
+        match self {
+            Cc::Nz => "NZ",
+            Cc::Z => "Z",
+            Cc::Nc => "NC",
+            Cc::C => "C",
+        }
+    }
+}
+
+impl AluOp {
+    fn prefix(self) -> &'static str {
+        match self {
+            AluOp::Add => "ADD A,",
+            AluOp::Adc => "ADC A,",
+            AluOp::Sub => "SUB ",
+            AluOp::Sbc => "SBC A,",
+            AluOp::And => "AND ",
+            AluOp::Xor => "XOR ",
+            AluOp::Or => "OR ",
+            AluOp::Cp => "CP ",
+        }
+    }
+}
+
+impl CbOp {
+    fn name(self) -> &'static str {
+        match self {
+            CbOp::Rlc => "RLC",
+            CbOp::Rrc => "RRC",
+            CbOp::Rl => "RL",
+            CbOp::Rr => "RR",
+            CbOp::Sla => "SLA",
+            CbOp::Sra => "SRA",
+            CbOp::Swap => "SWAP",
+            CbOp::Srl => "SRL",
+            CbOp::Bit(_) => "BIT",
+            CbOp::Res(_) => "RES",
+            CbOp::Set(_) => "SET",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mnemonic(bytes: &[u8], pc: u16) -> String {
+        disassemble(bytes, pc).unwrap().mnemonic
+    }
+
+    #[test]
+    fn loads_are_disassembled() {
+        assert_eq!(mnemonic(&[0x2a], 0), "LD A,(HL+)");
+        assert_eq!(mnemonic(&[0x32], 0), "LD (HL-),A");
+        assert_eq!(mnemonic(&[0x41], 0), "LD B,C");
+        assert_eq!(mnemonic(&[0x36, 0x42], 0), "LD (HL),$42");
+        assert_eq!(mnemonic(&[0x01, 0x34, 0x12], 0), "LD BC,$1234");
+        assert_eq!(mnemonic(&[0xe0, 0x40], 0), "LDH ($40),A");
+        assert_eq!(mnemonic(&[0xf8, 0xfe], 0), "LD HL,SP-2")
+    }
+
+    #[test]
+    fn relative_jumps_show_target_address() {
+        assert_eq!(mnemonic(&[0x20, 0xfe], 0x0150), "JR NZ,$0150");
+        assert_eq!(mnemonic(&[0x18, 0x10], 0x0150), "JR $0162")
+    }
+
+    #[test]
+    fn operands_are_named_after_labels() {
+        let symbols =
+            Symbols::from_sym("00:0150 Main\n01:4000 Update\n00:c000 wCounter\n").unwrap();
+        let bank = |addr| {
+            if (0x4000..0x8000).contains(&addr) {
+                1
+            } else {
+                0
+            }
+        };
+        let mnemonic = |bytes: &[u8]| {
+            disassemble_with_symbols(bytes, 0x0150, &symbols, bank)
+                .unwrap()
+                .mnemonic
+        };
+        assert_eq!(mnemonic(&[0xcd, 0x00, 0x40]), "CALL Update");
+        assert_eq!(mnemonic(&[0x18, 0xfe]), "JR Main");
+        assert_eq!(mnemonic(&[0xfa, 0x00, 0xc0]), "LD A,(wCounter)");
+        assert_eq!(mnemonic(&[0x21, 0x01, 0xc0]), "LD HL,wCounter+$1");
+        assert_eq!(mnemonic(&[0x01, 0x00, 0x80]), "LD BC,$8000");
+        assert_eq!(mnemonic(&[0x3e, 0x40]), "LD A,$40")
+    }
+
+    #[test]
+    fn cb_page_is_disassembled() {
+        assert_eq!(mnemonic(&[0xcb, 0x7c], 0), "BIT 7,H");
+        assert_eq!(mnemonic(&[0xcb, 0x86], 0), "RES 0,(HL)");
+        assert_eq!(mnemonic(&[0xcb, 0x37], 0), "SWAP A")
+    }
+
+    #[test]
+    fn length_and_cycles_are_reported() {
+        assert_eq!(
+            disassemble(&[0xc4, 0x00, 0x40], 0),
+            Some(Disassembly {
+                mnemonic: "CALL NZ,$4000".to_string(),
+                len: 3,
+                m_cycles: 3,
+                m_cycles_taken: Some(6),
+            })
+        );
+        let disassembly = disassemble(&[0xcb, 0x46], 0).unwrap();
+        assert_eq!((disassembly.len, disassembly.m_cycles), (2, 3))
+    }
+
+    #[test]
+    fn truncated_instruction_is_not_disassembled() {
+        assert_eq!(disassemble(&[0xc3, 0x00], 0), None);
+        assert_eq!(disassemble(&[], 0), None)
+    }
+
+    #[test]
+    fn illegal_opcodes_are_data() {
+        assert_eq!(mnemonic(&[0xd3], 0), "DB $D3")
+    }
+
+    // Instructions the executor implements, except HALT and STOP, which don't
+    // fetch another opcode on their own.
+    fn is_executed(instruction: Instruction) -> bool {
+        matches!(
+            instruction,
+            Instruction::Nop
+                | Instruction::Jr(_)
+                | Instruction::LdDdNn(_)
+                | Instruction::LdDerefBcA
+                | Instruction::IncDerefHl
+                | Instruction::IncR(_)
+                | Instruction::LdDerefHlN
+                | Instruction::LdRN(_)
+                | Instruction::LdDerefNnSp
+                | Instruction::LdADerefBc
+                | Instruction::LdDerefDeA
+                | Instruction::LdADerefDe
+                | Instruction::LdDerefHliA
+                | Instruction::LdADerefHli
+                | Instruction::LdDerefHldA
+                | Instruction::LdADerefHld
+                | Instruction::LdRDerefHl(_)
+                | Instruction::LdDerefHlR(_)
+                | Instruction::LdRR(_, _)
+                | Instruction::AluOpDerefHl(_)
+                | Instruction::AluOpR(_, _)
+                | Instruction::PopQq(_)
+                | Instruction::Jp(_)
+                | Instruction::Call(_)
+                | Instruction::PushQq(_)
+                | Instruction::AluOpN(_)
+                | Instruction::Ret(None)
+                | Instruction::LdDerefNA
+                | Instruction::LdDerefCA
+                | Instruction::JpDerefHl
+                | Instruction::LdDerefNnA
+                | Instruction::LdADerefN
+                | Instruction::LdADerefC
+                | Instruction::LdhlSpE
+                | Instruction::LdSpHl
+                | Instruction::LdADerefNn
+        )
+    }
+
+    // Runs the instruction at 0x0000 with all flags clear and counts M-cycles
+    // until the next opcode fetch.
+    fn executed_m_cycles(bytes: &[u8]) -> u8 {
+        let mut memory = vec![0x00; 0x10000];
+        memory[..bytes.len()].copy_from_slice(bytes);
+        let mut cpu = Cpu::default();
+        cpu.data.sp = 0xd000;
+        cpu.data.h = 0xc0;
+        let mut data = None;
+        let mut boundaries = 0;
+        let mut half_cycles = 0;
+        for _ in 0..64 {
+            let output = cpu.step(&Input {
+                data: data.take(),
+                r#if: 0x00,
+            });
+            match output.bus {
+                Some(BusActivity {
+                    addr,
+                    op: Some(BusOp::Read),
+                }) => data = Some(memory[usize::from(addr)]),
+                Some(BusActivity {
+                    addr,
+                    op: Some(BusOp::Write(value)),
+                }) => memory[usize::from(addr)] = value,
+                _ => (),
+            }
+            if boundaries == 1 {
+                half_cycles += 1
+            }
+            if cpu.status().at_instruction_boundary {
+                boundaries += 1;
+                if boundaries == 2 {
+                    return half_cycles / 2;
+                }
+            }
+        }
+        panic!("{:02X?} never fetched the next opcode", bytes)
+    }
+
+    #[test]
+    fn cycles_match_executor() {
+        for opcode in (0x00..=0xff).filter(|&opcode| is_executed(decode(opcode))) {
+            let bytes = [opcode, 0x00, 0x00];
+            let disassembly = disassemble(&bytes, 0).unwrap();
+            // With all flags clear, NZ and NC are taken while Z and C fall through.
+            let expected = match decode(opcode) {
+                Instruction::Jr(Some(cc))
+                | Instruction::Jp(Some(cc))
+                | Instruction::Call(Some(cc))
+                    if matches!(cc, Cc::Nz | Cc::Nc) =>
+                {
+                    disassembly.m_cycles_taken.unwrap()
+                }
+                _ => disassembly.m_cycles,
+            };
+            assert_eq!(
+                executed_m_cycles(&bytes),
+                expected,
+                "{}",
+                disassembly.mnemonic
+            )
+        }
+    }
+}
@@ -1,9 +1,6 @@
+use super::decode::{decode, Instruction};
 use super::*;
 
-fn split_opcode(opcode: u8) -> (u8, u8, u8) {
-    (opcode >> 6, (opcode >> 3) & 0b111, opcode & 0b111)
-}
-
 impl<'a> RunView<'a, InstructionExecutionState> {
     pub(super) fn step(&mut self, input: &Input) -> (Option<ModeTransition>, Output) {
         match self.basic.phase {
@@ -25,10 +22,8 @@ impl<'a> RunView<'a, InstructionExecutionState> {
                         self.basic.pc += 1;
                         ModeTransition::Instruction(self.state.bus_data.unwrap())
                     })
-                } else if let Some(standby) = self.state.standby {
-                    Some(standby.into())
                 } else {
-                    None
+                    self.state.standby.map(Into::into)
                 };
                 (transition, Default::default())
             }
@@ -36,47 +31,45 @@ impl<'a> RunView<'a, InstructionExecutionState> {
     }
 
     fn exec_instr(&mut self) -> Output {
-        let bus = match split_opcode(self.state.opcode) {
-            (0b00, 0b000, 0b000) => self.nop(),
-            (0b00, cc, 0b000) if cc & 0b100 > 0 => self.jr(Some((cc & 0b011).into())),
-            (0b00, dest, 0b001) if dest & 0b001 == 0 => self.ld_dd_nn((dest >> 1).into()),
-            (0b00, 0b000, 0b010) => self.ld_deref_bc_a(),
-            (0b00, 0b110, 0b100) => self.inc_deref_hl(),
-            (0b00, operand, 0b100) => self.inc_r(operand.into()),
-            (0b00, 0b110, 0b110) => self.ld_deref_hl_n(),
-            (0b00, dest, 0b110) => self.ld_r_n(dest.into()),
-            (0b00, 0b001, 0b000) => self.ld_deref_nn_sp(),
-            (0b00, 0b001, 0b010) => self.ld_a_deref_bc(),
-            (0b00, 0b010, 0b010) => self.ld_deref_de_a(),
-            (0b00, 0b011, 0b000) => self.jr(None),
-            (0b00, 0b011, 0b010) => self.ld_a_deref_de(),
-            (0b00, 0b100, 0b010) => self.ld_deref_hli_a(),
-            (0b00, 0b101, 0b010) => self.ld_a_deref_hli(),
-            (0b00, 0b110, 0b010) => self.ld_deref_hld_a(),
-            (0b00, 0b111, 0b010) => self.ld_a_deref_hld(),
-            (0b01, 0b110, 0b110) => self.halt(),
-            (0b01, dest, 0b110) => self.ld_r_deref_hl(dest.into()),
-            (0b01, 0b110, src) => self.ld_deref_hl_r(src.into()),
-            (0b01, dest, src) => self.ld_r_r(dest.into(), src.into()),
-            (0b10, op, 0b110) => self.alu_op_deref_hl(op.into()),
-            (0b10, op, src) => self.alu_op_r(op.into(), src.into()),
-            (0b11, dest, 0b001) if dest & 0b001 == 0 => self.pop_qq((dest >> 1).into()),
-            (0b11, 0b000, 0b011) => self.jp(None),
-            (0b11, cc, 0b010) if cc <= 0b011 => self.jp(Some(cc.into())),
-            (0b11, cc, 0b100) if cc <= 0b011 => self.call(Some(cc.into())),
-            (0b11, src, 0b101) if src & 0b001 == 0 => self.push_qq((src >> 1).into()),
-            (0b11, op, 0b110) => self.alu_op_n(op.into()),
-            (0b11, 0b001, 0b001) => self.ret(),
-            (0b11, 0b001, 0b101) => self.call(None),
-            (0b11, 0b100, 0b000) => self.ld_deref_n_a(),
-            (0b11, 0b100, 0b010) => self.ld_deref_c_a(),
-            (0b11, 0b101, 0b001) => self.jp_deref_hl(),
-            (0b11, 0b101, 0b010) => self.ld_deref_nn_a(),
-            (0b11, 0b110, 0b000) => self.ld_a_deref_n(),
-            (0b11, 0b110, 0b010) => self.ld_a_deref_c(),
-            (0b11, 0b111, 0b000) => self.ldhl_sp_e(),
-            (0b11, 0b111, 0b001) => self.ld_sp_hl(),
-            (0b11, 0b111, 0b010) => self.ld_a_deref_nn(),
+        let bus = match decode(self.state.opcode) {
+            Instruction::Nop => self.nop(),
+            Instruction::Stop => self.stop(),
+            Instruction::Jr(cc) => self.jr(cc),
+            Instruction::LdDdNn(dd) => self.ld_dd_nn(dd),
+            Instruction::LdDerefBcA => self.ld_deref_bc_a(),
+            Instruction::IncDerefHl => self.inc_deref_hl(),
+            Instruction::IncR(r) => self.inc_r(r),
+            Instruction::LdDerefHlN => self.ld_deref_hl_n(),
+            Instruction::LdRN(dest) => self.ld_r_n(dest),
+            Instruction::LdDerefNnSp => self.ld_deref_nn_sp(),
+            Instruction::LdADerefBc => self.ld_a_deref_bc(),
+            Instruction::LdDerefDeA => self.ld_deref_de_a(),
+            Instruction::LdADerefDe => self.ld_a_deref_de(),
+            Instruction::LdDerefHliA => self.ld_deref_hli_a(),
+            Instruction::LdADerefHli => self.ld_a_deref_hli(),
+            Instruction::LdDerefHldA => self.ld_deref_hld_a(),
+            Instruction::LdADerefHld => self.ld_a_deref_hld(),
+            Instruction::Halt => self.halt(),
+            Instruction::LdRDerefHl(dest) => self.ld_r_deref_hl(dest),
+            Instruction::LdDerefHlR(src) => self.ld_deref_hl_r(src),
+            Instruction::LdRR(dest, src) => self.ld_r_r(dest, src),
+            Instruction::AluOpDerefHl(op) => self.alu_op_deref_hl(op),
+            Instruction::AluOpR(op, src) => self.alu_op_r(op, src),
+            Instruction::PopQq(qq) => self.pop_qq(qq),
+            Instruction::Jp(cc) => self.jp(cc),
+            Instruction::Call(cc) => self.call(cc),
+            Instruction::PushQq(qq) => self.push_qq(qq),
+            Instruction::AluOpN(op) => self.alu_op_n(op),
+            Instruction::Ret(None) => self.ret(),
+            Instruction::LdDerefNA => self.ld_deref_n_a(),
+            Instruction::LdDerefCA => self.ld_deref_c_a(),
+            Instruction::JpDerefHl => self.jp_deref_hl(),
+            Instruction::LdDerefNnA => self.ld_deref_nn_a(),
+            Instruction::LdADerefN => self.ld_a_deref_n(),
+            Instruction::LdADerefC => self.ld_a_deref_c(),
+            Instruction::LdhlSpE => self.ldhl_sp_e(),
+            Instruction::LdSpHl => self.ld_sp_hl(),
+            Instruction::LdADerefNn => self.ld_a_deref_nn(),
             _ => unimplemented!(),
         };
         Output { bus, ack: 0x00 }
@@ -99,6 +92,16 @@ impl<'a> RunView<'a, InstructionExecutionState> {
         }
     }
 
+    fn stop(&mut self) -> Option<BusActivity> {
+        match self.run.m_cycle {
+            M2 => {
+                self.state.standby = Some(Standby::Stop);
+                self.read_immediate()
+            }
+            _ => unreachable!(),
+        }
+    }
+
     fn ld_r_r(&mut self, dest: R, src: R) -> Option<BusActivity> {
         match self.run.m_cycle {
             M2 => {
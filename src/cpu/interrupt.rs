@@ -34,7 +34,7 @@ impl<'a> RunView<'a, InterruptDispatchState> {
                     let n = (input.r#if & self.basic.ie).trailing_zeros();
                     self.basic.pc = 0x0040 + 8 * n as u16;
                     (
-                        Some(ModeTransition::Instruction(NOP)),
+                        Some(ModeTransition::Resume),
                         Output {
                             bus: None,
                             ack: 1 << n,
@@ -1,5 +1,8 @@
 use self::{MCycle::*, Phase::*};
 
+use crate::interrupt::JOYPAD;
+use crate::Model;
+
 use std::ops::{BitAnd, BitOr, Not};
 
 #[cfg(test)]
@@ -17,17 +20,44 @@ macro_rules! flags {
     };
 }
 
+pub mod asm;
+pub mod bus_trace;
+mod decode;
+pub mod disasm;
 mod instruction;
 mod interrupt;
+mod state;
+pub mod trace;
 
 #[cfg(test)]
 mod tests;
 
 pub struct Cpu {
     pub data: BasicData,
+    model: Model,
     mode: Mode,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CpuStatus {
+    pub activity: Activity,
+    pub opcode: Option<u8>,
+    pub m_cycle: Option<u8>,
+    pub phase: Phase,
+    // The opcode has been fetched and none of its M-cycles have run yet. The
+    // NOP run after power-on, an interrupt dispatch or a wake-up doesn't count,
+    // since it was never fetched and pc already points past it.
+    pub at_instruction_boundary: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Activity {
+    Running,
+    Dispatching,
+    Halted,
+    Stopped,
+}
+
 #[derive(Default)]
 pub struct BasicData {
     // ISA registers
@@ -58,11 +88,14 @@ pub struct Flags {
 
 enum Mode {
     Halt(Halt),
+    Stop(Stop),
     Run(Run),
 }
 
 struct Halt;
 
+struct Stop;
+
 struct Run {
     data: RunData,
     task: Task,
@@ -85,29 +118,86 @@ struct InstructionExecutionState {
     read_ie: bool,
     standby: Option<Standby>,
     m1: bool,
+    fetched: bool,
 }
 
 #[derive(Clone, Copy)]
 enum Standby {
     Halt,
+    Stop,
 }
 
 struct InterruptDispatchState;
 
 impl Default for Cpu {
     fn default() -> Self {
-        Self {
-            data: Default::default(),
-            mode: Mode::Run(Run::new(Task::Instruction(InstructionExecutionState::new(
-                NOP,
-            )))),
-        }
+        Self::new(Model::Dmg)
     }
 }
 
 const NOP: u8 = 0x00;
 
 impl Cpu {
+    pub fn new(model: Model) -> Self {
+        Self {
+            data: Default::default(),
+            model,
+            mode: ModeTransition::Resume.into(),
+        }
+    }
+
+    pub fn post_boot(model: Model, header_checksum: u8) -> Self {
+        let mut cpu = Cpu::new(model);
+        let data = &mut cpu.data;
+        match model {
+            Model::Dmg0 => {
+                data.a = 0x01;
+                data.b = 0xff;
+                data.c = 0x13;
+                data.e = 0xc1;
+                data.h = 0x84;
+                data.l = 0x03;
+            }
+            Model::Dmg | Model::Mgb => {
+                data.a = if model == Model::Dmg { 0x01 } else { 0xff };
+                data.f = Flags {
+                    z: true,
+                    n: false,
+                    h: header_checksum != 0x00,
+                    cy: header_checksum != 0x00,
+                };
+                data.c = 0x13;
+                data.e = 0xd8;
+                data.h = 0x01;
+                data.l = 0x4d;
+            }
+            Model::Sgb | Model::Sgb2 => {
+                data.a = if model == Model::Sgb { 0x01 } else { 0xff };
+                data.c = 0x14;
+                data.h = 0xc0;
+                data.l = 0x60;
+            }
+            Model::Cgb | Model::Agb => {
+                data.a = 0x11;
+                data.f = Flags {
+                    z: model == Model::Cgb,
+                    ..Default::default()
+                };
+                data.b = if model == Model::Agb { 0x01 } else { 0x00 };
+                data.d = 0xff;
+                data.e = 0x56;
+                data.l = 0x0d;
+            }
+        }
+        data.pc = 0x0100;
+        data.sp = 0xfffe;
+        cpu
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
     pub fn step(&mut self, input: &Input) -> Output {
         let (transition, output) = match &mut self.mode {
             Mode::Halt(mode) => BasicView {
@@ -115,6 +205,11 @@ impl Cpu {
                 mode,
             }
             .step(input),
+            Mode::Stop(mode) => BasicView {
+                basic: &mut self.data,
+                mode,
+            }
+            .step(input),
             Mode::Run(mode) => BasicView {
                 basic: &mut self.data,
                 mode,
@@ -130,6 +225,49 @@ impl Cpu {
         }
         output
     }
+
+    pub fn status(&self) -> CpuStatus {
+        let (activity, opcode, m_cycle, fetched) = match &self.mode {
+            Mode::Halt(_) => (Activity::Halted, None, None, false),
+            Mode::Stop(_) => (Activity::Stopped, None, None, false),
+            Mode::Run(run) => match &run.task {
+                Task::Instruction(state) => (
+                    Activity::Running,
+                    Some(state.opcode),
+                    Some(run.data.m_cycle),
+                    state.fetched,
+                ),
+                Task::Interrupt(_) => (Activity::Dispatching, None, Some(run.data.m_cycle), false),
+            },
+        };
+        CpuStatus {
+            activity,
+            opcode,
+            m_cycle: m_cycle.map(MCycle::number),
+            phase: self.data.phase,
+            at_instruction_boundary: activity == Activity::Running
+                && fetched
+                && m_cycle == Some(M2)
+                && self.data.phase == Tick,
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        matches!(self.mode, Mode::Stop(_))
+    }
+
+    pub fn resume(&mut self) {
+        if self.is_stopped() {
+            self.mode = ModeTransition::Resume.into()
+        }
+    }
+
+    // Restarts execution at `pc` as if `opcode` had just been fetched from it.
+    pub fn jump(&mut self, pc: u16, opcode: u8) {
+        self.data.pc = pc.wrapping_add(1);
+        self.data.phase = Tick;
+        self.mode = ModeTransition::Instruction(opcode).into()
+    }
 }
 
 struct BasicView<'a, T> {
@@ -146,7 +284,7 @@ impl<'a> BasicView<'a, Halt> {
                     Some(if self.basic.ime {
                         ModeTransition::Interrupt
                     } else {
-                        ModeTransition::Instruction(NOP)
+                        ModeTransition::Resume
                     })
                 } else {
                     None
@@ -157,6 +295,16 @@ impl<'a> BasicView<'a, Halt> {
     }
 }
 
+impl<'a> BasicView<'a, Stop> {
+    fn step(&mut self, input: &Input) -> (Option<ModeTransition>, Output) {
+        let transition = match self.basic.phase {
+            Tock if input.r#if & JOYPAD != 0x00 => Some(ModeTransition::Resume),
+            _ => None,
+        };
+        (transition, Default::default())
+    }
+}
+
 impl<'a> BasicView<'a, Run> {
     fn step(&mut self, input: &Input) -> (Option<ModeTransition>, Output) {
         let result = match &mut self.mode.task {
@@ -321,6 +469,18 @@ enum MCycle {
 }
 
 impl MCycle {
+    fn number(self) -> u8 {
+        match self {
+            M2 => 2,
+            M3 => 3,
+            M4 => 4,
+            M5 => 5,
+            M6 => 6,
+            M7 => 7,
+            M8 => 8,
+        }
+    }
+
     fn next(self) -> Self {
         match self {
             M2 => M3,
@@ -337,7 +497,10 @@ impl MCycle {
 #[derive(Clone, Copy)]
 enum ModeTransition {
     Halt,
+    Stop,
     Instruction(u8),
+    // Runs a NOP that wasn't fetched, so the next fetch reads from pc.
+    Resume,
     Interrupt,
 }
 
@@ -345,8 +508,12 @@ impl From<ModeTransition> for Mode {
     fn from(transition: ModeTransition) -> Self {
         match transition {
             ModeTransition::Halt => Mode::Halt(Halt),
+            ModeTransition::Stop => Mode::Stop(Stop),
             ModeTransition::Instruction(opcode) => Mode::Run(Run::new(Task::Instruction(
-                InstructionExecutionState::new(opcode),
+                InstructionExecutionState::new(opcode, true),
+            ))),
+            ModeTransition::Resume => Mode::Run(Run::new(Task::Instruction(
+                InstructionExecutionState::new(NOP, false),
             ))),
             ModeTransition::Interrupt => {
                 Mode::Run(Run::new(Task::Interrupt(InterruptDispatchState)))
@@ -359,6 +526,7 @@ impl From<Standby> for ModeTransition {
     fn from(standby: Standby) -> Self {
         match standby {
             Standby::Halt => ModeTransition::Halt,
+            Standby::Stop => ModeTransition::Stop,
         }
     }
 }
@@ -373,7 +541,7 @@ impl Run {
 }
 
 impl InstructionExecutionState {
-    fn new(opcode: u8) -> Self {
+    fn new(opcode: u8, fetched: bool) -> Self {
         Self {
             opcode,
             w: None,
@@ -382,6 +550,7 @@ impl InstructionExecutionState {
             bus_data: None,
             read_ie: false,
             standby: None,
+            fetched,
         }
     }
 }
@@ -402,30 +571,28 @@ fn sign_extension(data: u8) -> u8 {
     }
 }
 
+#[cfg(test)]
 #[derive(Clone)]
 struct AluOutput {
     result: u8,
     flags: Flags,
 }
 
+pub type CpuTrace = Vec<(Input, Output)>;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Input {
-    data: Option<u8>,
-    r#if: u8,
+    pub data: Option<u8>,
+    pub r#if: u8,
 }
 
-#[derive(Clone, Copy, PartialEq)]
-enum Phase {
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Phase {
+    #[default]
     Tick,
     Tock,
 }
 
-impl Default for Phase {
-    fn default() -> Self {
-        Tick
-    }
-}
-
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Output {
     pub bus: Option<BusActivity>,
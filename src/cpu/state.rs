@@ -0,0 +1,138 @@
+use super::*;
+
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+use std::convert::TryFrom;
+
+const STATE_VERSION: u8 = 1;
+
+impl Cpu {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.u8(STATE_VERSION);
+        self.save(&mut writer);
+        writer.into_bytes()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(state);
+        match reader.u8()? {
+            STATE_VERSION => self.load(&mut reader),
+            version => Err(StateError::UnsupportedVersion(version.into())),
+        }
+    }
+}
+
+impl SaveState for Cpu {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.model.into());
+        let data = &self.data;
+        for &register in &[
+            data.a,
+            data.f.into(),
+            data.b,
+            data.c,
+            data.d,
+            data.e,
+            data.h,
+            data.l,
+        ] {
+            writer.u8(register)
+        }
+        writer.u16(data.pc);
+        writer.u16(data.sp);
+        writer.u8(data.ie);
+        writer.bool(data.ime);
+        writer.bool(data.phase == Tock);
+        match &self.mode {
+            Mode::Halt(Halt) => writer.u8(0),
+            Mode::Stop(Stop) => writer.u8(1),
+            Mode::Run(run) => {
+                writer.u8(2);
+                writer.u8(run.data.m_cycle as u8);
+                match &run.task {
+                    Task::Instruction(state) => {
+                        writer.u8(0);
+                        writer.u8(state.opcode);
+                        writer.option_u8(state.w);
+                        writer.option_u8(state.z);
+                        writer.option_u8(state.bus_data);
+                        writer.bool(state.read_ie);
+                        writer.option_u8(state.standby.map(|standby| standby as u8));
+                        writer.bool(state.m1);
+                        writer.bool(state.fetched)
+                    }
+                    Task::Interrupt(InterruptDispatchState) => writer.u8(1),
+                }
+            }
+        }
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let model = reader.u8()?;
+        let model = Model::try_from(model)
+            .map_err(|code| StateError::InvalidValue("model", code.into()))?;
+        let mut data = BasicData {
+            a: reader.u8()?,
+            f: reader.u8()?.into(),
+            b: reader.u8()?,
+            c: reader.u8()?,
+            d: reader.u8()?,
+            e: reader.u8()?,
+            h: reader.u8()?,
+            l: reader.u8()?,
+            pc: reader.u16()?,
+            sp: reader.u16()?,
+            ie: reader.u8()?,
+            ime: reader.bool()?,
+            phase: Tick,
+        };
+        if reader.bool()? {
+            data.phase = Tock
+        }
+        let mode = match reader.u8()? {
+            0 => Mode::Halt(Halt),
+            1 => Mode::Stop(Stop),
+            2 => {
+                let m_cycle = match reader.u8()? {
+                    0 => M2,
+                    1 => M3,
+                    2 => M4,
+                    3 => M5,
+                    4 => M6,
+                    5 => M7,
+                    6 => M8,
+                    code => return Err(StateError::InvalidValue("M-cycle", code.into())),
+                };
+                let task = match reader.u8()? {
+                    0 => Task::Instruction(InstructionExecutionState {
+                        opcode: reader.u8()?,
+                        w: reader.option_u8()?,
+                        z: reader.option_u8()?,
+                        bus_data: reader.option_u8()?,
+                        read_ie: reader.bool()?,
+                        standby: match reader.option_u8()? {
+                            None => None,
+                            Some(0) => Some(Standby::Halt),
+                            Some(1) => Some(Standby::Stop),
+                            Some(code) => {
+                                return Err(StateError::InvalidValue("standby", code.into()))
+                            }
+                        },
+                        m1: reader.bool()?,
+                        fetched: reader.bool()?,
+                    }),
+                    1 => Task::Interrupt(InterruptDispatchState),
+                    code => return Err(StateError::InvalidValue("task", code.into())),
+                };
+                Mode::Run(Run {
+                    data: RunData { m_cycle },
+                    task,
+                })
+            }
+            code => return Err(StateError::InvalidValue("mode", code.into())),
+        };
+        *self = Cpu { data, model, mode };
+        Ok(())
+    }
+}
@@ -0,0 +1,106 @@
+use super::*;
+
+use crate::cpu::asm::assemble;
+use crate::cpu::bus_trace::{BusTrace, BusTraceWriter, Divergence};
+use crate::cpu::trace::tests::SharedBuffer;
+use crate::state::StateError;
+
+fn initial_cpu() -> Cpu {
+    let mut cpu = Cpu::default();
+    cpu.data.sp = 0xd000;
+    cpu
+}
+
+fn trace_of(steps: &[(Input, Output)]) -> Vec<u8> {
+    let buffer = SharedBuffer::default();
+    let mut trace = BusTraceWriter::new(buffer.clone(), &initial_cpu());
+    for (input, output) in steps {
+        trace.record(input, output)
+    }
+    trace.finish().unwrap();
+    buffer.bytes()
+}
+
+fn recorded_session() -> CpuTrace {
+    let program = assemble(
+        "
+        loop:
+            inc a
+            ld [$c000], a
+            push af
+            pop bc
+            jr loop
+        ",
+        0x0000,
+    )
+    .unwrap();
+    let mut memory = vec![0x00; 0x10000];
+    memory[..program.len()].copy_from_slice(&program);
+    let mut cpu = initial_cpu();
+    let mut steps = Vec::new();
+    let mut data = None;
+    for step in 0..400 {
+        let input = Input {
+            data: data.take(),
+            r#if: if step > 300 { 0x04 } else { 0x00 },
+        };
+        let output = cpu.step(&input);
+        if let Some(bus) = &output.bus {
+            match bus.op {
+                Some(BusOp::Read) => data = Some(memory[usize::from(bus.addr)]),
+                Some(BusOp::Write(byte)) => memory[usize::from(bus.addr)] = byte,
+                None => (),
+            }
+        }
+        steps.push((input, output))
+    }
+    steps
+}
+
+#[test]
+fn streamed_session_reads_back_and_replays() {
+    let steps = recorded_session();
+    let trace = BusTrace::from_bytes(&trace_of(&steps)).unwrap();
+    assert!(trace.steps() == &steps[..]);
+    assert_eq!(trace.replay(), Ok(()))
+}
+
+#[test]
+fn first_divergence_is_reported() {
+    let mut steps = recorded_session();
+    let (step, addr) = steps
+        .iter()
+        .enumerate()
+        .find_map(|(step, (_, output))| match &output.bus {
+            Some(BusActivity {
+                addr,
+                op: Some(BusOp::Write(_)),
+            }) => Some((step, *addr)),
+            _ => None,
+        })
+        .unwrap();
+    let actual = steps[step].1.clone();
+    steps[step].1.bus = Some(bus_write(addr, 0xff));
+    assert_eq!(
+        BusTrace::from_bytes(&trace_of(&steps)).unwrap().replay(),
+        Err(Divergence {
+            step,
+            input: steps[step].0.clone(),
+            expected: steps[step].1.clone(),
+            actual,
+        })
+    )
+}
+
+#[test]
+fn truncated_trace_is_rejected() {
+    let bytes = trace_of(&recorded_session());
+    assert!(matches!(
+        BusTrace::from_bytes(&bytes[..bytes.len() - 1]),
+        Err(StateError::UnexpectedEnd)
+    ));
+    assert!(matches!(
+        BusTrace::from_bytes(b"GBST\x01\x00"),
+        Err(StateError::BadMagic)
+    ))
+}
@@ -21,6 +21,21 @@ fn interrupt_0_dispatch_resets_ime() {
     assert!(!bench.cpu.data.ime)
 }
 
+#[test]
+fn nop_ending_interrupt_dispatch_is_not_at_instruction_boundary() {
+    let mut bench = TestBench::default();
+    bench.trace_interrupt_request_and_dispatch(0);
+    let status = bench.cpu.status();
+    assert_eq!(
+        (status.activity, status.opcode),
+        (Activity::Running, Some(NOP))
+    );
+    assert!(!status.at_instruction_boundary);
+    bench.trace_nop();
+    assert!(bench.cpu.status().at_instruction_boundary);
+    assert_eq!(bench.cpu.data.pc, 0x0041)
+}
+
 #[test]
 fn dispatch_interrupt_1() {
     let mut bench = TestBench::default();
@@ -83,6 +98,34 @@ fn halt_mode_canceled_and_interrupt_not_dispatched_when_ime_is_reset() {
     assert_eq!(bench.trace, bench.expected)
 }
 
+#[test]
+fn stop_mode_canceled_by_joypad_interrupt() {
+    let mut bench = TestBench::default();
+    bench.cpu.data.ie = 0x00;
+    bench.trace_fetch(bench.cpu.data.pc, &[STOP]);
+    bench.trace_open_bus_read(bench.cpu.data.pc);
+    assert!(bench.cpu.is_stopped());
+    bench.r#if = 0x01;
+    bench.trace_bus_no_op();
+    assert!(bench.cpu.is_stopped());
+    bench.r#if = 0x10;
+    bench.trace_bus_no_op();
+    bench.trace_fetch(bench.cpu.data.pc, &[NOP]);
+    assert_eq!(bench.trace, bench.expected);
+    assert_eq!(bench.cpu.data.pc, 0x0003)
+}
+
+#[test]
+fn stopped_cpu_resumes_on_request() {
+    let mut bench = TestBench::default();
+    bench.trace_fetch(bench.cpu.data.pc, &[STOP]);
+    bench.trace_open_bus_read(bench.cpu.data.pc);
+    bench.cpu.resume();
+    assert!(!bench.cpu.is_stopped());
+    bench.trace_fetch(bench.cpu.data.pc, &[NOP]);
+    assert_eq!(bench.trace, bench.expected)
+}
+
 #[test]
 fn reading_0xffff_returns_ie() {
     let mut bench = TestBench::default();
@@ -178,4 +221,4 @@ impl TestBench {
     }
 }
 
-const HALT: u8 = 0x76;
+const STOP: u8 = 0x10;
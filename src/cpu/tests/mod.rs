@@ -44,45 +44,13 @@ macro_rules! output {
 
 mod alu;
 mod branch;
+mod bus_trace;
 mod interrupt;
 mod ld;
+mod state;
+mod status;
 
-impl R {
-    fn code(self) -> u8 {
-        match self {
-            R::A => 0b111,
-            R::B => 0b000,
-            R::C => 0b001,
-            R::D => 0b010,
-            R::E => 0b011,
-            R::H => 0b100,
-            R::L => 0b101,
-        }
-    }
-}
-
-impl Dd {
-    fn encode(self) -> u8 {
-        match self {
-            Dd::Bc => 0b00,
-            Dd::De => 0b01,
-            Dd::Hl => 0b10,
-            Dd::Sp => 0b11,
-        }
-    }
-}
-
-impl Qq {
-    fn encode(self) -> u8 {
-        match self {
-            Qq::Bc => 0b00,
-            Qq::De => 0b01,
-            Qq::Hl => 0b10,
-            Qq::Af => 0b11,
-        }
-    }
-}
-
+const HALT: u8 = 0x76;
 const RET: u8 = 0xc9;
 
 struct TestBench {
@@ -92,8 +60,6 @@ struct TestBench {
     expected: CpuTrace,
 }
 
-type CpuTrace = Vec<(Input, Output)>;
-
 impl Default for TestBench {
     fn default() -> Self {
         let mut cpu = Cpu::default();
@@ -0,0 +1,86 @@
+use super::*;
+
+use crate::cpu::asm::assemble;
+use crate::state::StateError;
+
+const PROGRAM: &str = "
+        ld sp, $d000
+        call store
+    .halt:
+        jr .halt
+    store:
+        ld a, $42
+        ld [$c000], a
+        ret
+";
+
+#[derive(Clone)]
+struct Bus {
+    memory: Vec<u8>,
+    data: Option<u8>,
+}
+
+impl Bus {
+    fn run(&mut self, cpu: &mut Cpu, steps: usize) -> Vec<Output> {
+        (0..steps)
+            .map(|_| {
+                let output = cpu.step(&Input {
+                    data: self.data.take(),
+                    r#if: 0x00,
+                });
+                match &output.bus {
+                    Some(BusActivity {
+                        addr,
+                        op: Some(BusOp::Read),
+                    }) => self.data = Some(self.memory[usize::from(*addr)]),
+                    Some(BusActivity {
+                        addr,
+                        op: Some(BusOp::Write(data)),
+                    }) => self.memory[usize::from(*addr)] = *data,
+                    _ => (),
+                }
+                output
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn state_restores_mid_instruction() {
+    for split in 0..64 {
+        let mut memory = vec![0x00; 0x10000];
+        let program = assemble(PROGRAM, 0x0000).unwrap();
+        memory[..program.len()].copy_from_slice(&program);
+        let mut bus = Bus { memory, data: None };
+        let mut cpu = Cpu::default();
+        bus.run(&mut cpu, split);
+        let mut restored = Cpu::new(Model::Cgb);
+        restored.load_state(&cpu.save_state()).unwrap();
+        assert_eq!(
+            bus.clone().run(&mut restored, 64),
+            bus.run(&mut cpu, 64),
+            "split at half-cycle {}",
+            split
+        );
+        assert_eq!(restored.model(), Model::Dmg)
+    }
+}
+
+#[test]
+fn unknown_state_version_is_rejected() {
+    let mut state = Cpu::default().save_state();
+    state[0] = 0xff;
+    assert_eq!(
+        Cpu::default().load_state(&state),
+        Err(StateError::UnsupportedVersion(0xff))
+    )
+}
+
+#[test]
+fn truncated_state_leaves_cpu_untouched() {
+    let mut cpu = Cpu::default();
+    cpu.data.a = 0x42;
+    let state = Cpu::default().save_state();
+    assert_eq!(cpu.load_state(&state[..8]), Err(StateError::UnexpectedEnd));
+    assert_eq!(cpu.data.a, 0x42)
+}
@@ -0,0 +1,57 @@
+use super::*;
+
+#[test]
+fn fetched_opcode_is_at_instruction_boundary() {
+    let mut cpu = Cpu::default();
+    cpu.data.pc = 0x0150;
+    assert_eq!(
+        cpu.status(),
+        CpuStatus {
+            activity: Activity::Running,
+            opcode: Some(NOP),
+            m_cycle: Some(2),
+            phase: Phase::Tick,
+            at_instruction_boundary: false,
+        }
+    );
+    cpu.step(&input!());
+    assert!(!cpu.status().at_instruction_boundary);
+    cpu.step(&input!(data: 0x3e));
+    let status = cpu.status();
+    assert_eq!((status.opcode, status.m_cycle), (Some(0x3e), Some(2)));
+    assert!(status.at_instruction_boundary);
+    cpu.step(&input!());
+    cpu.step(&input!(data: 0x42));
+    let status = cpu.status();
+    assert_eq!((status.opcode, status.m_cycle), (Some(0x3e), Some(3)));
+    assert!(!status.at_instruction_boundary)
+}
+
+#[test]
+fn halted_cpu_reports_halted() {
+    let mut cpu = Cpu::default();
+    cpu.test_opcode(&[HALT], &[(input!(), output!()), (input!(), output!())]);
+    assert_eq!(cpu.status().activity, Activity::Halted);
+    assert_eq!(cpu.status().opcode, None)
+}
+
+#[test]
+fn interrupt_dispatch_is_reported() {
+    let mut bench = TestBench {
+        r#if: 0x01,
+        ..Default::default()
+    };
+    bench.trace_nop();
+    assert_eq!(bench.cpu.status().activity, Activity::Dispatching);
+    assert!(!bench.cpu.status().at_instruction_boundary)
+}
+
+#[test]
+fn jump_restarts_at_instruction_boundary() {
+    let mut cpu = Cpu::default();
+    cpu.step(&input!());
+    cpu.jump(0x4000, 0x3c);
+    assert!(cpu.status().at_instruction_boundary);
+    assert_eq!(cpu.status().opcode, Some(0x3c));
+    assert_eq!(cpu.data.pc, 0x4001)
+}
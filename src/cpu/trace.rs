@@ -0,0 +1,173 @@
+use super::Cpu;
+
+use crate::memory::MemoryMap;
+use crate::symbols::{Location, Symbols};
+
+use std::io::{self, Write};
+
+pub struct DoctorTrace {
+    writer: Box<dyn Write>,
+    error: Option<io::Error>,
+    symbols: Option<Symbols>,
+}
+
+impl DoctorTrace {
+    pub fn new(writer: impl Write + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            error: None,
+            symbols: None,
+        }
+    }
+
+    // Appends the symbolized PC to each line, which departs from the plain
+    // Gameboy Doctor format.
+    pub fn with_symbols(self, symbols: Symbols) -> Self {
+        Self {
+            symbols: Some(symbols),
+            ..self
+        }
+    }
+
+    // Call after every CPU step; only instruction boundaries produce a line.
+    pub fn record(&mut self, cpu: &Cpu, memory: &MemoryMap) {
+        let status = cpu.status();
+        if self.error.is_some() || !status.at_instruction_boundary {
+            return;
+        }
+        let data = &cpu.data;
+        let pc = data.pc.wrapping_sub(1);
+        let pcmem = [
+            status.opcode.unwrap(),
+            memory.peek(pc.wrapping_add(1)),
+            memory.peek(pc.wrapping_add(2)),
+            memory.peek(pc.wrapping_add(3)),
+        ];
+        let label = self.symbols.as_ref().and_then(|symbols| {
+            symbols.symbolize(Location {
+                bank: memory.bank(pc),
+                addr: pc,
+            })
+        });
+        let result = write!(
+            self.writer,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} \
+             SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            data.a,
+            u8::from(data.f),
+            data.b,
+            data.c,
+            data.d,
+            data.e,
+            data.h,
+            data.l,
+            data.sp,
+            pc,
+            pcmem[0],
+            pcmem[1],
+            pcmem[2],
+            pcmem[3],
+        )
+        .and_then(|()| match label {
+            Some(label) => writeln!(self.writer, " ; {}", label),
+            None => writeln!(self.writer),
+        });
+        if let Err(error) = result {
+            self.error = Some(error)
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::cartridge::tests::rom;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::Input;
+    use crate::Model;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        pub(crate) fn bytes(&self) -> Vec<u8> {
+            self.0.borrow().clone()
+        }
+
+        pub(crate) fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.borrow().clone())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn memory_map(program: &[u8]) -> MemoryMap {
+        let mut memory = MemoryMap::new(Cartridge::new(rom(0x00, 2, 0)).unwrap(), Model::Dmg);
+        for (offset, &byte) in program.iter().enumerate() {
+            memory.poke(0x0100 + offset as u16, byte)
+        }
+        memory
+    }
+
+    #[test]
+    fn line_matches_gameboy_doctor_format() {
+        let buffer = SharedBuffer::default();
+        let mut trace = DoctorTrace::new(buffer.clone());
+        let mut cpu = Cpu::post_boot(Model::Dmg, 0x4d);
+        cpu.jump(0x0100, 0x00);
+        trace.record(&cpu, &memory_map(&[0x00, 0xc3, 0x13, 0x02]));
+        trace.finish().unwrap();
+        assert_eq!(
+            buffer.lines(),
+            ["A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02"]
+        )
+    }
+
+    #[test]
+    fn nothing_is_recorded_between_boundaries() {
+        let buffer = SharedBuffer::default();
+        let mut trace = DoctorTrace::new(buffer.clone());
+        let mut cpu = Cpu::default();
+        cpu.step(&Input {
+            data: None,
+            r#if: 0x00,
+        });
+        trace.record(&cpu, &memory_map(&[]));
+        assert!(buffer.0.borrow().is_empty())
+    }
+
+    #[test]
+    fn symbols_are_appended_when_given() {
+        let buffer = SharedBuffer::default();
+        let symbols = Symbols::from_sym("00:00fe Entry").unwrap();
+        let mut trace = DoctorTrace::new(buffer.clone()).with_symbols(symbols);
+        let mut cpu = Cpu::post_boot(Model::Dmg, 0x4d);
+        cpu.jump(0x0100, 0x00);
+        trace.record(&cpu, &memory_map(&[0x00, 0xc3, 0x13, 0x02]));
+        assert!(buffer.lines()[0].ends_with("PCMEM:00,C3,13,02 ; Entry+$2"))
+    }
+}
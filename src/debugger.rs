@@ -0,0 +1,510 @@
+use crate::cpu::{BusActivity, BusOp};
+use crate::symbols::{is_banked, Location, Symbols};
+use crate::system::GameBoy;
+
+use std::ops::RangeInclusive;
+
+const CALL: u8 = 0xcd;
+const RET: u8 = 0xc9;
+const RETI: u8 = 0xd9;
+
+pub struct Debugger {
+    game_boy: GameBoy,
+    breakpoints: Vec<Option<Breakpoint>>,
+    watchpoints: Vec<Option<Watchpoint>>,
+    symbols: Symbols,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    pub bank: Option<u16>,
+    pub condition: Option<Condition>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    Af,
+    Bc,
+    De,
+    Hl,
+    Sp,
+    Pc,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub access: Access,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stop {
+    Step,
+    Breakpoint(usize),
+    Watchpoint(usize, BusActivity),
+    CycleLimit,
+}
+
+impl Breakpoint {
+    pub fn at(addr: u16) -> Self {
+        Self {
+            addr,
+            bank: None,
+            condition: None,
+        }
+    }
+}
+
+impl Condition {
+    fn holds(&self, game_boy: &GameBoy, pc: u16) -> bool {
+        let data = &game_boy.cpu().data;
+        let pair = |h: u8, l: u8| u16::from_be_bytes([h, l]);
+        let value = match self.register {
+            Register::A => data.a.into(),
+            Register::F => u8::from(data.f).into(),
+            Register::B => data.b.into(),
+            Register::C => data.c.into(),
+            Register::D => data.d.into(),
+            Register::E => data.e.into(),
+            Register::H => data.h.into(),
+            Register::L => data.l.into(),
+            Register::Af => pair(data.a, data.f.into()),
+            Register::Bc => pair(data.b, data.c),
+            Register::De => pair(data.d, data.e),
+            Register::Hl => pair(data.h, data.l),
+            Register::Sp => data.sp,
+            Register::Pc => pc,
+        };
+        match self.comparison {
+            Comparison::Eq => value == self.value,
+            Comparison::Ne => value != self.value,
+            Comparison::Lt => value < self.value,
+            Comparison::Le => value <= self.value,
+            Comparison::Gt => value > self.value,
+            Comparison::Ge => value >= self.value,
+        }
+    }
+}
+
+impl Watchpoint {
+    fn matches(&self, activity: &BusActivity) -> bool {
+        let access = match activity.op {
+            Some(BusOp::Read) => Access::Read,
+            Some(BusOp::Write(_)) => Access::Write,
+            None => return false,
+        };
+        self.range.contains(&activity.addr)
+            && (self.access == access || self.access == Access::ReadWrite)
+    }
+}
+
+impl Debugger {
+    pub fn new(mut game_boy: GameBoy) -> Self {
+        game_boy.set_split_at_boundary(true);
+        Self {
+            game_boy,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            symbols: Symbols::default(),
+        }
+    }
+
+    pub fn game_boy(&self) -> &GameBoy {
+        &self.game_boy
+    }
+
+    pub fn game_boy_mut(&mut self) -> &mut GameBoy {
+        &mut self.game_boy
+    }
+
+    pub fn into_inner(mut self) -> GameBoy {
+        self.game_boy.set_split_at_boundary(false);
+        self.game_boy
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols
+    }
+
+    // Builds a breakpoint from `label+offset`, `bank:addr` or `addr`; labels in
+    // switchable regions only match their own bank.
+    pub fn breakpoint_at(&self, expr: &str) -> Option<Breakpoint> {
+        let (bank, addr) = self.symbols.resolve(expr)?;
+        Some(Breakpoint {
+            bank: bank.filter(|_| is_banked(addr)),
+            ..Breakpoint::at(addr)
+        })
+    }
+
+    // Label of the current PC, e.g. `Main.loop+$2`.
+    pub fn pc_symbol(&self) -> Option<String> {
+        let addr = self.pc();
+        self.symbols.symbolize(Location {
+            bank: self.game_boy.memory().bank(addr),
+            addr,
+        })
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.breakpoints.push(Some(breakpoint));
+        self.breakpoints.len() - 1
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> Option<Breakpoint> {
+        self.breakpoints.get_mut(id)?.take()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        enumerate_live(&self.breakpoints)
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(Some(watchpoint));
+        self.watchpoints.len() - 1
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> Option<Watchpoint> {
+        self.watchpoints.get_mut(id)?.take()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        enumerate_live(&self.watchpoints)
+    }
+
+    // Address of the instruction about to execute when stopped at a boundary.
+    pub fn pc(&self) -> u16 {
+        let cpu = self.game_boy.cpu();
+        if cpu.status().at_instruction_boundary {
+            cpu.data.pc.wrapping_sub(1)
+        } else {
+            cpu.data.pc
+        }
+    }
+
+    pub fn resume(&mut self, cycle_limit: u64) -> Stop {
+        self.run(cycle_limit, |_| false)
+    }
+
+    pub fn step_into(&mut self, cycle_limit: u64) -> Stop {
+        self.run(cycle_limit, |_| true)
+    }
+
+    pub fn step_over(&mut self, cycle_limit: u64) -> Stop {
+        let cpu = self.game_boy.cpu();
+        let status = cpu.status();
+        let len = match status.opcode {
+            Some(CALL) => 3,
+            Some(opcode) if opcode & 0xe7 == 0xc4 => 3,
+            Some(opcode) if opcode & 0xc7 == 0xc7 => 1,
+            _ => return self.step_into(cycle_limit),
+        };
+        if !status.at_instruction_boundary {
+            return self.step_into(cycle_limit);
+        }
+        let return_addr = self.pc().wrapping_add(len);
+        let sp = cpu.data.sp;
+        self.run(cycle_limit, |debugger| {
+            debugger.pc() == return_addr && debugger.game_boy.cpu().data.sp >= sp
+        })
+    }
+
+    pub fn step_out(&mut self, cycle_limit: u64) -> Stop {
+        let entry_sp = self.game_boy.cpu().data.sp;
+        let mut previous = self.instruction();
+        self.run(cycle_limit, |debugger| {
+            let (opcode, sp) = previous;
+            let current = debugger.instruction();
+            previous = current;
+            let returned = matches!(opcode, Some(RET) | Some(RETI))
+                || opcode.is_some_and(|opcode| opcode & 0xe7 == 0xc0);
+            returned && current.1 == sp.wrapping_add(2) && current.1 > entry_sp
+        })
+    }
+
+    pub fn run_to(&mut self, addr: u16, bank: Option<u16>, cycle_limit: u64) -> Stop {
+        self.run(cycle_limit, |debugger| debugger.is_at(addr, bank))
+    }
+
+    fn instruction(&self) -> (Option<u8>, u16) {
+        let cpu = self.game_boy.cpu();
+        (cpu.status().opcode, cpu.data.sp)
+    }
+
+    fn is_at(&self, addr: u16, bank: Option<u16>) -> bool {
+        self.pc() == addr && bank.is_none_or(|bank| self.game_boy.memory().bank(addr) == bank)
+    }
+
+    fn run(&mut self, cycle_limit: u64, mut done: impl FnMut(&Self) -> bool) -> Stop {
+        let mut watchpoint = None;
+        for _ in 0..cycle_limit {
+            self.game_boy.step();
+            if watchpoint.is_none() {
+                watchpoint = self.watchpoint_hit()
+            }
+            if !self.game_boy.reached_instruction_boundary() {
+                continue;
+            }
+            if let Some((id, activity)) = watchpoint {
+                return Stop::Watchpoint(id, activity);
+            }
+            if let Some(id) = self.breakpoint_hit() {
+                return Stop::Breakpoint(id);
+            }
+            if done(self) {
+                return Stop::Step;
+            }
+        }
+        Stop::CycleLimit
+    }
+
+    fn watchpoint_hit(&self) -> Option<(usize, BusActivity)> {
+        self.game_boy.bus_activity().iter().find_map(|activity| {
+            self.watchpoints()
+                .find(|(_, watchpoint)| watchpoint.matches(activity))
+                .map(|(id, _)| (id, activity.clone()))
+        })
+    }
+
+    fn breakpoint_hit(&self) -> Option<usize> {
+        let pc = self.pc();
+        self.breakpoints()
+            .find(|(_, breakpoint)| {
+                self.is_at(breakpoint.addr, breakpoint.bank)
+                    && breakpoint
+                        .condition
+                        .as_ref()
+                        .is_none_or(|condition| condition.holds(&self.game_boy, pc))
+            })
+            .map(|(id, _)| id)
+    }
+}
+
+fn enumerate_live<T>(slots: &[Option<T>]) -> impl Iterator<Item = (usize, &T)> {
+    slots
+        .iter()
+        .enumerate()
+        .filter_map(|(id, slot)| slot.as_ref().map(|item| (id, item)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cartridge::Cartridge;
+    use crate::memory::{Memory, IE, IF, KEY1};
+    use crate::system::tests::{cgb_rom, rom_with_source};
+    use crate::Model;
+
+    const LIMIT: u64 = 10_000;
+
+    const PROGRAM: &str = "
+        main:
+            ld sp, $d000
+            ld hl, $c000
+        loop:
+            call increment
+            nop
+            jr loop
+        increment:
+            inc a
+            call store
+            ret
+        store:
+            ld [hl], a
+            ret
+    ";
+
+    fn debugger(model: Model) -> Debugger {
+        let mut rom = rom_with_source(PROGRAM);
+        if model.is_cgb() {
+            rom = cgb_rom(rom)
+        }
+        Debugger::new(GameBoy::with_model(Cartridge::new(rom).unwrap(), model))
+    }
+
+    fn at(debugger: &Debugger) -> u16 {
+        assert!(debugger.game_boy().cpu().status().at_instruction_boundary);
+        debugger.pc()
+    }
+
+    #[test]
+    fn breakpoint_stops_before_instruction() {
+        let mut debugger = debugger(Model::Dmg);
+        let id = debugger.add_breakpoint(Breakpoint::at(0x0156));
+        assert_eq!(debugger.resume(LIMIT), Stop::Breakpoint(id));
+        assert_eq!(at(&debugger), 0x0156);
+        assert_eq!(debugger.game_boy().cpu().data.h, 0xc0);
+        assert_eq!(debugger.resume(LIMIT), Stop::Breakpoint(id));
+        assert_eq!(debugger.game_boy().cpu().data.a, 0x02)
+    }
+
+    #[test]
+    fn breakpoints_accept_symbols() {
+        let mut debugger = debugger(Model::Dmg);
+        debugger.set_symbols(
+            Symbols::from_sym("00:0150 main\n00:0156 loop\n00:015c increment\n01:4000 banked")
+                .unwrap(),
+        );
+        assert_eq!(debugger.breakpoint_at("banked+2").unwrap().bank, Some(1));
+        let breakpoint = debugger.breakpoint_at("increment+1").unwrap();
+        assert_eq!(breakpoint, Breakpoint::at(0x015d));
+        let id = debugger.add_breakpoint(breakpoint);
+        assert_eq!(debugger.resume(LIMIT), Stop::Breakpoint(id));
+        assert_eq!(debugger.pc_symbol().unwrap(), "increment+$1")
+    }
+
+    #[test]
+    fn removed_breakpoint_no_longer_stops() {
+        let mut debugger = debugger(Model::Dmg);
+        let id = debugger.add_breakpoint(Breakpoint::at(0x0156));
+        assert!(debugger.remove_breakpoint(id).is_some());
+        assert_eq!(debugger.breakpoints().count(), 0);
+        assert_eq!(debugger.resume(100), Stop::CycleLimit)
+    }
+
+    #[test]
+    fn banked_breakpoint_only_matches_mapped_bank() {
+        let mut debugger = debugger(Model::Dmg);
+        debugger.add_breakpoint(Breakpoint {
+            bank: Some(1),
+            ..Breakpoint::at(0x0156)
+        });
+        assert_eq!(debugger.resume(1000), Stop::CycleLimit)
+    }
+
+    #[test]
+    fn conditional_breakpoint_checks_register() {
+        let mut debugger = debugger(Model::Dmg);
+        let id = debugger.add_breakpoint(Breakpoint {
+            condition: Some(Condition {
+                register: Register::A,
+                comparison: Comparison::Eq,
+                value: 0x05,
+            }),
+            ..Breakpoint::at(0x015d)
+        });
+        assert_eq!(debugger.resume(LIMIT), Stop::Breakpoint(id));
+        assert_eq!(debugger.game_boy().cpu().data.a, 0x05)
+    }
+
+    #[test]
+    fn write_watchpoint_stops_after_the_writing_instruction() {
+        let mut debugger = debugger(Model::Dmg);
+        let id = debugger.add_watchpoint(Watchpoint {
+            range: 0xc000..=0xc0ff,
+            access: Access::Write,
+        });
+        assert_eq!(
+            debugger.resume(LIMIT),
+            Stop::Watchpoint(
+                id,
+                BusActivity {
+                    addr: 0xc000,
+                    op: Some(BusOp::Write(0x02)),
+                }
+            )
+        );
+        assert_eq!(at(&debugger), 0x0162)
+    }
+
+    #[test]
+    fn read_watchpoint_ignores_writes() {
+        let mut debugger = debugger(Model::Dmg);
+        debugger.add_watchpoint(Watchpoint {
+            range: 0xc000..=0xc000,
+            access: Access::Read,
+        });
+        assert_eq!(debugger.resume(1000), Stop::CycleLimit)
+    }
+
+    #[test]
+    fn step_over_skips_call() {
+        let mut debugger = debugger(Model::Dmg);
+        assert_eq!(debugger.run_to(0x0156, None, LIMIT), Stop::Step);
+        assert_eq!(debugger.step_over(LIMIT), Stop::Step);
+        assert_eq!(at(&debugger), 0x0159);
+        assert_eq!(debugger.game_boy().cpu().data.a, 0x02)
+    }
+
+    #[test]
+    fn step_into_enters_call_and_step_out_returns() {
+        let mut debugger = debugger(Model::Dmg);
+        assert_eq!(debugger.run_to(0x0156, None, LIMIT), Stop::Step);
+        assert_eq!(debugger.step_into(LIMIT), Stop::Step);
+        assert_eq!(at(&debugger), 0x015c);
+        assert_eq!(debugger.step_into(LIMIT), Stop::Step);
+        assert_eq!(debugger.step_out(LIMIT), Stop::Step);
+        assert_eq!(at(&debugger), 0x0159)
+    }
+
+    #[test]
+    fn step_into_stops_at_interrupt_vector() {
+        let mut rom = rom_with_source(PROGRAM);
+        rom[0x0040] = 0xc9; // RET
+        let mut debugger = Debugger::new(GameBoy::new(Cartridge::new(rom).unwrap()));
+        assert_eq!(debugger.run_to(0x0156, None, LIMIT), Stop::Step);
+        let game_boy = debugger.game_boy_mut();
+        game_boy.poke(IE, 0x01);
+        game_boy.poke(IF, 0x01);
+        game_boy.cpu_mut().data.ime = true;
+        // The CALL already fetched at 0x0156 runs before the dispatch.
+        assert_eq!(debugger.step_into(LIMIT), Stop::Step);
+        assert_eq!(at(&debugger), 0x0040);
+        assert_eq!(debugger.step_into(LIMIT), Stop::Step);
+        assert_eq!(at(&debugger), 0x015c)
+    }
+
+    #[test]
+    fn double_speed_stops_at_every_boundary() {
+        let mut debugger = debugger(Model::Cgb);
+        let memory = debugger.game_boy_mut().memory_mut();
+        memory.write(KEY1, 0x01);
+        memory.switch_speed();
+        let mut pcs = Vec::new();
+        for _ in 0..8 {
+            assert_eq!(debugger.step_into(LIMIT), Stop::Step);
+            pcs.push(at(&debugger))
+        }
+        assert_eq!(
+            pcs,
+            [0x0100, 0x0150, 0x0153, 0x0156, 0x015c, 0x015d, 0x0161, 0x0162]
+        )
+    }
+}
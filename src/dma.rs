@@ -0,0 +1,339 @@
+use crate::cpu::{BusActivity, BusOp};
+use crate::memory::Memory;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const DMA: u16 = 0xff46;
+
+const OAM_SIZE: u8 = 0xa0;
+
+#[derive(Default)]
+pub struct OamDma {
+    register: u8,
+    requested: Option<u8>,
+    starting: Option<u8>,
+    transfer: Option<Transfer>,
+}
+
+#[derive(Clone, Copy)]
+struct Transfer {
+    source: u8,
+    index: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Bus {
+    External,
+    Video,
+    Oam,
+}
+
+impl OamDma {
+    pub fn is_active(&self) -> bool {
+        self.transfer.is_some()
+    }
+
+    pub fn register(&self) -> u8 {
+        self.register
+    }
+
+    // Stores the register without requesting a transfer.
+    pub fn poke(&mut self, data: u8) {
+        self.register = data
+    }
+
+    pub fn cycle<M: Memory>(&mut self, access: Option<&BusActivity>, memory: &mut M) -> Option<u8> {
+        let conflict = self.transfer.map(|transfer| {
+            let addr = transfer.source_addr();
+            let data = memory.read(addr);
+            memory.write_oam(transfer.index, data);
+            (Bus::of(addr), data)
+        });
+        let data = access.and_then(|access| self.arbitrate(access, conflict, memory));
+        self.advance();
+        data
+    }
+
+    fn arbitrate<M: Memory>(
+        &mut self,
+        access: &BusActivity,
+        conflict: Option<(Option<Bus>, u8)>,
+        memory: &mut M,
+    ) -> Option<u8> {
+        if let Some((source_bus, data)) = conflict {
+            let bus = Bus::of(access.addr);
+            if bus.is_some() && (bus == source_bus || bus == Some(Bus::Oam)) {
+                return match access.op {
+                    Some(BusOp::Read) if bus == source_bus => Some(data),
+                    Some(BusOp::Read) => Some(0xff),
+                    _ => None,
+                };
+            }
+        }
+        self.pass_through(access, memory)
+    }
+
+    fn pass_through<M: Memory>(&mut self, access: &BusActivity, memory: &mut M) -> Option<u8> {
+        match access.op {
+            Some(BusOp::Read) if access.addr == DMA => Some(self.register),
+            Some(BusOp::Read) => Some(memory.read(access.addr)),
+            Some(BusOp::Write(data)) if access.addr == DMA => {
+                self.register = data;
+                self.requested = Some(data);
+                None
+            }
+            Some(BusOp::Write(data)) => {
+                memory.write(access.addr, data);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn advance(&mut self) {
+        self.transfer = self.transfer.and_then(Transfer::next);
+        if let Some(source) = self.starting.take() {
+            self.transfer = Some(Transfer { source, index: 0 })
+        }
+        self.starting = self.requested.take();
+    }
+}
+
+impl Transfer {
+    fn source_addr(self) -> u16 {
+        let source = if self.source >= 0xe0 {
+            self.source - 0x20
+        } else {
+            self.source
+        };
+        u16::from_be_bytes([source, self.index])
+    }
+
+    fn next(self) -> Option<Self> {
+        if self.index + 1 < OAM_SIZE {
+            Some(Transfer {
+                index: self.index + 1,
+                ..self
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl Bus {
+    fn of(addr: u16) -> Option<Self> {
+        match addr {
+            0x0000..=0x7fff => Some(Bus::External),
+            0x8000..=0x9fff => Some(Bus::Video),
+            0xa000..=0xfdff => Some(Bus::External),
+            0xfe00..=0xfeff => Some(Bus::Oam),
+            0xff00..=0xffff => None,
+        }
+    }
+}
+
+impl SaveState for OamDma {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.register);
+        writer.option_u8(self.requested);
+        writer.option_u8(self.starting);
+        writer.option_u8(self.transfer.map(|transfer| transfer.source));
+        writer.u8(self.transfer.map_or(0, |transfer| transfer.index))
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.register = reader.u8()?;
+        self.requested = reader.option_u8()?;
+        self.starting = reader.option_u8()?;
+        let source = reader.option_u8()?;
+        let index = reader.u8()?;
+        self.transfer = source.map(|source| Transfer { source, index });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestMemory {
+        bytes: Vec<u8>,
+        oam: [u8; OAM_SIZE as usize],
+    }
+
+    impl Default for TestMemory {
+        fn default() -> Self {
+            Self {
+                bytes: (0..=0xffff)
+                    .map(|addr: u32| (addr ^ addr >> 8) as u8)
+                    .collect(),
+                oam: [0x00; OAM_SIZE as usize],
+            }
+        }
+    }
+
+    impl Memory for TestMemory {
+        fn read(&mut self, addr: u16) -> u8 {
+            match addr {
+                0xfe00..=0xfe9f => self.oam[(addr - 0xfe00) as usize],
+                _ => self.bytes[addr as usize],
+            }
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            match addr {
+                0xfe00..=0xfe9f => self.oam[(addr - 0xfe00) as usize] = data,
+                _ => self.bytes[addr as usize] = data,
+            }
+        }
+
+        fn write_oam(&mut self, index: u8, data: u8) {
+            self.oam[index as usize] = data
+        }
+    }
+
+    fn read(addr: u16) -> BusActivity {
+        BusActivity {
+            addr,
+            op: Some(BusOp::Read),
+        }
+    }
+
+    fn write(addr: u16, data: u8) -> BusActivity {
+        BusActivity {
+            addr,
+            op: Some(BusOp::Write(data)),
+        }
+    }
+
+    fn start_transfer(dma: &mut OamDma, memory: &mut TestMemory, source: u8) {
+        dma.cycle(Some(&write(DMA, source)), memory);
+        dma.cycle(None, memory);
+    }
+
+    #[test]
+    fn transfer_copies_160_bytes_to_oam() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0xc1);
+        for _ in 0..OAM_SIZE {
+            assert!(dma.is_active());
+            dma.cycle(None, &mut memory);
+        }
+        assert!(!dma.is_active());
+        assert_eq!(memory.oam[..], memory.bytes[0xc100..0xc1a0])
+    }
+
+    #[test]
+    fn transfer_from_echo_ram_reads_work_ram() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0xe2);
+        for _ in 0..OAM_SIZE {
+            dma.cycle(None, &mut memory);
+        }
+        assert_eq!(memory.oam[..], memory.bytes[0xc200..0xc2a0])
+    }
+
+    #[test]
+    fn startup_cycle_does_not_conflict() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        dma.cycle(Some(&write(DMA, 0xc1)), &mut memory);
+        assert_eq!(dma.cycle(Some(&read(0xfe00)), &mut memory), Some(0x00));
+        assert_eq!(dma.cycle(Some(&read(0xfe00)), &mut memory), Some(0xff))
+    }
+
+    #[test]
+    fn read_from_source_bus_returns_transferred_byte() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0xc1);
+        dma.cycle(None, &mut memory);
+        assert_eq!(
+            dma.cycle(Some(&read(0x4000)), &mut memory),
+            Some(memory.bytes[0xc101])
+        )
+    }
+
+    #[test]
+    fn read_from_other_bus_is_not_affected() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0xc1);
+        assert_eq!(
+            dma.cycle(Some(&read(0x8123)), &mut memory),
+            Some(memory.bytes[0x8123])
+        )
+    }
+
+    #[test]
+    fn read_from_video_bus_during_video_transfer_returns_transferred_byte() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0x80);
+        assert_eq!(
+            dma.cycle(Some(&read(0x9abc)), &mut memory),
+            Some(memory.bytes[0x8000])
+        );
+        assert_eq!(
+            dma.cycle(Some(&read(0x0123)), &mut memory),
+            Some(memory.bytes[0x0123])
+        )
+    }
+
+    #[test]
+    fn read_from_oam_returns_0xff() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0xc1);
+        assert_eq!(dma.cycle(Some(&read(0xfe10)), &mut memory), Some(0xff))
+    }
+
+    #[test]
+    fn hram_is_accessible_during_transfer() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0xc1);
+        dma.cycle(Some(&write(0xff80, 0x42)), &mut memory);
+        assert_eq!(dma.cycle(Some(&read(0xff80)), &mut memory), Some(0x42))
+    }
+
+    #[test]
+    fn write_to_source_bus_is_ignored_during_transfer() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0xc1);
+        let before = memory.bytes[0xd000];
+        dma.cycle(Some(&write(0xd000, !before)), &mut memory);
+        assert_eq!(memory.bytes[0xd000], before)
+    }
+
+    #[test]
+    fn dma_register_reads_back_last_written_value() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0xc1);
+        assert_eq!(dma.cycle(Some(&read(DMA)), &mut memory), Some(0xc1))
+    }
+
+    #[test]
+    fn restarting_transfer_continues_old_transfer_during_startup() {
+        let mut dma = OamDma::default();
+        let mut memory = TestMemory::default();
+        start_transfer(&mut dma, &mut memory, 0xc1);
+        for _ in 0..4 {
+            dma.cycle(None, &mut memory);
+        }
+        dma.cycle(Some(&write(DMA, 0xd0)), &mut memory);
+        assert_eq!(
+            dma.cycle(Some(&read(0x0000)), &mut memory),
+            Some(memory.bytes[0xc105])
+        );
+        for _ in 0..OAM_SIZE {
+            dma.cycle(None, &mut memory);
+        }
+        assert!(!dma.is_active());
+        assert_eq!(memory.oam[..], memory.bytes[0xd000..0xd0a0])
+    }
+}
@@ -0,0 +1,489 @@
+use crate::cpu::Flags;
+use crate::debugger::{Access, Breakpoint, Debugger, Stop, Watchpoint};
+
+use std::collections::HashMap;
+use std::io::{self, Read, Stdin, Stdout, Write};
+use std::net::TcpStream;
+
+const CYCLES_PER_POLL: u64 = 70224;
+// About ten seconds of emulated time.
+const STDIO_POLL_LIMIT: u32 = 600;
+const SIGTRAP: &str = "S05";
+const SIGINT: &str = "S02";
+const INTERRUPT: u8 = 0x03;
+const REGISTERS: usize = 6;
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\">\
+<feature name=\"org.gnu.gdb.sm83.cpu\">\
+<reg name=\"af\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"bc\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"de\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"hl\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"sp\" bitsize=\"16\" type=\"data_ptr\"/>\
+<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+</feature>\
+</target>";
+
+pub trait Connection: Read + Write {
+    // Consumes a pending interrupt request (Ctrl-C) sent while the target runs.
+    fn poll_interrupt(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0x00];
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+        match result {
+            Ok(1) => Ok(byte[0] == INTERRUPT),
+            Ok(_) => Ok(false),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+// Stdin can't be read without blocking, so Ctrl-C goes unseen while the target
+// runs. Instead, `c` and `s` stop as if interrupted after `STDIO_POLL_LIMIT`
+// polls.
+pub struct Stdio {
+    stdin: Stdin,
+    stdout: Stdout,
+    polls: u32,
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self {
+            stdin: io::stdin(),
+            stdout: io::stdout(),
+            polls: 0,
+        }
+    }
+}
+
+impl Read for Stdio {
+    // The target only runs between packets, so each read starts a new budget.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.polls = 0;
+        self.stdin.read(buf)
+    }
+}
+
+impl Write for Stdio {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdout.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+impl Connection for Stdio {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.polls += 1;
+        Ok(self.polls >= STDIO_POLL_LIMIT)
+    }
+}
+
+pub struct GdbServer {
+    debugger: Debugger,
+    breakpoints: HashMap<u16, usize>,
+    watchpoints: HashMap<(u8, u16, u16), usize>,
+}
+
+enum Action {
+    Reply(String),
+    Resume,
+    Step,
+    Detach,
+    Kill,
+}
+
+impl GdbServer {
+    pub fn new(debugger: Debugger) -> Self {
+        Self {
+            debugger,
+            breakpoints: HashMap::new(),
+            watchpoints: HashMap::new(),
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn debugger_mut(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    pub fn into_inner(self) -> Debugger {
+        self.debugger
+    }
+
+    // Serves one client until it detaches, kills the session or disconnects.
+    pub fn serve(&mut self, connection: &mut impl Connection) -> io::Result<()> {
+        while let Some(packet) = read_packet(connection)? {
+            let packet = match packet {
+                Some(packet) => packet,
+                None => {
+                    write_ack(connection, b'-')?;
+                    continue;
+                }
+            };
+            write_ack(connection, b'+')?;
+            let reply = match self.handle(&packet) {
+                Action::Reply(reply) => reply,
+                Action::Resume => self.resume(connection)?,
+                Action::Step => self.step(connection)?,
+                Action::Detach => {
+                    write_packet(connection, "OK")?;
+                    return Ok(());
+                }
+                Action::Kill => return Ok(()),
+            };
+            write_packet(connection, &reply)?
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self, connection: &mut impl Connection) -> io::Result<String> {
+        loop {
+            match self.debugger.resume(CYCLES_PER_POLL) {
+                Stop::CycleLimit if connection.poll_interrupt()? => {
+                    // Finish the current instruction; a halted CPU stops where it is.
+                    return Ok(match self.debugger.step_into(CYCLES_PER_POLL) {
+                        Stop::Step | Stop::CycleLimit => SIGINT.into(),
+                        stop => self.stop_reply(stop),
+                    });
+                }
+                Stop::CycleLimit => (),
+                stop => return Ok(self.stop_reply(stop)),
+            }
+        }
+    }
+
+    // Stepping a halted or stopped CPU only ends with the next instruction or Ctrl-C.
+    fn step(&mut self, connection: &mut impl Connection) -> io::Result<String> {
+        loop {
+            match self.debugger.step_into(CYCLES_PER_POLL) {
+                Stop::CycleLimit if connection.poll_interrupt()? => return Ok(SIGINT.into()),
+                Stop::CycleLimit => (),
+                stop => return Ok(self.stop_reply(stop)),
+            }
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Watchpoint(id, activity) => {
+                let access = self
+                    .debugger
+                    .watchpoints()
+                    .find(|&(watchpoint, _)| watchpoint == id)
+                    .map(|(_, watchpoint)| watchpoint.access);
+                let kind = match access {
+                    Some(Access::Write) => "watch",
+                    Some(Access::Read) => "rwatch",
+                    _ => "awatch",
+                };
+                format!("T05{}:{:04x};", kind, activity.addr)
+            }
+            _ => SIGTRAP.into(),
+        }
+    }
+
+    fn handle(&mut self, packet: &[u8]) -> Action {
+        let packet = String::from_utf8_lossy(packet);
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => Some(SIGTRAP.into()),
+            Some(b'g') => Some(self.read_registers()),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => self.read_register(&packet[1..]),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') => self.insert_point(&packet[1..]),
+            Some(b'z') => self.remove_point(&packet[1..]),
+            Some(b'c') => return Action::Resume,
+            Some(b's') => return Action::Step,
+            Some(b'D') => return Action::Detach,
+            Some(b'k') => return Action::Kill,
+            Some(b'H') => Some("OK".into()),
+            Some(b'q') => Some(query(&packet[1..])),
+            _ => Some(String::new()),
+        };
+        Action::Reply(reply.unwrap_or_else(|| "E01".into()))
+    }
+
+    fn registers(&self) -> [u16; REGISTERS] {
+        let data = &self.debugger.game_boy().cpu().data;
+        let pair = |h: u8, l: u8| u16::from_be_bytes([h, l]);
+        [
+            pair(data.a, data.f.into()),
+            pair(data.b, data.c),
+            pair(data.d, data.e),
+            pair(data.h, data.l),
+            data.sp,
+            self.debugger.pc(),
+        ]
+    }
+
+    fn set_register(&mut self, index: usize, value: u16) {
+        let pc = self.debugger.pc();
+        let game_boy = self.debugger.game_boy_mut();
+        let [high, low] = value.to_be_bytes();
+        let data = &mut game_boy.cpu_mut().data;
+        match index {
+            0 => {
+                data.a = high;
+                data.f = Flags::from(low)
+            }
+            1 => {
+                data.b = high;
+                data.c = low
+            }
+            2 => {
+                data.d = high;
+                data.e = low
+            }
+            3 => {
+                data.h = high;
+                data.l = low
+            }
+            4 => data.sp = value,
+            _ if value != pc => {
+                let opcode = game_boy.peek(value);
+                game_boy.cpu_mut().jump(value, opcode)
+            }
+            _ => (),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        self.registers()
+            .iter()
+            .map(|&value| hex_u16(value))
+            .collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let bytes = parse_hex_bytes(args)?;
+        if bytes.len() != 2 * REGISTERS {
+            return None;
+        }
+        for (index, value) in bytes.chunks(2).enumerate() {
+            self.set_register(index, u16::from_le_bytes([value[0], value[1]]))
+        }
+        Some("OK".into())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let index = usize::from_str_radix(args, 16).ok()?;
+        self.registers().get(index).map(|&value| hex_u16(value))
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (index, value) = args.split_once('=')?;
+        let index = usize::from_str_radix(index, 16).ok()?;
+        let bytes = parse_hex_bytes(value)?;
+        if index >= REGISTERS || bytes.len() != 2 {
+            return None;
+        }
+        self.set_register(index, u16::from_le_bytes([bytes[0], bytes[1]]));
+        Some("OK".into())
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = parse_addr_len(args)?;
+        let game_boy = self.debugger.game_boy();
+        Some(
+            (0..len)
+                .map(|offset| format!("{:02x}", game_boy.peek(addr.wrapping_add(offset))))
+                .collect(),
+        )
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (target, data) = args.split_once(':')?;
+        let (addr, len) = parse_addr_len(target)?;
+        let bytes = parse_hex_bytes(data)?;
+        if bytes.len() != usize::from(len) {
+            return None;
+        }
+        let game_boy = self.debugger.game_boy_mut();
+        for (offset, &byte) in bytes.iter().enumerate() {
+            game_boy.poke(addr.wrapping_add(offset as u16), byte)
+        }
+        Some("OK".into())
+    }
+
+    fn insert_point(&mut self, args: &str) -> Option<String> {
+        let (kind, addr, len) = parse_point(args)?;
+        match kind {
+            0 | 1 => {
+                if !self.breakpoints.contains_key(&addr) {
+                    let id = self.debugger.add_breakpoint(Breakpoint::at(addr));
+                    self.breakpoints.insert(addr, id);
+                }
+            }
+            2..=4 => {
+                let watchpoint = Watchpoint {
+                    range: addr..=addr.wrapping_add(len.max(1) - 1),
+                    access: match kind {
+                        2 => Access::Write,
+                        3 => Access::Read,
+                        _ => Access::ReadWrite,
+                    },
+                };
+                let id = self.debugger.add_watchpoint(watchpoint);
+                if let Some(old) = self.watchpoints.insert((kind, addr, len), id) {
+                    self.debugger.remove_watchpoint(old);
+                }
+            }
+            _ => return Some(String::new()),
+        }
+        Some("OK".into())
+    }
+
+    fn remove_point(&mut self, args: &str) -> Option<String> {
+        let (kind, addr, len) = parse_point(args)?;
+        match kind {
+            0 | 1 => {
+                let id = self.breakpoints.remove(&addr)?;
+                self.debugger.remove_breakpoint(id);
+            }
+            2..=4 => {
+                let id = self.watchpoints.remove(&(kind, addr, len))?;
+                self.debugger.remove_watchpoint(id);
+            }
+            _ => return Some(String::new()),
+        }
+        Some("OK".into())
+    }
+}
+
+fn query(query: &str) -> String {
+    if query.starts_with("Supported") {
+        "PacketSize=1000;qXfer:features:read+".into()
+    } else if query == "Attached" {
+        "1".into()
+    } else if let Some(args) = query.strip_prefix("Xfer:features:read:target.xml:") {
+        match parse_offset_len(args) {
+            Some((offset, len)) => {
+                let start = offset.min(TARGET_XML.len());
+                let end = start.saturating_add(len).min(TARGET_XML.len());
+                let prefix = if end == TARGET_XML.len() { 'l' } else { 'm' };
+                format!("{}{}", prefix, &TARGET_XML[start..end])
+            }
+            None => "E01".into(),
+        }
+    } else {
+        String::new()
+    }
+}
+
+// Yields `Some(None)` for a packet with a bad checksum and `None` at end of stream.
+fn read_packet(connection: &mut impl Read) -> io::Result<Option<Option<Vec<u8>>>> {
+    loop {
+        match read_byte(connection)? {
+            None => return Ok(None),
+            Some(b'$') => break,
+            Some(_) => (),
+        }
+    }
+    let mut packet = Vec::new();
+    let mut checksum = 0u8;
+    let mut escaped = false;
+    loop {
+        let byte = match read_byte(connection)? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        if byte == b'#' && !escaped {
+            break;
+        }
+        checksum = checksum.wrapping_add(byte);
+        if escaped {
+            packet.push(byte ^ 0x20);
+            escaped = false
+        } else if byte == b'}' {
+            escaped = true
+        } else {
+            packet.push(byte)
+        }
+    }
+    let mut digits = [0x00; 2];
+    connection.read_exact(&mut digits)?;
+    let expected = std::str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+    Ok(Some(if expected == Some(checksum) {
+        Some(packet)
+    } else {
+        None
+    }))
+}
+
+fn read_byte(connection: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0x00];
+    match connection.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn write_ack(connection: &mut impl Write, ack: u8) -> io::Result<()> {
+    connection.write_all(&[ack])?;
+    connection.flush()
+}
+
+fn write_packet(connection: &mut impl Write, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, u8::wrapping_add);
+    write!(connection, "${}#{:02x}", data, checksum)?;
+    connection.flush()
+}
+
+fn hex_u16(value: u16) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).ok())
+        .collect()
+}
+
+fn parse_addr_len(args: &str) -> Option<(u16, u16)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        u16::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn parse_offset_len(args: &str) -> Option<(usize, usize)> {
+    let (offset, len) = args.split_once(',')?;
+    Some((
+        usize::from_str_radix(offset, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+fn parse_point(args: &str) -> Option<(u8, u16, u16)> {
+    let (kind, rest) = args.split_once(',')?;
+    let (addr, len) = parse_addr_len(rest.split(';').next()?)?;
+    Some((kind.parse().ok()?, addr, len))
+}
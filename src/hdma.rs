@@ -0,0 +1,195 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const HDMA1: u16 = 0xff51;
+pub const HDMA2: u16 = 0xff52;
+pub const HDMA3: u16 = 0xff53;
+pub const HDMA4: u16 = 0xff54;
+pub const HDMA5: u16 = 0xff55;
+
+const BLOCK_SIZE: u16 = 0x10;
+
+#[derive(Default)]
+pub struct Hdma {
+    source: u16,
+    dest: u16,
+    blocks: u8,
+    hblank_mode: bool,
+    active: bool,
+    pending: u16,
+}
+
+impl Hdma {
+    pub fn is_transferring(&self) -> bool {
+        self.pending > 0
+    }
+
+    pub fn start_hblank_block(&mut self) {
+        if self.active && self.hblank_mode && self.pending == 0 {
+            self.pending = BLOCK_SIZE
+        }
+    }
+
+    pub fn next_transfer(&mut self) -> Option<(u16, u16)> {
+        if self.pending == 0 {
+            return None;
+        }
+        let transfer = (self.source, 0x8000 | self.dest & 0x1fff);
+        self.source = self.source.wrapping_add(1);
+        self.dest = self.dest.wrapping_add(1);
+        self.pending -= 1;
+        if self.pending % BLOCK_SIZE == 0 {
+            self.blocks -= 1;
+            if self.blocks == 0 {
+                self.active = false
+            }
+        }
+        Some(transfer)
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            HDMA5 if self.active => (self.blocks - 1) & 0x7f,
+            HDMA5 => 0x80 | self.blocks.wrapping_sub(1) & 0x7f,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            HDMA1 => self.source = self.source & 0x00ff | u16::from(data) << 8,
+            HDMA2 => self.source = self.source & 0xff00 | u16::from(data & 0xf0),
+            HDMA3 => self.dest = self.dest & 0x00ff | u16::from(data & 0x1f) << 8,
+            HDMA4 => self.dest = self.dest & 0xff00 | u16::from(data & 0xf0),
+            HDMA5 if self.active && self.hblank_mode && data & 0x80 == 0 => self.active = false,
+            HDMA5 => {
+                self.blocks = (data & 0x7f) + 1;
+                self.active = true;
+                self.hblank_mode = data & 0x80 != 0;
+                if !self.hblank_mode {
+                    self.pending = u16::from(self.blocks) * BLOCK_SIZE
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Like `write`, but HDMA5 only sets the length and mode; no transfer starts or stops.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            HDMA5 => {
+                self.blocks = (data & 0x7f) + 1;
+                self.hblank_mode = data & 0x80 != 0
+            }
+            _ => self.write(addr, data),
+        }
+    }
+}
+
+impl SaveState for Hdma {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u16(self.source);
+        writer.u16(self.dest);
+        writer.u8(self.blocks);
+        writer.bool(self.hblank_mode);
+        writer.bool(self.active);
+        writer.u16(self.pending)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.source = reader.u16()?;
+        self.dest = reader.u16()?;
+        self.blocks = reader.u8()?;
+        self.hblank_mode = reader.bool()?;
+        self.active = reader.bool()?;
+        self.pending = reader.u16()?;
+        // Each pending byte belongs to a block that is still counted down.
+        if self.active && self.blocks == 0 || self.pending > u16::from(self.blocks) * BLOCK_SIZE {
+            return Err(StateError::InvalidValue("HDMA blocks", self.blocks.into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hdma(source: u16, dest: u16) -> Hdma {
+        let mut hdma = Hdma::default();
+        hdma.write(HDMA1, (source >> 8) as u8);
+        hdma.write(HDMA2, source as u8);
+        hdma.write(HDMA3, (dest >> 8) as u8);
+        hdma.write(HDMA4, dest as u8);
+        hdma
+    }
+
+    fn drain(hdma: &mut Hdma) -> Vec<(u16, u16)> {
+        std::iter::from_fn(|| hdma.next_transfer()).collect()
+    }
+
+    #[test]
+    fn general_purpose_dma_transfers_whole_length_at_once() {
+        let mut hdma = hdma(0xc000, 0x8800);
+        hdma.write(HDMA5, 0x01);
+        assert!(hdma.is_transferring());
+        let transfers = drain(&mut hdma);
+        assert_eq!(transfers.len(), 32);
+        assert_eq!(transfers[0], (0xc000, 0x8800));
+        assert_eq!(transfers[31], (0xc01f, 0x881f));
+        assert!(!hdma.is_transferring());
+        assert_eq!(hdma.read(HDMA5), 0xff)
+    }
+
+    #[test]
+    fn address_registers_ignore_low_nibble() {
+        let mut hdma = hdma(0xc00f, 0xff0f);
+        hdma.write(HDMA5, 0x00);
+        assert_eq!(hdma.next_transfer(), Some((0xc000, 0x9f00)))
+    }
+
+    #[test]
+    fn hblank_dma_transfers_one_block_per_hblank() {
+        let mut hdma = hdma(0xc000, 0x8000);
+        hdma.write(HDMA5, 0x82);
+        assert!(!hdma.is_transferring());
+        assert_eq!(hdma.read(HDMA5), 0x02);
+        hdma.start_hblank_block();
+        assert_eq!(drain(&mut hdma).len(), 16);
+        assert_eq!(hdma.read(HDMA5), 0x01);
+        hdma.start_hblank_block();
+        assert_eq!(drain(&mut hdma)[0], (0xc010, 0x8010))
+    }
+
+    #[test]
+    fn hblank_dma_can_be_canceled() {
+        let mut hdma = hdma(0xc000, 0x8000);
+        hdma.write(HDMA5, 0x83);
+        hdma.start_hblank_block();
+        drain(&mut hdma);
+        hdma.write(HDMA5, 0x00);
+        assert_eq!(hdma.read(HDMA5), 0x82);
+        hdma.start_hblank_block();
+        assert!(!hdma.is_transferring())
+    }
+
+    #[test]
+    fn address_registers_are_write_only() {
+        let hdma = hdma(0xc000, 0x8000);
+        assert_eq!(hdma.read(HDMA1), 0xff);
+        assert_eq!(hdma.read(HDMA4), 0xff)
+    }
+
+    #[test]
+    fn loading_active_transfer_without_blocks_fails() {
+        let mut hdma = hdma(0xc000, 0x8800);
+        hdma.write(HDMA5, 0x80);
+        let mut writer = StateWriter::default();
+        hdma.save(&mut writer);
+        let mut state = writer.into_bytes();
+        state[4] = 0x00;
+        assert_eq!(
+            hdma.load(&mut StateReader::new(&state)),
+            Err(StateError::InvalidValue("HDMA blocks", 0x00))
+        )
+    }
+}
@@ -0,0 +1,262 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+use std::ops::{BitAnd, BitOr, Not};
+
+pub const P1: u16 = 0xff00;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Buttons {
+    bits: u8,
+}
+
+impl Buttons {
+    pub const RIGHT: Self = Self { bits: 0x01 };
+    pub const LEFT: Self = Self { bits: 0x02 };
+    pub const UP: Self = Self { bits: 0x04 };
+    pub const DOWN: Self = Self { bits: 0x08 };
+    pub const A: Self = Self { bits: 0x10 };
+    pub const B: Self = Self { bits: 0x20 };
+    pub const SELECT: Self = Self { bits: 0x40 };
+    pub const START: Self = Self { bits: 0x80 };
+
+    pub const fn empty() -> Self {
+        Self { bits: 0x00 }
+    }
+
+    pub const fn all() -> Self {
+        Self { bits: 0xff }
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self { bits }
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.bits
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.bits == 0x00
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self & other == other
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        *self = *self | other
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        *self = *self & !other
+    }
+
+    fn directions(self) -> u8 {
+        self.bits & 0x0f
+    }
+
+    fn actions(self) -> u8 {
+        self.bits >> 4
+    }
+}
+
+impl BitAnd for Buttons {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self {
+            bits: self.bits & rhs.bits,
+        }
+    }
+}
+
+impl BitOr for Buttons {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self {
+            bits: self.bits | rhs.bits,
+        }
+    }
+}
+
+impl Not for Buttons {
+    type Output = Self;
+
+    fn not(self) -> Self::Output {
+        Self { bits: !self.bits }
+    }
+}
+
+#[derive(Default)]
+pub struct Joypad {
+    buttons: Buttons,
+    select: u8,
+    pressed: u8,
+    interrupt: bool,
+}
+
+impl Joypad {
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+        self.update_lines()
+    }
+
+    pub fn read(&self) -> u8 {
+        0xc0 | self.select | !self.pressed & 0x0f
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.select = data & 0x30;
+        self.update_lines()
+    }
+
+    // Like `write`, but never requests the joypad interrupt.
+    pub fn poke(&mut self, data: u8) {
+        self.select = data & 0x30;
+        self.pressed = self.lines()
+    }
+
+    // A high-to-low transition on any input line both requests the joypad interrupt and is what
+    // wakes the CPU from STOP, so callers treat this as the wake-up signal as well.
+    pub fn take_interrupt(&mut self) -> bool {
+        let interrupt = self.interrupt;
+        self.interrupt = false;
+        interrupt
+    }
+
+    fn lines(&self) -> u8 {
+        let mut pressed = 0x00;
+        if self.select & 0x10 == 0 {
+            pressed |= self.buttons.directions()
+        }
+        if self.select & 0x20 == 0 {
+            pressed |= self.buttons.actions()
+        }
+        pressed
+    }
+
+    fn update_lines(&mut self) {
+        let pressed = self.lines();
+        if pressed & !self.pressed != 0 {
+            self.interrupt = true
+        }
+        self.pressed = pressed
+    }
+}
+
+impl SaveState for Joypad {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.buttons.bits);
+        writer.u8(self.select);
+        writer.u8(self.pressed);
+        writer.bool(self.interrupt)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = Buttons::from_bits(reader.u8()?);
+        self.select = reader.u8()?;
+        self.pressed = reader.u8()?;
+        self.interrupt = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SELECT_DIRECTIONS: u8 = 0x20;
+    const SELECT_ACTIONS: u8 = 0x10;
+    const SELECT_NONE: u8 = 0x30;
+
+    #[test]
+    fn no_buttons_pressed_reads_all_lines_high() {
+        let mut joypad = Joypad::default();
+        joypad.write(SELECT_DIRECTIONS);
+        assert_eq!(joypad.read(), 0xef)
+    }
+
+    #[test]
+    fn pressed_direction_reads_low_when_directions_selected() {
+        let mut joypad = Joypad::default();
+        joypad.write(SELECT_DIRECTIONS);
+        joypad.set_buttons(Buttons::LEFT | Buttons::A);
+        assert_eq!(joypad.read(), 0xed)
+    }
+
+    #[test]
+    fn pressed_action_reads_low_when_actions_selected() {
+        let mut joypad = Joypad::default();
+        joypad.write(SELECT_ACTIONS);
+        joypad.set_buttons(Buttons::LEFT | Buttons::START);
+        assert_eq!(joypad.read(), 0xd7)
+    }
+
+    #[test]
+    fn selecting_both_groups_combines_lines() {
+        let mut joypad = Joypad::default();
+        joypad.write(0x00);
+        joypad.set_buttons(Buttons::RIGHT | Buttons::B);
+        assert_eq!(joypad.read(), 0xcc)
+    }
+
+    #[test]
+    fn deselected_buttons_are_not_visible() {
+        let mut joypad = Joypad::default();
+        joypad.write(SELECT_NONE);
+        joypad.set_buttons(Buttons::all());
+        assert_eq!(joypad.read(), 0xff)
+    }
+
+    #[test]
+    fn pressing_selected_button_requests_interrupt() {
+        let mut joypad = Joypad::default();
+        joypad.write(SELECT_ACTIONS);
+        joypad.set_buttons(Buttons::A);
+        assert!(joypad.take_interrupt());
+        assert!(!joypad.take_interrupt())
+    }
+
+    #[test]
+    fn pressing_deselected_button_does_not_request_interrupt() {
+        let mut joypad = Joypad::default();
+        joypad.write(SELECT_DIRECTIONS);
+        joypad.set_buttons(Buttons::A);
+        assert!(!joypad.take_interrupt())
+    }
+
+    #[test]
+    fn releasing_button_does_not_request_interrupt() {
+        let mut joypad = Joypad::default();
+        joypad.write(SELECT_ACTIONS);
+        joypad.set_buttons(Buttons::A);
+        joypad.take_interrupt();
+        joypad.set_buttons(Buttons::empty());
+        assert!(!joypad.take_interrupt())
+    }
+
+    #[test]
+    fn selecting_group_with_held_button_requests_interrupt() {
+        let mut joypad = Joypad::default();
+        joypad.write(SELECT_NONE);
+        joypad.set_buttons(Buttons::DOWN);
+        assert!(!joypad.take_interrupt());
+        joypad.write(SELECT_DIRECTIONS);
+        assert!(joypad.take_interrupt())
+    }
+
+    #[test]
+    fn buttons_insert_and_remove() {
+        let mut buttons = Buttons::UP | Buttons::SELECT;
+        buttons.insert(Buttons::B);
+        buttons.remove(Buttons::UP);
+        assert_eq!(buttons, Buttons::B | Buttons::SELECT);
+        assert!(buttons.contains(Buttons::SELECT));
+        assert!(!buttons.contains(Buttons::UP | Buttons::B))
+    }
+}
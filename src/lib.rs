@@ -1 +1,26 @@
+// `is_multiple_of` needs a newer toolchain than the rest of the crate does.
+#![allow(clippy::manual_is_multiple_of)]
+
+pub use model::Model;
+pub use system::GameBoy;
+
+pub mod apu;
+pub mod cartridge;
 pub mod cpu;
+pub mod debugger;
+pub mod dma;
+pub mod gdb;
+pub mod hdma;
+pub mod interrupt;
+pub mod joypad;
+pub mod memory;
+pub mod model;
+pub mod ppu;
+pub mod profiler;
+pub mod rewind;
+pub mod serial;
+pub mod sgb;
+pub mod state;
+pub mod symbols;
+pub mod system;
+pub mod timer;
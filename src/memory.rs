@@ -0,0 +1,522 @@
+use crate::apu::Apu;
+use crate::cartridge::Cartridge;
+use crate::hdma::Hdma;
+use crate::joypad::Joypad;
+use crate::ppu::{Ppu, BCPD, BCPS, VBK};
+use crate::serial::{Serial, SC};
+use crate::sgb::Sgb;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+use crate::timer::Timer;
+use crate::Model;
+
+pub const IF: u16 = 0xff0f;
+pub const IE: u16 = 0xffff;
+pub const KEY1: u16 = 0xff4d;
+pub const BOOT: u16 = 0xff50;
+pub const SVBK: u16 = 0xff70;
+
+const WRAM_BANK_SIZE: usize = 0x1000;
+const HRAM_SIZE: usize = 0x7f;
+
+const POST_BOOT_IO: &[(u16, u8)] = &[
+    (0xff26, 0xf1),
+    (0xff10, 0x80),
+    (0xff11, 0xbf),
+    (0xff12, 0xf3),
+    (0xff13, 0xff),
+    (0xff16, 0x3f),
+    (0xff17, 0x00),
+    (0xff18, 0xff),
+    (0xff19, 0xbf),
+    (0xff1a, 0x7f),
+    (0xff1b, 0xff),
+    (0xff1c, 0x9f),
+    (0xff1d, 0xff),
+    (0xff1e, 0xbf),
+    (0xff20, 0xff),
+    (0xff21, 0x00),
+    (0xff22, 0x00),
+    (0xff23, 0xbf),
+    (0xff24, 0x77),
+    (0xff25, 0xf3),
+    (0xff00, 0xcf),
+    (0xff01, 0x00),
+    (0xff05, 0x00),
+    (0xff06, 0x00),
+    (0xff07, 0xf8),
+    (0xff0f, 0xe1),
+    (0xff40, 0x91),
+    (0xff41, 0x85),
+    (0xff42, 0x00),
+    (0xff43, 0x00),
+    (0xff45, 0x00),
+    (0xff47, 0xfc),
+    (0xff4a, 0x00),
+    (0xff4b, 0x00),
+];
+
+// Registers that the boot ROMs of different models leave in different states.
+fn post_boot_model_io(model: Model) -> [(u16, u8); 2] {
+    [
+        // The SGB boot ROM does not play the startup sound on channel 1.
+        (0xff14, if model.is_sgb() { 0x3f } else { 0xbf }),
+        (SC, if model.is_cgb() { 0x7f } else { 0x7e }),
+    ]
+}
+
+pub trait Memory {
+    fn read(&mut self, addr: u16) -> u8;
+    fn write(&mut self, addr: u16, data: u8);
+    fn write_oam(&mut self, index: u8, data: u8);
+}
+
+pub struct MemoryMap {
+    pub cartridge: Cartridge,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub timer: Timer,
+    pub serial: Serial,
+    pub joypad: Joypad,
+    pub hdma: Hdma,
+    pub sgb: Option<Sgb>,
+    pub r#if: u8,
+    cgb: bool,
+    boot_rom: Option<Vec<u8>>,
+    wram: Vec<u8>,
+    svbk: u8,
+    hram: [u8; HRAM_SIZE],
+    double_speed: bool,
+    speed_switch_armed: bool,
+}
+
+impl MemoryMap {
+    pub fn new(cartridge: Cartridge, model: Model) -> Self {
+        let wram_banks = if model.is_cgb() { 8 } else { 2 };
+        let cgb = model.is_cgb() && cartridge.supports_cgb();
+        Self {
+            ppu: Ppu::new(model, cartridge.supports_cgb()),
+            cartridge,
+            apu: Apu::new(model),
+            timer: Default::default(),
+            serial: Serial::new(model),
+            joypad: Default::default(),
+            hdma: Default::default(),
+            sgb: if model.is_sgb() {
+                Some(Default::default())
+            } else {
+                None
+            },
+            r#if: 0x00,
+            cgb,
+            boot_rom: None,
+            wram: vec![0x00; wram_banks * WRAM_BANK_SIZE],
+            svbk: 0,
+            hram: [0x00; HRAM_SIZE],
+            double_speed: false,
+            speed_switch_armed: false,
+        }
+    }
+
+    pub fn with_boot_rom(cartridge: Cartridge, model: Model, boot_rom: Vec<u8>) -> Self {
+        Self {
+            boot_rom: Some(boot_rom),
+            ..Self::new(cartridge, model)
+        }
+    }
+
+    pub fn post_boot(cartridge: Cartridge, model: Model) -> Self {
+        let mut memory = Self::new(cartridge, model);
+        memory.timer = Timer::with_div(model.post_boot_div());
+        for &(addr, data) in POST_BOOT_IO.iter().chain(&post_boot_model_io(model)) {
+            memory.write(addr, data)
+        }
+        if memory.cgb {
+            memory.write(BCPS, 0x80);
+            for _ in 0..0x40 {
+                memory.write(BCPD, 0xff)
+            }
+        } else if model.is_cgb() {
+            memory.ppu.load_compatibility_palettes()
+        }
+        memory
+    }
+
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Bank mapped at `addr`, numbered like RGBDS does: unbanked regions are bank 0.
+    pub fn bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x0000..=0x7fff | 0xa000..=0xbfff => self.cartridge.bank(addr),
+            0x8000..=0x9fff => self.ppu.vram_bank().into(),
+            0xd000..=0xdfff | 0xf000..=0xfdff => self.svbk.max(1).into(),
+            _ => 0,
+        }
+    }
+
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn step_hdma(&mut self) {
+        for _ in 0..2 {
+            if let Some((source, dest)) = self.hdma.next_transfer() {
+                let data = self.read(source);
+                self.ppu.write(dest, data)
+            }
+        }
+    }
+
+    // Tool access: bypasses PPU mode blocking and cartridge RAM enable, patches
+    // ROM directly, and stores registers without their write side effects.
+    pub fn peek(&self, addr: u16) -> u8 {
+        if let Some(data) = self.boot_rom_byte(addr) {
+            return data;
+        }
+        match addr {
+            0x0000..=0x7fff | 0xa000..=0xbfff => self.cartridge.peek(addr),
+            0x8000..=0x9fff | 0xfe00..=0xfe9f | 0xff40..=0xff4b | VBK | 0xff68..=0xff6b => {
+                self.ppu.peek(addr)
+            }
+            _ => self.read_mapped(addr),
+        }
+    }
+
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        if let Some(index) = self.boot_rom_index(addr) {
+            if let Some(boot_rom) = &mut self.boot_rom {
+                boot_rom[index] = data
+            }
+            return;
+        }
+        match addr {
+            0x0000..=0x7fff | 0xa000..=0xbfff => self.cartridge.poke(addr, data),
+            0x8000..=0x9fff | 0xfe00..=0xfe9f | 0xff40..=0xff4b | VBK | 0xff68..=0xff6b => {
+                self.ppu.poke(addr, data)
+            }
+            0xff00 => self.joypad.poke(data),
+            0xff01..=0xff02 => self.serial.poke(addr, data),
+            0xff04..=0xff07 => self.timer.poke(addr, data),
+            0xff10..=0xff3f => self.apu.poke(addr, data),
+            KEY1 if self.cgb => {
+                self.double_speed = data & 0x80 != 0;
+                self.speed_switch_armed = data & 0x01 != 0
+            }
+            0xff51..=0xff55 if self.cgb => self.hdma.poke(addr, data),
+            BOOT => (),
+            _ => self.write(addr, data),
+        }
+    }
+
+    fn wram_index(&self, addr: u16) -> usize {
+        let offset = usize::from(addr) & (WRAM_BANK_SIZE - 1);
+        if addr & 0x1000 == 0 {
+            offset
+        } else {
+            usize::from(self.svbk.max(1)) * WRAM_BANK_SIZE + offset
+        }
+    }
+
+    fn read_mapped(&self, addr: u16) -> u8 {
+        if let Some(data) = self.boot_rom_byte(addr) {
+            return data;
+        }
+        match addr {
+            0x0000..=0x7fff | 0xa000..=0xbfff => self.cartridge.read(addr),
+            0x8000..=0x9fff | 0xfe00..=0xfe9f => self.ppu.read(addr),
+            0xc000..=0xfdff => self.wram[self.wram_index(addr)],
+            0xff00 => match &self.sgb {
+                Some(sgb) => sgb.read_p1(self.joypad.read()),
+                None => self.joypad.read(),
+            },
+            0xff01..=0xff02 => self.serial.read(addr),
+            0xff04..=0xff07 => self.timer.read(addr),
+            IF => 0xe0 | self.r#if,
+            0xff10..=0xff3f => self.apu.read(addr),
+            0xff40..=0xff4b | VBK | 0xff68..=0xff6b => self.ppu.read(addr),
+            KEY1 if self.cgb => {
+                0x7e | if self.double_speed { 0x80 } else { 0x00 }
+                    | if self.speed_switch_armed { 0x01 } else { 0x00 }
+            }
+            SVBK if self.cgb => 0xf8 | self.svbk,
+            0xff51..=0xff55 if self.cgb => self.hdma.read(addr),
+            0xff80..=0xfffe => self.hram[usize::from(addr - 0xff80)],
+            _ => 0xff,
+        }
+    }
+
+    fn boot_rom_byte(&self, addr: u16) -> Option<u8> {
+        let index = self.boot_rom_index(addr)?;
+        self.boot_rom.as_ref().map(|boot_rom| boot_rom[index])
+    }
+
+    fn boot_rom_index(&self, addr: u16) -> Option<usize> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        match addr {
+            0x0000..=0x00ff | 0x0200..=0x08ff if usize::from(addr) < boot_rom.len() => {
+                Some(usize::from(addr))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Memory for MemoryMap {
+    fn read(&mut self, addr: u16) -> u8 {
+        self.read_mapped(addr)
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..=0x7fff | 0xa000..=0xbfff => self.cartridge.write(addr, data),
+            0x8000..=0x9fff | 0xfe00..=0xfe9f => self.ppu.write(addr, data),
+            0xc000..=0xfdff => {
+                let index = self.wram_index(addr);
+                self.wram[index] = data
+            }
+            0xff00 => {
+                self.joypad.write(data);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_p1(data)
+                }
+            }
+            0xff01..=0xff02 => self.serial.write(addr, data),
+            0xff04..=0xff07 => self.timer.write(addr, data),
+            IF => self.r#if = data & 0x1f,
+            0xff10..=0xff3f => self.apu.write(addr, data),
+            0xff40..=0xff4b | VBK | 0xff68..=0xff6b => self.ppu.write(addr, data),
+            KEY1 if self.cgb => self.speed_switch_armed = data & 0x01 != 0,
+            SVBK if self.cgb => self.svbk = data & 0x07,
+            0xff51..=0xff55 if self.cgb => self.hdma.write(addr, data),
+            BOOT if data != 0x00 => self.boot_rom = None,
+            0xff80..=0xfffe => self.hram[usize::from(addr - 0xff80)] = data,
+            _ => (),
+        }
+    }
+
+    fn write_oam(&mut self, index: u8, data: u8) {
+        self.ppu.write_oam(index, data)
+    }
+}
+
+impl SaveState for MemoryMap {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.r#if);
+        writer.bool(self.boot_rom.is_some());
+        if let Some(boot_rom) = &self.boot_rom {
+            writer.u32(boot_rom.len() as u32);
+            writer.bytes(boot_rom)
+        }
+        writer.bytes(&self.wram);
+        writer.u8(self.svbk);
+        writer.bytes(&self.hram);
+        writer.bool(self.double_speed);
+        writer.bool(self.speed_switch_armed)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.r#if = reader.u8()?;
+        self.boot_rom = if reader.bool()? {
+            let len = reader.u32()? as usize;
+            Some(reader.bytes(len)?.to_vec())
+        } else {
+            None
+        };
+        reader.fill(&mut self.wram)?;
+        self.svbk = reader.u8_below("SVBK", 8)?;
+        reader.fill(&mut self.hram)?;
+        self.double_speed = reader.bool()?;
+        self.speed_switch_armed = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cartridge::tests::rom;
+    use crate::hdma::HDMA5;
+    use crate::ppu::{Mode, LCDC};
+    use crate::timer::DIV;
+
+    fn memory_map(model: Model) -> MemoryMap {
+        let mut rom = rom(0x00, 2, 0);
+        rom[0x0143] = 0x80;
+        MemoryMap::new(Cartridge::new(rom).unwrap(), model)
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut memory = memory_map(Model::Dmg);
+        memory.write(0xc123, 0x42);
+        memory.write(0xfd00, 0x24);
+        assert_eq!(memory.read(0xe123), 0x42);
+        assert_eq!(memory.read(0xdd00), 0x24)
+    }
+
+    #[test]
+    fn svbk_selects_wram_bank_on_cgb() {
+        let mut memory = memory_map(Model::Cgb);
+        memory.write(0xd000, 0x11);
+        memory.write(SVBK, 0x07);
+        memory.write(0xd000, 0x77);
+        memory.write(0xc000, 0xcc);
+        assert_eq!(memory.read(SVBK), 0xff);
+        memory.write(SVBK, 0x00);
+        assert_eq!(memory.read(0xd000), 0x11);
+        assert_eq!(memory.read(0xc000), 0xcc);
+        memory.write(SVBK, 0x07);
+        assert_eq!(memory.read(0xd000), 0x77)
+    }
+
+    #[test]
+    fn post_boot_io_depends_on_model() {
+        for &(model, nr52, sc) in &[
+            (Model::Dmg, 0xf1, 0x7e),
+            (Model::Sgb2, 0xf0, 0x7e),
+            (Model::Cgb, 0xf1, 0x7f),
+        ] {
+            let memory = MemoryMap::post_boot(Cartridge::new(rom(0x00, 2, 0)).unwrap(), model);
+            assert_eq!((memory.peek(0xff26), memory.peek(SC)), (nr52, sc))
+        }
+    }
+
+    #[test]
+    fn dmg_cartridge_on_cgb_runs_in_compatibility_mode() {
+        let cartridge = Cartridge::new(rom(0x00, 2, 0)).unwrap();
+        let mut memory = MemoryMap::post_boot(cartridge, Model::Cgb);
+        memory.write(0xd000, 0x11);
+        memory.write(SVBK, 0x07);
+        memory.write(KEY1, 0x01);
+        assert_eq!(memory.read(SVBK), 0xff);
+        assert_eq!(memory.read(KEY1), 0xff);
+        assert_eq!(memory.read(0xd000), 0x11);
+        assert_eq!(memory.read(VBK), 0xff);
+        assert_eq!(memory.ppu.peek(BCPD), 0xff)
+    }
+
+    #[test]
+    fn svbk_is_ignored_on_dmg() {
+        let mut memory = memory_map(Model::Dmg);
+        memory.write(0xd000, 0x11);
+        memory.write(SVBK, 0x02);
+        assert_eq!(memory.read(0xd000), 0x11);
+        assert_eq!(memory.read(SVBK), 0xff)
+    }
+
+    #[test]
+    fn speed_switch_requires_arming_key1() {
+        let mut memory = memory_map(Model::Cgb);
+        assert!(!memory.switch_speed());
+        memory.write(KEY1, 0x01);
+        assert_eq!(memory.read(KEY1), 0x7f);
+        assert!(memory.switch_speed());
+        assert!(memory.is_double_speed());
+        assert_eq!(memory.read(KEY1), 0xfe)
+    }
+
+    #[test]
+    fn bank_reports_mapped_wram_and_rom_banks() {
+        let mut rom = rom(0x01, 8, 0);
+        rom[0x0143] = 0x80;
+        let mut memory = MemoryMap::new(Cartridge::new(rom).unwrap(), Model::Cgb);
+        assert_eq!(memory.bank(0x0150), 0);
+        assert_eq!(memory.bank(0x4000), 1);
+        assert_eq!(memory.bank(0xd000), 1);
+        memory.write(0x2000, 0x05);
+        memory.write(SVBK, 0x03);
+        assert_eq!(memory.bank(0x7fff), 5);
+        assert_eq!(memory.bank(0xdfff), 3);
+        assert_eq!(memory.bank(0xc000), 0);
+        assert_eq!(memory.bank(0xff80), 0)
+    }
+
+    #[test]
+    fn peek_and_poke_bypass_mode_blocking() {
+        let mut memory = memory_map(Model::Dmg);
+        memory.write(LCDC, 0x80);
+        while memory.ppu.mode() != Mode::Drawing {
+            memory.ppu.step();
+        }
+        memory.poke(0x8000, 0x12);
+        memory.poke(0xfe00, 0x34);
+        assert_eq!(memory.read(0x8000), 0xff);
+        assert_eq!(memory.peek(0x8000), 0x12);
+        assert_eq!(memory.peek(0xfe00), 0x34)
+    }
+
+    #[test]
+    fn poke_skips_register_side_effects() {
+        let mut memory = memory_map(Model::Cgb);
+        memory.write(BCPS, 0x80);
+        memory.poke(BCPD, 0x55);
+        assert_eq!(memory.read(BCPS), 0xc0);
+        assert_eq!(memory.peek(BCPD), 0x55);
+        memory.poke(LCDC, 0x00);
+        assert_eq!(memory.peek(LCDC), 0x00);
+        memory.poke(BOOT, 0x01);
+        memory.poke(0x0000, 0x42);
+        assert_eq!(memory.peek(0x0000), 0x42);
+        memory.poke(DIV, 0x12);
+        assert_eq!(memory.peek(DIV), 0x12);
+        memory.write(0xff26, 0x80);
+        memory.write(0xff24, 0x77);
+        memory.write(0xff17, 0xf0);
+        memory.poke(0xff19, 0x80);
+        assert_eq!(memory.peek(0xff26) & 0x02, 0x00);
+        memory.poke(0xff26, 0x00);
+        assert_eq!(memory.peek(0xff26) & 0x80, 0x00);
+        assert_eq!(memory.peek(0xff24), 0x77);
+        memory.poke(HDMA5, 0x01);
+        assert!(!memory.hdma.is_transferring());
+        assert_eq!(memory.peek(HDMA5), 0x81)
+    }
+
+    #[test]
+    fn poke_to_sc_does_not_restart_transfer() {
+        let mut memory = memory_map(Model::Cgb);
+        let clock = |memory: &mut MemoryMap| {
+            memory.serial.step(true);
+            memory.serial.step(false)
+        };
+        memory.write(SC, 0x81);
+        for _ in 0..4 {
+            assert!(!clock(&mut memory))
+        }
+        memory.poke(SC, 0x81);
+        assert_eq!(memory.peek(SC), 0xfd);
+        for _ in 0..3 {
+            assert!(!clock(&mut memory))
+        }
+        assert!(clock(&mut memory))
+    }
+
+    #[test]
+    fn poke_patches_mapped_boot_rom() {
+        let cartridge = Cartridge::new(rom(0x00, 2, 0)).unwrap();
+        let mut memory = MemoryMap::with_boot_rom(cartridge, Model::Dmg, vec![0x00; 0x100]);
+        memory.poke(0x0010, 0xaa);
+        assert_eq!(memory.peek(0x0010), 0xaa);
+        memory.write(BOOT, 0x01);
+        assert_eq!(memory.peek(0x0010), 0x00)
+    }
+
+    #[test]
+    fn key1_is_unmapped_on_dmg() {
+        let mut memory = memory_map(Model::Dmg);
+        memory.write(KEY1, 0x01);
+        assert_eq!(memory.read(KEY1), 0xff);
+        assert!(!memory.switch_speed())
+    }
+}
@@ -0,0 +1,65 @@
+use std::convert::TryFrom;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl Model {
+    pub fn is_cgb(self) -> bool {
+        match self {
+            Model::Dmg0 | Model::Dmg | Model::Mgb | Model::Sgb | Model::Sgb2 => false,
+            Model::Cgb | Model::Agb => true,
+        }
+    }
+
+    pub fn is_sgb(self) -> bool {
+        matches!(self, Model::Sgb | Model::Sgb2)
+    }
+
+    pub fn post_boot_div(self) -> u16 {
+        match self {
+            Model::Dmg0 => 0x182c,
+            Model::Dmg | Model::Mgb => 0xabcc,
+            Model::Sgb | Model::Sgb2 => 0xd85c,
+            Model::Cgb | Model::Agb => 0x1ea0,
+        }
+    }
+}
+
+impl From<Model> for u8 {
+    fn from(model: Model) -> Self {
+        match model {
+            Model::Dmg0 => 0,
+            Model::Dmg => 1,
+            Model::Mgb => 2,
+            Model::Sgb => 3,
+            Model::Sgb2 => 4,
+            Model::Cgb => 5,
+            Model::Agb => 6,
+        }
+    }
+}
+
+impl TryFrom<u8> for Model {
+    type Error = u8;
+
+    fn try_from(code: u8) -> Result<Self, u8> {
+        Ok(match code {
+            0 => Model::Dmg0,
+            1 => Model::Dmg,
+            2 => Model::Mgb,
+            3 => Model::Sgb,
+            4 => Model::Sgb2,
+            5 => Model::Cgb,
+            6 => Model::Agb,
+            code => return Err(code),
+        })
+    }
+}
@@ -0,0 +1,50 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorCorrection {
+    None,
+    Lcd,
+}
+
+pub fn to_rgb888(color: u16, correction: ColorCorrection) -> [u8; 3] {
+    let r = u32::from(color & 0x1f);
+    let g = u32::from(color >> 5 & 0x1f);
+    let b = u32::from(color >> 10 & 0x1f);
+    match correction {
+        ColorCorrection::None => [expand(r), expand(g), expand(b)],
+        ColorCorrection::Lcd => [
+            mix(26 * r + 4 * g + 2 * b),
+            mix(24 * g + 8 * b),
+            mix(6 * r + 4 * g + 22 * b),
+        ],
+    }
+}
+
+fn expand(channel: u32) -> u8 {
+    (channel << 3 | channel >> 2) as u8
+}
+
+fn mix(channel: u32) -> u8 {
+    (channel.min(960) >> 2) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uncorrected_channels_are_expanded_to_8_bits() {
+        assert_eq!(to_rgb888(0x7fff, ColorCorrection::None), [0xff, 0xff, 0xff]);
+        assert_eq!(to_rgb888(0x0000, ColorCorrection::None), [0x00, 0x00, 0x00]);
+        assert_eq!(to_rgb888(0x7c10, ColorCorrection::None), [0x84, 0x00, 0xff])
+    }
+
+    #[test]
+    fn lcd_correction_desaturates_primaries() {
+        assert_eq!(to_rgb888(0x001f, ColorCorrection::Lcd), [0xc9, 0x00, 0x2e]);
+        assert_eq!(to_rgb888(0x03e0, ColorCorrection::Lcd), [0x1f, 0xba, 0x1f])
+    }
+
+    #[test]
+    fn lcd_correction_maps_white_to_light_grey() {
+        assert_eq!(to_rgb888(0x7fff, ColorCorrection::Lcd), [0xf0, 0xf0, 0xf0])
+    }
+}
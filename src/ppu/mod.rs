@@ -0,0 +1,599 @@
+use crate::interrupt;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+use crate::Model;
+
+pub use self::color::{to_rgb888, ColorCorrection};
+
+mod color;
+mod render;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+pub const LCDC: u16 = 0xff40;
+pub const STAT: u16 = 0xff41;
+pub const SCY: u16 = 0xff42;
+pub const SCX: u16 = 0xff43;
+pub const LY: u16 = 0xff44;
+pub const LYC: u16 = 0xff45;
+pub const BGP: u16 = 0xff47;
+pub const OBP0: u16 = 0xff48;
+pub const OBP1: u16 = 0xff49;
+pub const WY: u16 = 0xff4a;
+pub const WX: u16 = 0xff4b;
+pub const VBK: u16 = 0xff4f;
+pub const BCPS: u16 = 0xff68;
+pub const BCPD: u16 = 0xff69;
+pub const OCPS: u16 = 0xff6a;
+pub const OCPD: u16 = 0xff6b;
+
+const VRAM_SIZE: usize = 0x2000;
+const OAM_SIZE: usize = 0xa0;
+const PALETTE_RAM_SIZE: usize = 0x40;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+
+const WHITE: u16 = 0x7fff;
+
+// What the CGB boot ROM picks for a DMG cartridge without a palette of its own.
+const COMPATIBILITY_PALETTE: [u16; 4] = [0x7fff, 0x1bef, 0x6180, 0x0000];
+
+pub struct Ppu {
+    cgb: bool,
+    compatibility: bool,
+    vram: Vec<u8>,
+    vbk: u8,
+    oam: [u8; OAM_SIZE],
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    bcps: u8,
+    ocps: u8,
+    bg_palettes: [u8; PALETTE_RAM_SIZE],
+    obj_palettes: [u8; PALETTE_RAM_SIZE],
+    dot: u16,
+    mode: Mode,
+    window_line: u8,
+    stat_line: bool,
+    framebuffer: Vec<u8>,
+    rgb555_framebuffer: Vec<u16>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    HBlank,
+    VBlank,
+    OamScan,
+    Drawing,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self {
+            cgb: false,
+            compatibility: false,
+            vram: vec![0x00; 2 * VRAM_SIZE],
+            vbk: 0,
+            oam: [0x00; OAM_SIZE],
+            lcdc: 0x00,
+            stat: 0x00,
+            scy: 0x00,
+            scx: 0x00,
+            ly: 0,
+            lyc: 0,
+            bgp: 0x00,
+            obp0: 0x00,
+            obp1: 0x00,
+            wy: 0x00,
+            wx: 0x00,
+            bcps: 0x00,
+            ocps: 0x00,
+            bg_palettes: [0x00; PALETTE_RAM_SIZE],
+            obj_palettes: [0x00; PALETTE_RAM_SIZE],
+            dot: 0,
+            mode: Mode::HBlank,
+            window_line: 0,
+            stat_line: false,
+            framebuffer: vec![0x00; SCREEN_WIDTH * SCREEN_HEIGHT],
+            rgb555_framebuffer: vec![WHITE; SCREEN_WIDTH * SCREEN_HEIGHT],
+        }
+    }
+}
+
+impl Ppu {
+    // A CGB runs cartridges without CGB support in DMG compatibility mode.
+    pub fn new(model: Model, cgb_cartridge: bool) -> Self {
+        Self {
+            cgb: model.is_cgb() && cgb_cartridge,
+            compatibility: model.is_cgb() && !cgb_cartridge,
+            ..Default::default()
+        }
+    }
+
+    pub fn load_compatibility_palettes(&mut self) {
+        let colors: Vec<u8> = COMPATIBILITY_PALETTE
+            .iter()
+            .flat_map(|color| color.to_le_bytes())
+            .collect();
+        self.bg_palettes[..8].copy_from_slice(&colors);
+        self.obj_palettes[..8].copy_from_slice(&colors);
+        self.obj_palettes[8..16].copy_from_slice(&colors)
+    }
+
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.framebuffer
+    }
+
+    pub fn rgb555_framebuffer(&self) -> &[u16] {
+        &self.rgb555_framebuffer
+    }
+
+    pub fn vram_bank(&self) -> u8 {
+        self.vbk
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn ly(&self) -> u8 {
+        self.ly
+    }
+
+    pub fn step(&mut self) -> u8 {
+        if !self.is_enabled() {
+            return 0x00;
+        }
+        let mut interrupts = 0x00;
+        self.dot += 4;
+        if self.ly < SCREEN_HEIGHT as u8 {
+            if self.dot == OAM_SCAN_DOTS {
+                self.mode = Mode::Drawing
+            } else if self.dot == OAM_SCAN_DOTS + DRAWING_DOTS {
+                self.render_line();
+                self.mode = Mode::HBlank
+            }
+        }
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.ly += 1;
+            if self.ly == SCREEN_HEIGHT as u8 {
+                self.mode = Mode::VBlank;
+                interrupts |= interrupt::VBLANK
+            } else if self.ly == LINES_PER_FRAME {
+                self.ly = 0;
+                self.window_line = 0;
+                self.mode = Mode::OamScan
+            } else if self.ly < SCREEN_HEIGHT as u8 {
+                self.mode = Mode::OamScan
+            }
+        }
+        let stat_line = self.stat_line();
+        if stat_line && !self.stat_line {
+            interrupts |= interrupt::STAT
+        }
+        self.stat_line = stat_line;
+        interrupts
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0x9fff if self.mode == Mode::Drawing => 0xff,
+            0x8000..=0x9fff => self.vram[self.vram_index(addr)],
+            0xfe00..=0xfe9f if self.is_oam_blocked() => 0xff,
+            0xfe00..=0xfe9f => self.oam[usize::from(addr - 0xfe00)],
+            LCDC => self.lcdc,
+            STAT => {
+                0x80 | self.stat | if self.ly == self.lyc { 0x04 } else { 0x00 } | self.mode.bits()
+            }
+            SCY => self.scy,
+            SCX => self.scx,
+            LY => self.ly,
+            LYC => self.lyc,
+            BGP => self.bgp,
+            OBP0 => self.obp0,
+            OBP1 => self.obp1,
+            WY => self.wy,
+            WX => self.wx,
+            VBK if self.cgb => 0xfe | self.vbk,
+            BCPS if self.cgb => 0x40 | self.bcps,
+            OCPS if self.cgb => 0x40 | self.ocps,
+            BCPD | OCPD if !self.cgb || self.mode == Mode::Drawing => 0xff,
+            BCPD => self.bg_palettes[usize::from(self.bcps & 0x3f)],
+            OCPD => self.obj_palettes[usize::from(self.ocps & 0x3f)],
+            _ => 0xff,
+        }
+    }
+
+    // Like `read`, but ignores blocking by the current mode.
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0x9fff => self.vram[self.vram_index(addr)],
+            0xfe00..=0xfe9f => self.oam[usize::from(addr - 0xfe00)],
+            BCPD if self.cgb => self.bg_palettes[usize::from(self.bcps & 0x3f)],
+            OCPD if self.cgb => self.obj_palettes[usize::from(self.ocps & 0x3f)],
+            _ => self.read(addr),
+        }
+    }
+
+    // Like `write`, but ignores mode blocking, doesn't auto-increment palette
+    // indices and doesn't switch the LCD on or off.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9fff => {
+                let index = self.vram_index(addr);
+                self.vram[index] = data
+            }
+            0xfe00..=0xfe9f => self.oam[usize::from(addr - 0xfe00)] = data,
+            LCDC => self.lcdc = data,
+            BCPD if self.cgb => self.bg_palettes[usize::from(self.bcps & 0x3f)] = data,
+            OCPD if self.cgb => self.obj_palettes[usize::from(self.ocps & 0x3f)] = data,
+            _ => self.write(addr, data),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9fff if self.mode == Mode::Drawing => (),
+            0x8000..=0x9fff => {
+                let index = self.vram_index(addr);
+                self.vram[index] = data
+            }
+            0xfe00..=0xfe9f if self.is_oam_blocked() => (),
+            0xfe00..=0xfe9f => self.oam[usize::from(addr - 0xfe00)] = data,
+            LCDC => self.write_lcdc(data),
+            STAT => self.stat = data & 0x78,
+            SCY => self.scy = data,
+            SCX => self.scx = data,
+            LYC => self.lyc = data,
+            BGP => self.bgp = data,
+            OBP0 => self.obp0 = data,
+            OBP1 => self.obp1 = data,
+            WY => self.wy = data,
+            WX => self.wx = data,
+            VBK if self.cgb => self.vbk = data & 0x01,
+            BCPS if self.cgb => self.bcps = data & 0xbf,
+            OCPS if self.cgb => self.ocps = data & 0xbf,
+            BCPD if self.cgb => {
+                if self.mode != Mode::Drawing {
+                    self.bg_palettes[usize::from(self.bcps & 0x3f)] = data
+                }
+                self.bcps = auto_increment(self.bcps)
+            }
+            OCPD if self.cgb => {
+                if self.mode != Mode::Drawing {
+                    self.obj_palettes[usize::from(self.ocps & 0x3f)] = data
+                }
+                self.ocps = auto_increment(self.ocps)
+            }
+            _ => (),
+        }
+    }
+
+    pub fn write_oam(&mut self, index: u8, data: u8) {
+        self.oam[usize::from(index)] = data
+    }
+
+    fn write_lcdc(&mut self, data: u8) {
+        let was_enabled = self.is_enabled();
+        self.lcdc = data;
+        if was_enabled && !self.is_enabled() {
+            self.ly = 0;
+            self.dot = 0;
+            self.mode = Mode::HBlank;
+            self.framebuffer.iter_mut().for_each(|pixel| *pixel = 0x00);
+            self.rgb555_framebuffer
+                .iter_mut()
+                .for_each(|pixel| *pixel = WHITE)
+        } else if !was_enabled && self.is_enabled() {
+            self.window_line = 0;
+            self.mode = Mode::OamScan
+        }
+    }
+
+    fn vram_index(&self, addr: u16) -> usize {
+        usize::from(self.vbk) * VRAM_SIZE + usize::from(addr - 0x8000)
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.lcdc & 0x80 != 0
+    }
+
+    fn is_oam_blocked(&self) -> bool {
+        self.mode == Mode::OamScan || self.mode == Mode::Drawing
+    }
+
+    fn stat_line(&self) -> bool {
+        self.stat & 0x40 != 0 && self.ly == self.lyc
+            || match self.mode {
+                Mode::HBlank => self.stat & 0x08 != 0,
+                Mode::VBlank => self.stat & 0x10 != 0,
+                Mode::OamScan => self.stat & 0x20 != 0,
+                Mode::Drawing => false,
+            }
+    }
+}
+
+fn auto_increment(index: u8) -> u8 {
+    if index & 0x80 != 0 {
+        0x80 | (index + 1) & 0x3f
+    } else {
+        index
+    }
+}
+
+impl Mode {
+    fn bits(self) -> u8 {
+        match self {
+            Mode::HBlank => 0b00,
+            Mode::VBlank => 0b01,
+            Mode::OamScan => 0b10,
+            Mode::Drawing => 0b11,
+        }
+    }
+}
+
+impl SaveState for Ppu {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.bytes(&self.vram);
+        writer.u8(self.vbk);
+        writer.bytes(&self.oam);
+        for &register in &[
+            self.lcdc, self.stat, self.scy, self.scx, self.ly, self.lyc, self.bgp, self.obp0,
+            self.obp1, self.wy, self.wx, self.bcps, self.ocps,
+        ] {
+            writer.u8(register)
+        }
+        writer.bytes(&self.bg_palettes);
+        writer.bytes(&self.obj_palettes);
+        writer.u16(self.dot);
+        writer.u8(self.mode as u8);
+        writer.u8(self.window_line);
+        writer.bool(self.stat_line);
+        writer.bytes(&self.framebuffer);
+        writer.u16s(&self.rgb555_framebuffer)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.fill(&mut self.vram)?;
+        self.vbk = reader.u8_below("VBK", 2)?;
+        reader.fill(&mut self.oam)?;
+        for register in &mut [
+            &mut self.lcdc,
+            &mut self.stat,
+            &mut self.scy,
+            &mut self.scx,
+            &mut self.ly,
+            &mut self.lyc,
+            &mut self.bgp,
+            &mut self.obp0,
+            &mut self.obp1,
+            &mut self.wy,
+            &mut self.wx,
+            &mut self.bcps,
+            &mut self.ocps,
+        ] {
+            **register = reader.u8()?
+        }
+        if self.ly >= LINES_PER_FRAME {
+            return Err(StateError::InvalidValue("LY", self.ly.into()));
+        }
+        reader.fill(&mut self.bg_palettes)?;
+        reader.fill(&mut self.obj_palettes)?;
+        self.dot = match reader.u16()? {
+            dot if dot % 4 == 0 && dot < DOTS_PER_LINE => dot,
+            dot => return Err(StateError::InvalidValue("PPU dot", dot)),
+        };
+        self.mode = match reader.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamScan,
+            3 => Mode::Drawing,
+            code => return Err(StateError::InvalidValue("PPU mode", code.into())),
+        };
+        self.window_line = reader.u8()?;
+        self.stat_line = reader.bool()?;
+        reader.fill(&mut self.framebuffer)?;
+        reader.fill_u16s(&mut self.rgb555_framebuffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Ppu {
+        fn enabled() -> Self {
+            let mut ppu = Ppu::default();
+            ppu.write(LCDC, 0x91);
+            ppu
+        }
+
+        fn run(&mut self, m_cycles: usize) -> u8 {
+            (0..m_cycles).fold(0x00, |interrupts, _| interrupts | self.step())
+        }
+
+        fn run_lines(&mut self, lines: usize) -> u8 {
+            self.run(lines * usize::from(DOTS_PER_LINE) / 4)
+        }
+    }
+
+    #[test]
+    fn disabled_lcd_does_not_advance() {
+        let mut ppu = Ppu::default();
+        ppu.run_lines(10);
+        assert_eq!(ppu.read(LY), 0);
+        assert_eq!(ppu.read(STAT) & 0x03, 0b00)
+    }
+
+    #[test]
+    fn line_goes_through_oam_scan_drawing_and_hblank() {
+        let mut ppu = Ppu::enabled();
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        ppu.run(usize::from(OAM_SCAN_DOTS) / 4);
+        assert_eq!(ppu.mode(), Mode::Drawing);
+        ppu.run(usize::from(DRAWING_DOTS) / 4);
+        assert_eq!(ppu.mode(), Mode::HBlank);
+        ppu.run(usize::from(DOTS_PER_LINE - OAM_SCAN_DOTS - DRAWING_DOTS) / 4);
+        assert_eq!(ppu.mode(), Mode::OamScan);
+        assert_eq!(ppu.read(LY), 1)
+    }
+
+    #[test]
+    fn vblank_interrupt_is_requested_at_line_144() {
+        let mut ppu = Ppu::enabled();
+        assert_eq!(ppu.run_lines(143) & interrupt::VBLANK, 0x00);
+        assert_eq!(ppu.run_lines(1) & interrupt::VBLANK, interrupt::VBLANK);
+        assert_eq!(ppu.mode(), Mode::VBlank)
+    }
+
+    #[test]
+    fn ly_wraps_after_154_lines() {
+        let mut ppu = Ppu::enabled();
+        ppu.run_lines(154);
+        assert_eq!(ppu.read(LY), 0);
+        assert_eq!(ppu.mode(), Mode::OamScan)
+    }
+
+    #[test]
+    fn lyc_match_requests_stat_interrupt() {
+        let mut ppu = Ppu::enabled();
+        ppu.write(LYC, 5);
+        ppu.write(STAT, 0x40);
+        assert_eq!(ppu.run_lines(4) & interrupt::STAT, 0x00);
+        assert_eq!(ppu.run_lines(1) & interrupt::STAT, interrupt::STAT);
+        assert_eq!(ppu.read(STAT) & 0x04, 0x04)
+    }
+
+    #[test]
+    fn hblank_stat_interrupt_is_requested_once_per_line() {
+        let mut ppu = Ppu::enabled();
+        ppu.write(STAT, 0x08);
+        let requests = (0..10)
+            .filter(|_| ppu.run_lines(1) & interrupt::STAT != 0)
+            .count();
+        assert_eq!(requests, 10)
+    }
+
+    #[test]
+    fn vram_is_inaccessible_while_drawing() {
+        let mut ppu = Ppu::enabled();
+        ppu.write(0x8000, 0x42);
+        ppu.run(usize::from(OAM_SCAN_DOTS) / 4);
+        assert_eq!(ppu.read(0x8000), 0xff);
+        ppu.write(0x8000, 0x00);
+        ppu.run(usize::from(DRAWING_DOTS) / 4);
+        assert_eq!(ppu.read(0x8000), 0x42)
+    }
+
+    #[test]
+    fn oam_is_inaccessible_during_oam_scan() {
+        let mut ppu = Ppu::enabled();
+        ppu.write_oam(0, 0x42);
+        assert_eq!(ppu.read(0xfe00), 0xff);
+        ppu.run(usize::from(OAM_SCAN_DOTS + DRAWING_DOTS) / 4);
+        assert_eq!(ppu.read(0xfe00), 0x42)
+    }
+
+    #[test]
+    fn vram_bank_1_is_selected_by_vbk_on_cgb() {
+        let mut ppu = Ppu::new(Model::Cgb, true);
+        ppu.write(0x8000, 0x11);
+        ppu.write(VBK, 0x01);
+        assert_eq!(ppu.read(0x8000), 0x00);
+        ppu.write(0x8000, 0x22);
+        assert_eq!(ppu.read(VBK), 0xff);
+        ppu.write(VBK, 0x00);
+        assert_eq!(ppu.read(0x8000), 0x11);
+        assert_eq!(ppu.read(VBK), 0xfe)
+    }
+
+    #[test]
+    fn vbk_is_ignored_on_dmg() {
+        let mut ppu = Ppu::default();
+        ppu.write(0x8000, 0x11);
+        ppu.write(VBK, 0x01);
+        assert_eq!(ppu.read(0x8000), 0x11);
+        assert_eq!(ppu.read(VBK), 0xff)
+    }
+
+    #[test]
+    fn loading_out_of_range_vbk_fails() {
+        let mut writer = StateWriter::default();
+        Ppu::default().save(&mut writer);
+        let mut state = writer.into_bytes();
+        state[2 * VRAM_SIZE] = 0x02;
+        assert_eq!(
+            Ppu::default().load(&mut StateReader::new(&state)),
+            Err(StateError::InvalidValue("VBK", 0x02))
+        )
+    }
+
+    #[test]
+    fn loading_out_of_range_ly_or_dot_fails() {
+        let mut writer = StateWriter::default();
+        Ppu::default().save(&mut writer);
+        let state = writer.into_bytes();
+        let ly = 2 * VRAM_SIZE + 1 + OAM_SIZE + 4;
+        let dot = ly + 9 + 2 * PALETTE_RAM_SIZE;
+        for &(offset, patch, field, value) in &[
+            (ly, &[154][..], "LY", 154),
+            (dot, &[0x02, 0x00][..], "PPU dot", 2),
+            (dot, &[0xc8, 0x01][..], "PPU dot", 456),
+        ] {
+            let mut state = state.clone();
+            state[offset..offset + patch.len()].copy_from_slice(patch);
+            assert_eq!(
+                Ppu::default().load(&mut StateReader::new(&state)),
+                Err(StateError::InvalidValue(field, value))
+            )
+        }
+    }
+
+    #[test]
+    fn palette_data_auto_increments() {
+        let mut ppu = Ppu::new(Model::Cgb, true);
+        ppu.write(BCPS, 0xbe);
+        ppu.write(BCPD, 0x12);
+        ppu.write(BCPD, 0x34);
+        assert_eq!(ppu.read(BCPS), 0xc0);
+        ppu.write(BCPS, 0x3e);
+        assert_eq!(ppu.read(BCPD), 0x12);
+        ppu.write(BCPS, 0x3f);
+        assert_eq!(ppu.read(BCPD), 0x34);
+        assert_eq!(ppu.read(OCPD), 0x00)
+    }
+
+    #[test]
+    fn palette_data_is_inaccessible_while_drawing() {
+        let mut ppu = Ppu::new(Model::Cgb, true);
+        ppu.write(LCDC, 0x91);
+        ppu.write(OCPS, 0x80);
+        ppu.write(OCPD, 0x11);
+        ppu.run(usize::from(OAM_SCAN_DOTS) / 4);
+        ppu.write(OCPD, 0x22);
+        assert_eq!(ppu.read(OCPS), 0xc2);
+        assert_eq!(ppu.read(OCPD), 0xff);
+        ppu.run(usize::from(DRAWING_DOTS) / 4);
+        assert_eq!(ppu.read(OCPD), 0x00);
+        ppu.write(OCPS, 0x00);
+        assert_eq!(ppu.read(OCPD), 0x11)
+    }
+
+    #[test]
+    fn unused_stat_bit_reads_as_set() {
+        let ppu = Ppu::default();
+        assert_eq!(ppu.read(STAT), 0x84)
+    }
+}
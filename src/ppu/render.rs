@@ -0,0 +1,475 @@
+use super::*;
+
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+const DMG_GREYS: [u16; 4] = [0x7fff, 0x56b5, 0x294a, 0x0000];
+
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attributes: u8,
+}
+
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    color: u8,
+    palette: u8,
+    priority: bool,
+}
+
+impl Ppu {
+    pub(super) fn render_line(&mut self) {
+        let ly = self.ly;
+        let window_visible = self.lcdc & 0x20 != 0 && self.wy <= ly && self.wx <= 166;
+        let mut bg = [BgPixel::default(); SCREEN_WIDTH];
+        if self.cgb || self.lcdc & 0x01 != 0 {
+            for (x, pixel) in bg.iter_mut().enumerate() {
+                let x = x as u8;
+                *pixel = if window_visible && x + 7 >= self.wx {
+                    self.tile_map_pixel(0x40, x + 7 - self.wx, self.window_line)
+                } else {
+                    self.tile_map_pixel(0x08, self.scx.wrapping_add(x), self.scy.wrapping_add(ly))
+                }
+            }
+        }
+        if window_visible {
+            self.window_line += 1
+        }
+        let sprites = self.sprites_on_line();
+        let mut shades = [0u8; SCREEN_WIDTH];
+        let mut colors = [0u16; SCREEN_WIDTH];
+        for (x, bg) in bg.iter().enumerate() {
+            let sprite_pixel = sprites
+                .iter()
+                .find_map(|sprite| {
+                    let column = (x as u8 + 8).wrapping_sub(sprite.x);
+                    if column < 8 {
+                        Some((sprite, self.sprite_pixel(sprite, column)))
+                            .filter(|&(_, color)| color != 0)
+                    } else {
+                        None
+                    }
+                })
+                .filter(|(sprite, _)| !self.bg_has_priority(bg, sprite));
+            if self.cgb {
+                colors[x] = match sprite_pixel {
+                    Some((sprite, color)) => {
+                        palette_color(&self.obj_palettes, sprite.attributes & 0x07, color)
+                    }
+                    None => palette_color(&self.bg_palettes, bg.palette, bg.color),
+                }
+            } else {
+                let (grey, obj_palette) = match sprite_pixel {
+                    Some((sprite, color)) if sprite.attributes & 0x10 != 0 => {
+                        (shade(self.obp1, color), Some(1))
+                    }
+                    Some((_, color)) => (shade(self.obp0, color), Some(0)),
+                    None => (shade(self.bgp, bg.color), None),
+                };
+                shades[x] = grey;
+                colors[x] = match obj_palette {
+                    _ if !self.compatibility => DMG_GREYS[usize::from(grey)],
+                    Some(palette) => palette_color(&self.obj_palettes, palette, grey),
+                    None => palette_color(&self.bg_palettes, 0, grey),
+                }
+            }
+        }
+        let offset = usize::from(ly) * SCREEN_WIDTH;
+        self.framebuffer[offset..][..SCREEN_WIDTH].copy_from_slice(&shades);
+        self.rgb555_framebuffer[offset..][..SCREEN_WIDTH].copy_from_slice(&colors)
+    }
+
+    fn bg_has_priority(&self, bg: &BgPixel, sprite: &Sprite) -> bool {
+        if self.cgb && self.lcdc & 0x01 == 0 {
+            return false;
+        }
+        bg.color != 0 && (sprite.attributes & 0x80 != 0 || bg.priority)
+    }
+
+    fn tile_map_pixel(&self, map_select: u8, x: u8, y: u8) -> BgPixel {
+        let map = if self.lcdc & map_select != 0 {
+            0x1c00
+        } else {
+            0x1800
+        };
+        let index = map + usize::from(y / 8) * 32 + usize::from(x / 8);
+        let tile = self.vram[index];
+        let attributes = if self.cgb {
+            self.vram[VRAM_SIZE + index]
+        } else {
+            0x00
+        };
+        let mut tile_addr = if self.lcdc & 0x10 != 0 {
+            usize::from(tile) * 16
+        } else {
+            (0x1000 + isize::from(tile as i8) * 16) as usize
+        };
+        if attributes & 0x08 != 0 {
+            tile_addr += VRAM_SIZE
+        }
+        let column = if attributes & 0x20 != 0 {
+            7 - x % 8
+        } else {
+            x % 8
+        };
+        let row = if attributes & 0x40 != 0 {
+            7 - y % 8
+        } else {
+            y % 8
+        };
+        BgPixel {
+            color: self.tile_pixel(tile_addr, column, row),
+            palette: attributes & 0x07,
+            priority: attributes & 0x80 != 0,
+        }
+    }
+
+    fn tile_pixel(&self, tile_addr: usize, column: u8, row: u8) -> u8 {
+        let addr = tile_addr + 2 * usize::from(row);
+        let (low, high) = (self.vram[addr], self.vram[addr + 1]);
+        let bit = 7 - column;
+        (high >> bit & 0x01) << 1 | low >> bit & 0x01
+    }
+
+    fn sprite_pixel(&self, sprite: &Sprite, column: u8) -> u8 {
+        let height = self.sprite_height();
+        let mut row = self.ly + 16 - sprite.y;
+        if sprite.attributes & 0x40 != 0 {
+            row = height - 1 - row
+        }
+        let column = if sprite.attributes & 0x20 != 0 {
+            7 - column
+        } else {
+            column
+        };
+        let tile = if height == 16 {
+            sprite.tile & 0xfe
+        } else {
+            sprite.tile
+        };
+        let bank = if self.cgb && sprite.attributes & 0x08 != 0 {
+            VRAM_SIZE
+        } else {
+            0
+        };
+        self.tile_pixel(bank + usize::from(tile) * 16, column, row)
+    }
+
+    fn sprites_on_line(&self) -> Vec<Sprite> {
+        if self.lcdc & 0x02 == 0 {
+            return Vec::new();
+        }
+        let height = self.sprite_height();
+        let line = self.ly + 16;
+        let mut sprites: Vec<_> = self
+            .oam
+            .chunks_exact(4)
+            .map(|entry| Sprite {
+                y: entry[0],
+                x: entry[1],
+                tile: entry[2],
+                attributes: entry[3],
+            })
+            .filter(|sprite| {
+                sprite.y <= line && u16::from(line) < u16::from(sprite.y) + u16::from(height)
+            })
+            .take(MAX_SPRITES_PER_LINE)
+            .collect();
+        if !self.cgb {
+            sprites.sort_by_key(|sprite| sprite.x)
+        }
+        sprites
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & 0x04 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+}
+
+fn shade(palette: u8, color: u8) -> u8 {
+    palette >> (2 * color) & 0x03
+}
+
+fn palette_color(palettes: &[u8; PALETTE_RAM_SIZE], palette: u8, color: u8) -> u16 {
+    let index = 8 * usize::from(palette) + 2 * usize::from(color);
+    u16::from_le_bytes([palettes[index], palettes[index + 1]]) & 0x7fff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOLID_TILE: [u8; 16] = [0xff; 16];
+    const STRIPED_TILE: [u8; 16] = [
+        0xaa, 0x00, 0xaa, 0x00, 0xaa, 0x00, 0xaa, 0x00, 0xaa, 0x00, 0xaa, 0x00, 0xaa, 0x00, 0xaa,
+        0x00,
+    ];
+
+    impl Ppu {
+        fn load_tile(&mut self, tile_addr: usize, tile: &[u8; 16]) {
+            self.vram[tile_addr..tile_addr + 16].copy_from_slice(tile)
+        }
+
+        fn render_frame(&mut self) {
+            self.write(LCDC, self.lcdc | 0x80);
+            while self.step() & interrupt::VBLANK == 0 {}
+        }
+
+        fn pixel(&self, x: usize, y: usize) -> u8 {
+            self.framebuffer[y * SCREEN_WIDTH + x]
+        }
+
+        fn color(&self, x: usize, y: usize) -> u16 {
+            self.rgb555_framebuffer[y * SCREEN_WIDTH + x]
+        }
+
+        fn load_palette(&mut self, index: u16, palette: u8, colors: &[u16; 4]) {
+            self.write(index, 0x80 | (8 * palette));
+            for color in colors {
+                self.write(index + 1, *color as u8);
+                self.write(index + 1, (*color >> 8) as u8)
+            }
+        }
+    }
+
+    fn ppu_with_palettes(lcdc: u8) -> Ppu {
+        Ppu {
+            lcdc,
+            bgp: 0xe4,
+            obp0: 0xe4,
+            obp1: 0x1b,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn background_uses_unsigned_tile_data() {
+        let mut ppu = ppu_with_palettes(0x11);
+        ppu.load_tile(16, &SOLID_TILE);
+        ppu.vram[0x1800] = 0x01;
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(0, 0), 3);
+        assert_eq!(ppu.pixel(7, 7), 3);
+        assert_eq!(ppu.pixel(8, 0), 0)
+    }
+
+    #[test]
+    fn background_uses_signed_tile_data() {
+        let mut ppu = ppu_with_palettes(0x01);
+        ppu.load_tile(0x1000 - 16, &SOLID_TILE);
+        ppu.vram[0x1800] = 0xff;
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(0, 0), 3);
+        assert_eq!(ppu.pixel(8, 0), 0)
+    }
+
+    #[test]
+    fn background_is_scrolled() {
+        let mut ppu = ppu_with_palettes(0x11);
+        ppu.load_tile(16, &SOLID_TILE);
+        ppu.vram[0x1800] = 0x01;
+        ppu.scx = 4;
+        ppu.scy = 2;
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(3, 5), 3);
+        assert_eq!(ppu.pixel(4, 5), 0);
+        assert_eq!(ppu.pixel(3, 6), 0)
+    }
+
+    #[test]
+    fn background_palette_maps_colors() {
+        let mut ppu = ppu_with_palettes(0x11);
+        ppu.load_tile(0, &STRIPED_TILE);
+        ppu.bgp = 0x0c;
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(0, 0), 3);
+        assert_eq!(ppu.pixel(1, 0), 0)
+    }
+
+    #[test]
+    fn window_covers_background() {
+        let mut ppu = ppu_with_palettes(0x71);
+        ppu.load_tile(16, &SOLID_TILE);
+        ppu.vram[0x1c00..0x2000]
+            .iter_mut()
+            .for_each(|tile| *tile = 0x01);
+        ppu.wx = 7 + 80;
+        ppu.wy = 100;
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(79, 100), 0);
+        assert_eq!(ppu.pixel(80, 100), 3);
+        assert_eq!(ppu.pixel(80, 99), 0)
+    }
+
+    #[test]
+    fn sprite_is_drawn_over_background() {
+        let mut ppu = ppu_with_palettes(0x13);
+        ppu.load_tile(32, &SOLID_TILE);
+        ppu.oam[..4].copy_from_slice(&[16 + 10, 8 + 20, 0x02, 0x00]);
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(20, 10), 3);
+        assert_eq!(ppu.pixel(27, 17), 3);
+        assert_eq!(ppu.pixel(28, 10), 0);
+        assert_eq!(ppu.pixel(20, 18), 0)
+    }
+
+    #[test]
+    fn sprite_uses_selected_palette() {
+        let mut ppu = ppu_with_palettes(0x13);
+        ppu.load_tile(32, &SOLID_TILE);
+        ppu.oam[..4].copy_from_slice(&[16, 8, 0x02, 0x10]);
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(0, 0), 0)
+    }
+
+    #[test]
+    fn sprite_behind_background_shows_only_over_color_0() {
+        let mut ppu = ppu_with_palettes(0x13);
+        ppu.load_tile(0, &STRIPED_TILE);
+        ppu.load_tile(32, &SOLID_TILE);
+        ppu.oam[..4].copy_from_slice(&[16, 8, 0x02, 0x80]);
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(0, 0), 1);
+        assert_eq!(ppu.pixel(1, 0), 3)
+    }
+
+    #[test]
+    fn sprite_is_flipped_horizontally() {
+        let mut ppu = ppu_with_palettes(0x13);
+        ppu.load_tile(32, &STRIPED_TILE);
+        ppu.oam[..4].copy_from_slice(&[16, 8, 0x02, 0x20]);
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(0, 0), 0);
+        assert_eq!(ppu.pixel(1, 0), 1)
+    }
+
+    #[test]
+    fn at_most_10_sprites_are_drawn_per_line() {
+        let mut ppu = ppu_with_palettes(0x13);
+        ppu.load_tile(32, &SOLID_TILE);
+        for i in 0..11 {
+            ppu.oam[4 * i..4 * i + 4].copy_from_slice(&[16, 8 + 8 * i as u8, 0x02, 0x00]);
+        }
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(9 * 8, 0), 3);
+        assert_eq!(ppu.pixel(10 * 8, 0), 0)
+    }
+
+    #[test]
+    fn tall_sprites_span_two_tiles() {
+        let mut ppu = ppu_with_palettes(0x17);
+        ppu.load_tile(48, &SOLID_TILE);
+        ppu.oam[..4].copy_from_slice(&[16, 8, 0x03, 0x00]);
+        ppu.render_frame();
+        assert_eq!(ppu.pixel(0, 7), 0);
+        assert_eq!(ppu.pixel(0, 8), 3)
+    }
+
+    const CGB_COLORS: [u16; 4] = [0x7fff, 0x001f, 0x03e0, 0x7c00];
+
+    fn cgb_ppu(lcdc: u8) -> Ppu {
+        let mut ppu = Ppu::new(Model::Cgb, true);
+        ppu.lcdc = lcdc;
+        ppu.load_palette(BCPS, 0, &[0x0000; 4]);
+        ppu.load_palette(BCPS, 3, &CGB_COLORS);
+        ppu.load_palette(OCPS, 5, &CGB_COLORS);
+        ppu
+    }
+
+    #[test]
+    fn dmg_shades_are_output_as_greys() {
+        let mut ppu = ppu_with_palettes(0x11);
+        ppu.load_tile(0, &STRIPED_TILE);
+        ppu.render_frame();
+        assert_eq!(ppu.color(0, 0), 0x56b5);
+        assert_eq!(ppu.color(1, 0), 0x7fff)
+    }
+
+    #[test]
+    fn dmg_cartridge_on_cgb_uses_compatibility_palettes() {
+        let mut ppu = Ppu::new(Model::Cgb, false);
+        ppu.load_compatibility_palettes();
+        ppu.lcdc = 0x13;
+        ppu.bgp = 0xe4;
+        ppu.obp1 = 0x1b;
+        ppu.load_tile(0, &STRIPED_TILE);
+        ppu.load_tile(32, &SOLID_TILE);
+        ppu.vram[VRAM_SIZE + 0x1800] = 0x03;
+        ppu.oam[..4].copy_from_slice(&[16, 8 + 8, 0x02, 0x10]);
+        ppu.render_frame();
+        assert_eq!(ppu.color(0, 0), 0x1bef);
+        assert_eq!(ppu.color(1, 0), 0x7fff);
+        assert_eq!(ppu.color(8, 0), 0x7fff);
+        assert_eq!(ppu.pixel(8, 0), 0)
+    }
+
+    #[test]
+    fn cgb_background_uses_attribute_palette() {
+        let mut ppu = cgb_ppu(0x11);
+        ppu.load_tile(0, &SOLID_TILE);
+        ppu.vram[VRAM_SIZE + 0x1800] = 0x03;
+        ppu.render_frame();
+        assert_eq!(ppu.color(0, 0), 0x7c00);
+        assert_eq!(ppu.color(8, 0), 0x0000)
+    }
+
+    #[test]
+    fn cgb_background_tile_can_come_from_bank_1() {
+        let mut ppu = cgb_ppu(0x11);
+        ppu.load_tile(VRAM_SIZE, &SOLID_TILE);
+        ppu.vram[VRAM_SIZE + 0x1800] = 0x0b;
+        ppu.render_frame();
+        assert_eq!(ppu.color(0, 0), 0x7c00);
+        assert_eq!(ppu.color(8, 0), 0x0000)
+    }
+
+    #[test]
+    fn cgb_background_tile_is_flipped() {
+        let mut ppu = cgb_ppu(0x11);
+        ppu.load_tile(0, &STRIPED_TILE);
+        ppu.vram[VRAM_SIZE + 0x1800] = 0x23;
+        ppu.render_frame();
+        assert_eq!(ppu.color(0, 0), 0x7fff);
+        assert_eq!(ppu.color(1, 0), 0x001f)
+    }
+
+    #[test]
+    fn cgb_background_priority_hides_sprite() {
+        let mut ppu = cgb_ppu(0x13);
+        ppu.load_tile(0, &STRIPED_TILE);
+        ppu.load_tile(32, &SOLID_TILE);
+        ppu.vram[VRAM_SIZE + 0x1800] = 0x83;
+        ppu.oam[..4].copy_from_slice(&[16, 8, 0x02, 0x05]);
+        ppu.render_frame();
+        assert_eq!(ppu.color(0, 0), 0x001f);
+        assert_eq!(ppu.color(1, 0), 0x7c00)
+    }
+
+    #[test]
+    fn cgb_lcdc_bit_0_gives_sprites_master_priority() {
+        let mut ppu = cgb_ppu(0x12);
+        ppu.load_tile(0, &SOLID_TILE);
+        ppu.load_tile(32, &SOLID_TILE);
+        ppu.vram[VRAM_SIZE + 0x1800] = 0x83;
+        ppu.oam[..4].copy_from_slice(&[16, 8, 0x02, 0x85]);
+        ppu.render_frame();
+        assert_eq!(ppu.color(0, 0), 0x7c00);
+        assert_eq!(ppu.color(8, 0), 0x0000)
+    }
+
+    #[test]
+    fn cgb_sprite_priority_follows_oam_order() {
+        let mut ppu = cgb_ppu(0x13);
+        ppu.load_tile(32, &SOLID_TILE);
+        ppu.load_palette(OCPS, 1, &[0x0000, 0x0000, 0x0000, 0x1234]);
+        ppu.oam[..8].copy_from_slice(&[16, 12, 0x02, 0x05, 16, 8, 0x02, 0x01]);
+        ppu.render_frame();
+        assert_eq!(ppu.color(0, 0), 0x1234);
+        assert_eq!(ppu.color(4, 0), 0x7c00)
+    }
+}
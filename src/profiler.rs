@@ -0,0 +1,262 @@
+use crate::cpu::{Activity, Cpu};
+use crate::memory::MemoryMap;
+use crate::symbols::{Location, Symbols};
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+const CALL: u8 = 0xcd;
+const RET: u8 = 0xc9;
+const RETI: u8 = 0xd9;
+
+// Attributes CPU M-cycles to the instruction executing during them and to the
+// call stack it runs in. Calls are followed through CALL, RST and interrupt
+// dispatch, returns through RET and RETI.
+#[derive(Default)]
+pub struct Profiler {
+    cycles: HashMap<Location, u64>,
+    nodes: Vec<Node>,
+    children: HashMap<(usize, Location), usize>,
+    stack: Vec<Frame>,
+    current: Option<Instruction>,
+    dispatched: bool,
+}
+
+struct Node {
+    parent: Option<usize>,
+    function: Location,
+    cycles: u64,
+}
+
+struct Frame {
+    node: usize,
+    sp: u16,
+}
+
+struct Instruction {
+    location: Location,
+    opcode: u8,
+    sp: u16,
+    cycles: u64,
+}
+
+impl Profiler {
+    // Call once per CPU M-cycle; `at_boundary` tells whether the CPU fetched
+    // a new instruction during it.
+    pub fn record_cycle(&mut self, cpu: &Cpu, memory: &MemoryMap, at_boundary: bool) {
+        let status = cpu.status();
+        if status.activity == Activity::Dispatching {
+            self.dispatched = true
+        }
+        if let Some(current) = &mut self.current {
+            current.cycles += 1
+        }
+        if !at_boundary {
+            return;
+        }
+        let addr = cpu.data.pc.wrapping_sub(1);
+        let next = Instruction {
+            location: Location {
+                bank: memory.bank(addr),
+                addr,
+            },
+            opcode: status.opcode.unwrap(),
+            sp: cpu.data.sp,
+            cycles: 0,
+        };
+        self.retire(&next);
+        self.dispatched = false;
+        self.current = Some(next)
+    }
+
+    pub fn cycles(&self, location: Location) -> u64 {
+        self.cycles.get(&location).copied().unwrap_or(0)
+    }
+
+    // Per-instruction cycle counts, hottest first.
+    pub fn hot_spots(&self) -> Vec<(Location, u64)> {
+        let mut hot_spots: Vec<_> = self
+            .cycles
+            .iter()
+            .map(|(&location, &cycles)| (location, cycles))
+            .collect();
+        hot_spots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot_spots
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.cycles.values().sum()
+    }
+
+    // Writes one `caller;callee cycles` line per call stack, the input format
+    // of flamegraph.pl and inferno. Functions are named after their entry
+    // point, symbolized when possible.
+    pub fn write_folded(&self, writer: &mut impl Write, symbols: &Symbols) -> io::Result<()> {
+        let mut lines: Vec<_> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.cycles != 0)
+            .map(|(index, node)| (self.path(index, symbols), node.cycles))
+            .collect();
+        lines.sort();
+        for (path, cycles) in lines {
+            writeln!(writer, "{} {}", path, cycles)?
+        }
+        Ok(())
+    }
+
+    fn retire(&mut self, next: &Instruction) {
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => {
+                let node = self.node(None, next.location);
+                self.stack.push(Frame { node, sp: u16::MAX });
+                return;
+            }
+        };
+        *self.cycles.entry(current.location).or_insert(0) += current.cycles;
+        let parent = self.stack.last().unwrap().node;
+        self.nodes[parent].cycles += current.cycles;
+        let called = matches!(current.opcode, CALL | 0xc4 | 0xcc | 0xd4 | 0xdc)
+            || current.opcode & 0xc7 == 0xc7;
+        let returned = matches!(current.opcode, RET | RETI | 0xc0 | 0xc8 | 0xd0 | 0xd8);
+        if self.dispatched || called && next.sp == current.sp.wrapping_sub(2) {
+            let node = self.node(Some(parent), next.location);
+            self.stack.push(Frame { node, sp: next.sp })
+        } else if returned && next.sp == current.sp.wrapping_add(2) {
+            while self.stack.len() > 1 && self.stack.last().unwrap().sp < next.sp {
+                self.stack.pop();
+            }
+        }
+    }
+
+    fn node(&mut self, parent: Option<usize>, function: Location) -> usize {
+        let key = (parent.unwrap_or(usize::MAX), function);
+        if let Some(&index) = self.children.get(&key) {
+            return index;
+        }
+        self.nodes.push(Node {
+            parent,
+            function,
+            cycles: 0,
+        });
+        self.children.insert(key, self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    fn path(&self, mut index: usize, symbols: &Symbols) -> String {
+        let mut names = Vec::new();
+        loop {
+            let node = &self.nodes[index];
+            names.push(symbols.symbolize(node.function).unwrap_or_else(|| {
+                format!("{:02X}:{:04X}", node.function.bank, node.function.addr)
+            }));
+            match node.parent {
+                Some(parent) => index = parent,
+                None => break,
+            }
+        }
+        names.reverse();
+        names.join(";")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cartridge::Cartridge;
+    use crate::memory::{IE, IF};
+    use crate::system::tests::rom_with_source;
+    use crate::GameBoy;
+
+    const PROGRAM: &str = "
+            ld sp, $d000
+        loop:
+            call outer
+            jr loop
+        outer:
+            call inner
+            call inner
+            ret
+        inner:
+            ld [$c000], sp
+            ret
+    ";
+
+    const SYMBOLS: &str = "00:0100 entry\n00:0158 outer\n00:015f inner\n00:0040 vblank\n";
+
+    fn game_boy() -> GameBoy {
+        GameBoy::new(Cartridge::new(rom_with_source(PROGRAM)).unwrap())
+    }
+
+    fn profile(mut game_boy: GameBoy, steps: usize) -> Profiler {
+        game_boy.start_profiling();
+        for _ in 0..steps {
+            game_boy.step();
+        }
+        game_boy.finish_profiling().unwrap()
+    }
+
+    fn folded(profiler: &Profiler) -> Vec<String> {
+        let mut output = Vec::new();
+        let symbols = Symbols::from_sym(SYMBOLS).unwrap();
+        profiler.write_folded(&mut output, &symbols).unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    fn stacks(lines: &[String]) -> Vec<&str> {
+        lines
+            .iter()
+            .map(|line| line.rsplit_once(' ').unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn cycles_are_attributed_to_instructions() {
+        let profiler = profile(game_boy(), 1000);
+        let location = |addr| Location { bank: 0, addr };
+        let (hottest, cycles) = profiler.hot_spots()[0];
+        assert_eq!(hottest, location(0x015f));
+        assert_eq!(cycles, profiler.cycles(location(0x015f)));
+        assert!(profiler.total_cycles() > 990);
+        assert_eq!(profiler.cycles(location(0x0200)), 0)
+    }
+
+    #[test]
+    fn folded_stacks_follow_calls_and_returns() {
+        let profiler = profile(game_boy(), 1000);
+        let lines = folded(&profiler);
+        assert_eq!(
+            stacks(&lines),
+            ["entry", "entry;outer", "entry;outer;inner"]
+        );
+        let total: u64 = lines
+            .iter()
+            .map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap())
+            .sum();
+        assert_eq!(total, profiler.total_cycles())
+    }
+
+    #[test]
+    fn interrupt_dispatch_enters_handler_frame() {
+        let mut rom = rom_with_source(PROGRAM);
+        rom[0x0040] = 0xc9; // RET
+        let mut game_boy = GameBoy::new(Cartridge::new(rom).unwrap());
+        game_boy.poke(IF, 0x00);
+        game_boy.poke(IE, 0x01);
+        game_boy.cpu_mut().data.ime = true;
+        let profiler = profile(game_boy, 2 * 17556);
+        let lines = folded(&profiler);
+        let stacks = stacks(&lines);
+        assert!(stacks.iter().any(|stack| stack.ends_with(";vblank")));
+        assert!(stacks
+            .iter()
+            .all(|stack| !stack.contains("vblank;") && stack.starts_with("entry")))
+    }
+}
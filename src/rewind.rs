@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+
+const KEYFRAME_INTERVAL: usize = 60;
+
+pub struct RewindBuffer {
+    budget: usize,
+    used: usize,
+    groups: VecDeque<Group>,
+}
+
+struct Group {
+    keyframe: Vec<u8>,
+    deltas: Vec<Vec<u8>>,
+}
+
+impl Group {
+    fn size(&self) -> usize {
+        self.keyframe.len() + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+impl RewindBuffer {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            used: 0,
+            groups: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.groups.iter().map(|group| 1 + group.deltas.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn push(&mut self, state: &[u8]) {
+        match self.groups.back_mut() {
+            Some(group)
+                if group.deltas.len() + 1 < KEYFRAME_INTERVAL
+                    && group.keyframe.len() == state.len() =>
+            {
+                let delta = encode_delta(&group.keyframe, state);
+                self.used += delta.len();
+                group.deltas.push(delta)
+            }
+            _ => {
+                self.used += state.len();
+                self.groups.push_back(Group {
+                    keyframe: state.to_vec(),
+                    deltas: Vec::new(),
+                })
+            }
+        }
+        while self.used > self.budget && self.groups.len() > 1 {
+            let group = self.groups.pop_front().unwrap();
+            self.used -= group.size()
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let group = self.groups.back_mut()?;
+        let state = match group.deltas.pop() {
+            Some(delta) => {
+                self.used -= delta.len();
+                decode_delta(&group.keyframe, &delta)
+            }
+            None => {
+                let group = self.groups.pop_back().unwrap();
+                self.used -= group.keyframe.len();
+                group.keyframe
+            }
+        };
+        Some(state)
+    }
+
+    pub fn last(&self) -> Option<Vec<u8>> {
+        self.nth_last(0)
+    }
+
+    // The state `n` pushes before the newest one.
+    pub fn nth_last(&self, mut n: usize) -> Option<Vec<u8>> {
+        for group in self.groups.iter().rev() {
+            let len = 1 + group.deltas.len();
+            if n < len {
+                return Some(match len - 1 - n {
+                    0 => group.keyframe.clone(),
+                    index => decode_delta(&group.keyframe, &group.deltas[index - 1]),
+                });
+            }
+            n -= len
+        }
+        None
+    }
+
+    // Drops the newest `n` states without decoding them.
+    pub fn discard(&mut self, n: usize) {
+        for _ in 0..n {
+            let group = match self.groups.back_mut() {
+                Some(group) => group,
+                None => return,
+            };
+            match group.deltas.pop() {
+                Some(delta) => self.used -= delta.len(),
+                None => {
+                    let group = self.groups.pop_back().unwrap();
+                    self.used -= group.keyframe.len()
+                }
+            }
+        }
+    }
+}
+
+// A delta is a sequence of (zero run, literal count, literals) chunks over the XOR of the
+// keyframe and the state, with both counts stored as little-endian u16.
+fn encode_delta(keyframe: &[u8], state: &[u8]) -> Vec<u8> {
+    let run = |start: usize, equal: bool| {
+        keyframe[start..]
+            .iter()
+            .zip(&state[start..])
+            .take(usize::from(u16::MAX))
+            .take_while(|(a, b)| (a == b) == equal)
+            .count()
+    };
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < state.len() {
+        let zeros = run(i, true);
+        i += zeros;
+        let literals = run(i, false);
+        delta.extend_from_slice(&(zeros as u16).to_le_bytes());
+        delta.extend_from_slice(&(literals as u16).to_le_bytes());
+        delta.extend(
+            keyframe[i..i + literals]
+                .iter()
+                .zip(&state[i..])
+                .map(|(a, b)| a ^ b),
+        );
+        i += literals
+    }
+    delta
+}
+
+fn decode_delta(keyframe: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = keyframe.to_vec();
+    let (mut i, mut j) = (0, 0);
+    while j < delta.len() {
+        let zeros = usize::from(u16::from_le_bytes([delta[j], delta[j + 1]]));
+        let literals = usize::from(u16::from_le_bytes([delta[j + 2], delta[j + 3]]));
+        j += 4;
+        i += zeros;
+        for (byte, &xor) in state[i..i + literals]
+            .iter_mut()
+            .zip(&delta[j..j + literals])
+        {
+            *byte ^= xor
+        }
+        i += literals;
+        j += literals
+    }
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trips() {
+        let keyframe: Vec<u8> = (0..0x30000).map(|i| i as u8).collect();
+        let mut state = keyframe.clone();
+        state[0] ^= 0xff;
+        state[0x1234] = 0x00;
+        state[0x2fffe] = 0x42;
+        state[0x2ffff] = 0x24;
+        let delta = encode_delta(&keyframe, &state);
+        assert!(delta.len() < 32);
+        assert_eq!(decode_delta(&keyframe, &delta), state)
+    }
+
+    #[test]
+    fn states_are_popped_newest_first() {
+        let mut buffer = RewindBuffer::new(usize::MAX);
+        for i in 0..100u8 {
+            buffer.push(&[i; 16])
+        }
+        assert_eq!(buffer.len(), 100);
+        assert_eq!(buffer.last(), Some(vec![99; 16]));
+        assert_eq!(buffer.nth_last(70), Some(vec![29; 16]));
+        assert_eq!(buffer.nth_last(100), None);
+        for i in (0..100).rev() {
+            assert_eq!(buffer.pop(), Some(vec![i; 16]))
+        }
+        assert_eq!(buffer.pop(), None);
+        assert_eq!(buffer.used(), 0)
+    }
+
+    #[test]
+    fn discarded_states_are_gone() {
+        let mut buffer = RewindBuffer::new(usize::MAX);
+        for i in 0..100u8 {
+            buffer.push(&[i; 16])
+        }
+        buffer.discard(45);
+        assert_eq!(buffer.len(), 55);
+        assert_eq!(buffer.last(), Some(vec![54; 16]));
+        buffer.discard(100);
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.used(), 0)
+    }
+
+    #[test]
+    fn oldest_groups_are_dropped_over_budget() {
+        let mut buffer = RewindBuffer::new(4096);
+        for i in 0..1000u16 {
+            let mut state = vec![0x00; 1024];
+            state[..2].copy_from_slice(&i.to_le_bytes());
+            buffer.push(&state)
+        }
+        assert!(buffer.used() <= 4096);
+        assert!(buffer.len() >= KEYFRAME_INTERVAL);
+        assert_eq!(&buffer.last().unwrap()[..2], &999u16.to_le_bytes())
+    }
+}
@@ -0,0 +1,349 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+use crate::Model;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+pub const SB: u16 = 0xff01;
+pub const SC: u16 = 0xff02;
+
+pub trait SerialPeer {
+    fn exchange(&mut self, bit: bool) -> bool;
+
+    fn poll(&mut self, _line: bool) -> Option<bool> {
+        None
+    }
+}
+
+pub struct Serial {
+    cgb: bool,
+    sb: u8,
+    sc: u8,
+    bits: u8,
+    div_bit: bool,
+    peer: Box<dyn SerialPeer>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self {
+            cgb: false,
+            sb: 0x00,
+            sc: 0x00,
+            bits: 0,
+            div_bit: false,
+            peer: Box::new(Disconnected),
+        }
+    }
+}
+
+impl Serial {
+    pub fn new(model: Model) -> Self {
+        Self {
+            cgb: model.is_cgb(),
+            ..Default::default()
+        }
+    }
+
+    pub fn connect(&mut self, peer: Box<dyn SerialPeer>) -> Box<dyn SerialPeer> {
+        std::mem::replace(&mut self.peer, peer)
+    }
+
+    pub fn disconnect(&mut self) -> Box<dyn SerialPeer> {
+        self.connect(Box::new(Disconnected))
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            SB => self.sb,
+            SC if self.cgb => self.sc | 0x7c,
+            SC => self.sc | 0x7e,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            SB => self.sb = data,
+            SC => {
+                self.sc = data & if self.cgb { 0x83 } else { 0x81 };
+                self.bits = 0
+            }
+            _ => (),
+        }
+    }
+
+    // Like `write`, but SC doesn't restart the bit counter of a transfer.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            SC => self.sc = data & if self.cgb { 0x83 } else { 0x81 },
+            _ => self.write(addr, data),
+        }
+    }
+
+    pub fn step(&mut self, div_bit: bool) -> bool {
+        let falling_edge = self.div_bit && !div_bit;
+        self.div_bit = div_bit;
+        if self.sc & 0x01 != 0 {
+            if self.is_transferring() && falling_edge {
+                let bit = self.peer.exchange(self.line());
+                return self.shift(bit);
+            }
+        } else {
+            let line = if self.is_transferring() {
+                self.line()
+            } else {
+                true
+            };
+            if let Some(bit) = self.peer.poll(line) {
+                if self.is_transferring() {
+                    return self.shift(bit);
+                }
+            }
+        }
+        false
+    }
+
+    // CGB internal clock at 32 times the normal rate.
+    pub fn is_fast_clock(&self) -> bool {
+        self.sc & 0x02 != 0
+    }
+
+    fn is_transferring(&self) -> bool {
+        self.sc & 0x80 != 0
+    }
+
+    fn line(&self) -> bool {
+        self.sb & 0x80 != 0
+    }
+
+    fn shift(&mut self, bit: bool) -> bool {
+        self.sb = self.sb << 1 | u8::from(bit);
+        self.bits += 1;
+        if self.bits == 8 {
+            self.bits = 0;
+            self.sc &= 0x7f;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl<T: SerialPeer> SerialPeer for Rc<RefCell<T>> {
+    fn exchange(&mut self, bit: bool) -> bool {
+        self.borrow_mut().exchange(bit)
+    }
+
+    fn poll(&mut self, line: bool) -> Option<bool> {
+        self.borrow_mut().poll(line)
+    }
+}
+
+pub struct Disconnected;
+
+impl SerialPeer for Disconnected {
+    fn exchange(&mut self, _bit: bool) -> bool {
+        true
+    }
+}
+
+pub struct Loopback;
+
+impl SerialPeer for Loopback {
+    fn exchange(&mut self, bit: bool) -> bool {
+        bit
+    }
+}
+
+#[derive(Default)]
+pub struct Capture {
+    bytes: Vec<u8>,
+    shifter: u8,
+    bits: u8,
+}
+
+impl Capture {
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn take(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+impl SerialPeer for Capture {
+    fn exchange(&mut self, bit: bool) -> bool {
+        self.shifter = self.shifter << 1 | u8::from(bit);
+        self.bits += 1;
+        if self.bits == 8 {
+            self.bytes.push(self.shifter);
+            self.bits = 0
+        }
+        true
+    }
+}
+
+pub struct LinkCable {
+    state: Rc<RefCell<CableState>>,
+    end: usize,
+}
+
+#[derive(Default)]
+struct CableState {
+    lines: [bool; 2],
+    clocked: [VecDeque<bool>; 2],
+}
+
+impl LinkCable {
+    pub fn new() -> (Self, Self) {
+        let state = Rc::new(RefCell::new(CableState {
+            lines: [true; 2],
+            ..Default::default()
+        }));
+        (
+            Self {
+                state: state.clone(),
+                end: 0,
+            },
+            Self { state, end: 1 },
+        )
+    }
+}
+
+impl SerialPeer for LinkCable {
+    fn exchange(&mut self, bit: bool) -> bool {
+        let mut state = self.state.borrow_mut();
+        let other = 1 - self.end;
+        state.clocked[other].push_back(bit);
+        state.lines[other]
+    }
+
+    fn poll(&mut self, line: bool) -> Option<bool> {
+        let mut state = self.state.borrow_mut();
+        state.lines[self.end] = line;
+        state.clocked[self.end].pop_front()
+    }
+}
+
+impl SaveState for Serial {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.sb);
+        writer.u8(self.sc);
+        writer.u8(self.bits);
+        writer.bool(self.div_bit)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.sb = reader.u8()?;
+        self.sc = reader.u8()?;
+        self.bits = reader.u8()?;
+        self.div_bit = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIV_BIT_PERIOD: usize = 128;
+
+    impl Serial {
+        fn run(&mut self, m_cycles: usize) -> usize {
+            (0..m_cycles)
+                .filter(|m_cycle| self.step(m_cycle % DIV_BIT_PERIOD < DIV_BIT_PERIOD / 2))
+                .count()
+        }
+    }
+
+    fn start_internal_transfer(serial: &mut Serial, data: u8) {
+        serial.write(SB, data);
+        serial.write(SC, 0x81)
+    }
+
+    #[test]
+    fn internal_clock_transfer_takes_8_bits() {
+        let mut serial = Serial::default();
+        start_internal_transfer(&mut serial, 0x42);
+        assert_eq!(serial.run(7 * DIV_BIT_PERIOD), 0);
+        assert_eq!(serial.read(SC), 0xff);
+        assert_eq!(serial.run(DIV_BIT_PERIOD), 1);
+        assert_eq!(serial.read(SC), 0x7f)
+    }
+
+    #[test]
+    fn clock_speed_bit_exists_only_on_cgb() {
+        let mut cgb = Serial::new(Model::Cgb);
+        cgb.write(SC, 0x00);
+        assert_eq!(cgb.read(SC), 0x7c);
+        cgb.write(SC, 0x02);
+        assert_eq!(cgb.read(SC), 0x7e);
+        assert!(cgb.is_fast_clock());
+        let mut dmg = Serial::new(Model::Dmg);
+        dmg.write(SC, 0x02);
+        assert_eq!(dmg.read(SC), 0x7e);
+        assert!(!dmg.is_fast_clock())
+    }
+
+    #[test]
+    fn disconnected_peer_shifts_in_ones() {
+        let mut serial = Serial::default();
+        start_internal_transfer(&mut serial, 0x42);
+        serial.run(8 * DIV_BIT_PERIOD);
+        assert_eq!(serial.read(SB), 0xff)
+    }
+
+    #[test]
+    fn loopback_receives_sent_byte() {
+        let mut serial = Serial::default();
+        serial.connect(Box::new(Loopback));
+        start_internal_transfer(&mut serial, 0x42);
+        serial.run(8 * DIV_BIT_PERIOD);
+        assert_eq!(serial.read(SB), 0x42)
+    }
+
+    #[test]
+    fn capture_collects_sent_bytes() {
+        let capture = Rc::new(RefCell::new(Capture::default()));
+        let mut serial = Serial::default();
+        serial.connect(Box::new(capture.clone()));
+        for &byte in b"ok" {
+            start_internal_transfer(&mut serial, byte);
+            serial.run(8 * DIV_BIT_PERIOD);
+        }
+        assert_eq!(capture.borrow().bytes(), b"ok");
+    }
+
+    #[test]
+    fn external_clock_without_peer_never_completes() {
+        let mut serial = Serial::default();
+        serial.write(SB, 0x42);
+        serial.write(SC, 0x80);
+        assert_eq!(serial.run(16 * DIV_BIT_PERIOD), 0);
+        assert_eq!(serial.read(SB), 0x42)
+    }
+
+    #[test]
+    fn linked_serials_exchange_bytes() {
+        let (master_end, slave_end) = LinkCable::new();
+        let mut master = Serial::default();
+        let mut slave = Serial::default();
+        master.connect(Box::new(master_end));
+        slave.connect(Box::new(slave_end));
+        slave.write(SB, 0xa5);
+        slave.write(SC, 0x80);
+        start_internal_transfer(&mut master, 0x3c);
+        let mut slave_interrupts = 0;
+        for m_cycle in 0..8 * DIV_BIT_PERIOD {
+            slave_interrupts += usize::from(slave.step(false));
+            master.step(m_cycle % DIV_BIT_PERIOD < DIV_BIT_PERIOD / 2);
+        }
+        slave_interrupts += usize::from(slave.step(false));
+        assert_eq!(master.read(SB), 0xa5);
+        assert_eq!(slave.read(SB), 0x3c);
+        assert_eq!(slave_interrupts, 1)
+    }
+}
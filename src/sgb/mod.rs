@@ -0,0 +1,542 @@
+use self::packet::{PacketReceiver, PACKET_SIZE};
+
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+mod packet;
+
+pub const SGB_SCREEN_WIDTH: usize = 256;
+pub const SGB_SCREEN_HEIGHT: usize = 224;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const MLT_REQ: u8 = 0x11;
+const CHR_TRN: u8 = 0x13;
+const PCT_TRN: u8 = 0x14;
+const MASK_EN: u8 = 0x17;
+
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+const COLUMNS: usize = SCREEN_WIDTH / 8;
+const ROWS: usize = SCREEN_HEIGHT / 8;
+const TRANSFER_SIZE: usize = 0x1000;
+const BORDER_TILE_SIZE: usize = 32;
+const BORDER_MAP_WIDTH: usize = 32;
+
+const DEFAULT_PALETTE: [u16; 4] = [0x7fff, 0x56b5, 0x294a, 0x0000];
+
+pub struct Sgb {
+    receiver: PacketReceiver,
+    command: Vec<u8>,
+    palettes: [[u16; 4]; 4],
+    attributes: [u8; COLUMNS * ROWS],
+    mask: Mask,
+    transfer: Option<Transfer>,
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; 4],
+    players: u8,
+    player: u8,
+    lines: u8,
+    screen: Vec<u16>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mask {
+    None,
+    Freeze,
+    Black,
+    Color0,
+}
+
+#[derive(Clone, Copy)]
+enum Transfer {
+    Chr(usize),
+    Pct,
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self {
+            receiver: Default::default(),
+            command: Vec::new(),
+            palettes: [DEFAULT_PALETTE; 4],
+            attributes: [0; COLUMNS * ROWS],
+            mask: Mask::None,
+            transfer: None,
+            border_tiles: vec![0x00; 2 * TRANSFER_SIZE],
+            border_map: vec![0x0000; BORDER_MAP_WIDTH * BORDER_MAP_WIDTH],
+            border_palettes: [[0x0000; 16]; 4],
+            players: 1,
+            player: 0,
+            lines: 0x30,
+            screen: vec![0x0000; SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT],
+        }
+    }
+}
+
+impl Sgb {
+    pub fn screen(&self) -> &[u16] {
+        &self.screen
+    }
+
+    pub fn mask(&self) -> Mask {
+        self.mask
+    }
+
+    pub fn player(&self) -> u8 {
+        self.player
+    }
+
+    pub fn write_p1(&mut self, data: u8) {
+        let lines = data & 0x30;
+        if self.players > 1 && self.lines & 0x20 == 0 && lines & 0x20 != 0 {
+            self.player = (self.player + 1) % self.players
+        }
+        self.lines = lines;
+        if let Some(packet) = self.receiver.write(data) {
+            self.receive_packet(&packet)
+        }
+    }
+
+    pub fn read_p1(&self, data: u8) -> u8 {
+        if self.players > 1 && self.lines == 0x30 {
+            data & 0xf0 | (0x0f - self.player)
+        } else if self.player != 0 {
+            data | 0x0f
+        } else {
+            data
+        }
+    }
+
+    pub fn vblank(&mut self, shades: &[u8]) {
+        if let Some(transfer) = self.transfer.take() {
+            let data = capture_transfer(shades);
+            match transfer {
+                Transfer::Chr(bank) => self.border_tiles[bank * TRANSFER_SIZE..][..TRANSFER_SIZE]
+                    .copy_from_slice(&data),
+                Transfer::Pct => self.load_border(&data),
+            }
+        }
+        self.render_border();
+        self.render_screen(shades)
+    }
+
+    fn receive_packet(&mut self, packet: &[u8; PACKET_SIZE]) {
+        if self.command.is_empty() && packet[0] & 0x07 == 0 {
+            return;
+        }
+        self.command.extend_from_slice(packet);
+        let len = usize::from(self.command[0] & 0x07) * PACKET_SIZE;
+        if self.command.len() >= len {
+            let command = std::mem::take(&mut self.command);
+            self.execute(&command)
+        }
+    }
+
+    fn execute(&mut self, command: &[u8]) {
+        match command[0] >> 3 {
+            PAL01 => self.set_palettes(0, 1, command),
+            PAL23 => self.set_palettes(2, 3, command),
+            PAL03 => self.set_palettes(0, 3, command),
+            PAL12 => self.set_palettes(1, 2, command),
+            ATTR_BLK => self.attr_blk(command),
+            MLT_REQ => {
+                self.players = match command[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _ => 1,
+                };
+                self.player = 0
+            }
+            CHR_TRN => self.transfer = Some(Transfer::Chr(usize::from(command[1] & 0x01))),
+            PCT_TRN => self.transfer = Some(Transfer::Pct),
+            MASK_EN => {
+                self.mask = match command[1] & 0x03 {
+                    0x00 => Mask::None,
+                    0x01 => Mask::Freeze,
+                    0x02 => Mask::Black,
+                    _ => Mask::Color0,
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn set_palettes(&mut self, first: usize, second: usize, command: &[u8]) {
+        let color =
+            |index: usize| u16::from_le_bytes([command[index], command[index + 1]]) & 0x7fff;
+        for palette in &mut self.palettes {
+            palette[0] = color(1)
+        }
+        for i in 1..4 {
+            self.palettes[first][i] = color(1 + 2 * i);
+            self.palettes[second][i] = color(7 + 2 * i)
+        }
+    }
+
+    fn attr_blk(&mut self, command: &[u8]) {
+        let sets = usize::from(command[1] & 0x1f).min(18);
+        for set in command[2..].chunks_exact(6).take(sets) {
+            let control = set[0] & 0x07;
+            let palettes = set[1];
+            let (x1, y1, x2, y2) = (set[2] & 0x1f, set[3] & 0x1f, set[4] & 0x1f, set[5] & 0x1f);
+            let inside = (control & 0x01 != 0).then_some(palettes & 0x03);
+            let outside = (control & 0x04 != 0).then_some(palettes >> 4 & 0x03);
+            let line = match control {
+                0x01 => inside,
+                0x04 => outside,
+                _ => (control & 0x02 != 0).then_some(palettes >> 2 & 0x03),
+            };
+            for y in 0..ROWS as u8 {
+                for x in 0..COLUMNS as u8 {
+                    let palette = if x1 < x && x < x2 && y1 < y && y < y2 {
+                        inside
+                    } else if x1 <= x && x <= x2 && y1 <= y && y <= y2 {
+                        line
+                    } else {
+                        outside
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[usize::from(y) * COLUMNS + usize::from(x)] = palette
+                    }
+                }
+            }
+        }
+    }
+
+    fn load_border(&mut self, data: &[u8]) {
+        for (entry, bytes) in self.border_map.iter_mut().zip(data.chunks_exact(2)) {
+            *entry = u16::from_le_bytes([bytes[0], bytes[1]])
+        }
+        let colors = data[0x800..0x880].chunks_exact(2);
+        for (color, bytes) in self.border_palettes.iter_mut().flatten().zip(colors) {
+            *color = u16::from_le_bytes([bytes[0], bytes[1]]) & 0x7fff
+        }
+    }
+
+    fn render_border(&mut self) {
+        let backdrop = self.palettes[0][0];
+        for y in 0..SGB_SCREEN_HEIGHT {
+            for x in 0..SGB_SCREEN_WIDTH {
+                if (SCREEN_X..SCREEN_X + SCREEN_WIDTH).contains(&x)
+                    && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT).contains(&y)
+                {
+                    continue;
+                }
+                let entry = self.border_map[y / 8 * BORDER_MAP_WIDTH + x / 8];
+                let column = if entry & 0x4000 != 0 {
+                    7 - x % 8
+                } else {
+                    x % 8
+                };
+                let row = if entry & 0x8000 != 0 {
+                    7 - y % 8
+                } else {
+                    y % 8
+                };
+                let index = self.border_pixel(usize::from(entry & 0xff), column, row);
+                let palette = usize::from(entry >> 10 & 0x07).saturating_sub(4);
+                self.screen[y * SGB_SCREEN_WIDTH + x] = if index == 0 {
+                    backdrop
+                } else {
+                    self.border_palettes[palette][usize::from(index)]
+                }
+            }
+        }
+    }
+
+    fn border_pixel(&self, tile: usize, column: usize, row: usize) -> u8 {
+        let tile = &self.border_tiles[tile * BORDER_TILE_SIZE..][..BORDER_TILE_SIZE];
+        let bit = 7 - column;
+        [
+            tile[2 * row],
+            tile[2 * row + 1],
+            tile[16 + 2 * row],
+            tile[17 + 2 * row],
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |index, (plane, byte)| {
+            index | (byte >> bit & 0x01) << plane
+        })
+    }
+
+    fn render_screen(&mut self, shades: &[u8]) {
+        for y in 0..SCREEN_HEIGHT {
+            for x in 0..SCREEN_WIDTH {
+                let palette = usize::from(self.attributes[y / 8 * COLUMNS + x / 8]);
+                let index = (SCREEN_Y + y) * SGB_SCREEN_WIDTH + SCREEN_X + x;
+                self.screen[index] = match self.mask {
+                    Mask::None => self.palettes[palette][usize::from(shades[y * SCREEN_WIDTH + x])],
+                    Mask::Freeze => continue,
+                    Mask::Black => 0x0000,
+                    Mask::Color0 => self.palettes[0][0],
+                }
+            }
+        }
+    }
+}
+
+fn capture_transfer(shades: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(TRANSFER_SIZE);
+    for tile in 0..TRANSFER_SIZE / 16 {
+        let (tile_x, tile_y) = (tile % COLUMNS * 8, tile / COLUMNS * 8);
+        for row in 0..8 {
+            let line = &shades[(tile_y + row) * SCREEN_WIDTH + tile_x..][..8];
+            let (low, high) = line.iter().fold((0, 0), |(low, high), &shade| {
+                (low << 1 | shade & 0x01, high << 1 | shade >> 1 & 0x01)
+            });
+            data.push(low);
+            data.push(high)
+        }
+    }
+    data
+}
+
+impl SaveState for Sgb {
+    fn save(&self, writer: &mut StateWriter) {
+        self.receiver.save(writer);
+        writer.u8(self.command.len() as u8);
+        writer.bytes(&self.command);
+        for palette in &self.palettes {
+            writer.u16s(palette)
+        }
+        writer.bytes(&self.attributes);
+        writer.u8(self.mask as u8);
+        match self.transfer {
+            None => writer.u8(0),
+            Some(Transfer::Chr(bank)) => {
+                writer.u8(1);
+                writer.u8(bank as u8)
+            }
+            Some(Transfer::Pct) => writer.u8(2),
+        }
+        writer.bytes(&self.border_tiles);
+        writer.u16s(&self.border_map);
+        for palette in &self.border_palettes {
+            writer.u16s(palette)
+        }
+        writer.u8(self.players);
+        writer.u8(self.player);
+        writer.u8(self.lines);
+        writer.u16s(&self.screen)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.receiver.load(reader)?;
+        let len = reader.u8()?;
+        self.command = reader.bytes(usize::from(len))?.to_vec();
+        for palette in &mut self.palettes {
+            reader.fill_u16s(palette)?
+        }
+        reader.fill(&mut self.attributes)?;
+        if let Some(&palette) = self.attributes.iter().find(|&&palette| palette > 3) {
+            return Err(StateError::InvalidValue("SGB attribute", palette.into()));
+        }
+        self.mask = match reader.u8()? {
+            0 => Mask::None,
+            1 => Mask::Freeze,
+            2 => Mask::Black,
+            3 => Mask::Color0,
+            code => return Err(StateError::InvalidValue("SGB mask", code.into())),
+        };
+        self.transfer = match reader.u8()? {
+            0 => None,
+            1 => Some(Transfer::Chr(usize::from(
+                reader.u8_below("CHR_TRN bank", 2)?,
+            ))),
+            2 => Some(Transfer::Pct),
+            code => return Err(StateError::InvalidValue("SGB transfer", code.into())),
+        };
+        reader.fill(&mut self.border_tiles)?;
+        reader.fill_u16s(&mut self.border_map)?;
+        for palette in &mut self.border_palettes {
+            reader.fill_u16s(palette)?
+        }
+        self.players = reader.u8()?;
+        self.player = reader.u8_below("SGB player", 4)?;
+        self.lines = reader.u8()?;
+        reader.fill_u16s(&mut self.screen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::packet::tests::pulses;
+    use super::*;
+
+    impl Sgb {
+        fn send(&mut self, command: &[u8]) {
+            for packet in command.chunks(PACKET_SIZE) {
+                let mut bytes = [0x00; PACKET_SIZE];
+                bytes[..packet.len()].copy_from_slice(packet);
+                for pulse in pulses(&bytes) {
+                    self.write_p1(pulse)
+                }
+            }
+        }
+
+        fn pixel(&self, x: usize, y: usize) -> u16 {
+            self.screen[(SCREEN_Y + y) * SGB_SCREEN_WIDTH + SCREEN_X + x]
+        }
+    }
+
+    fn command(code: u8, packets: u8, args: &[u8]) -> Vec<u8> {
+        let mut command = vec![0x00; PACKET_SIZE * usize::from(packets)];
+        command[0] = code << 3 | packets;
+        command[1..=args.len()].copy_from_slice(args);
+        command
+    }
+
+    fn pal(code: u8, colors: [u16; 7]) -> Vec<u8> {
+        let bytes: Vec<_> = colors
+            .iter()
+            .flat_map(|color| color.to_le_bytes())
+            .collect();
+        command(code, 1, &bytes)
+    }
+
+    fn screen_of(shade: u8) -> Vec<u8> {
+        vec![shade; SCREEN_WIDTH * SCREEN_HEIGHT]
+    }
+
+    fn shades_for(data: &[u8]) -> Vec<u8> {
+        let mut shades = screen_of(0);
+        for (i, pair) in data.chunks_exact(2).enumerate() {
+            let (tile, row) = (i / 8, i % 8);
+            let y = tile / COLUMNS * 8 + row;
+            for column in 0..8 {
+                let bit = 7 - column;
+                let shade = (pair[0] >> bit & 0x01) | (pair[1] >> bit & 0x01) << 1;
+                shades[y * SCREEN_WIDTH + tile % COLUMNS * 8 + column] = shade
+            }
+        }
+        shades
+    }
+
+    #[test]
+    fn pal01_sets_shared_color_0_and_two_palettes() {
+        let mut sgb = Sgb::default();
+        sgb.send(&pal(
+            PAL01,
+            [0x0001, 0x0002, 0x0003, 0x0004, 0x0005, 0x0006, 0x0007],
+        ));
+        assert_eq!(sgb.palettes[0], [0x0001, 0x0002, 0x0003, 0x0004]);
+        assert_eq!(sgb.palettes[1], [0x0001, 0x0005, 0x0006, 0x0007]);
+        assert_eq!(sgb.palettes[2][0], 0x0001);
+        assert_eq!(sgb.palettes[3][1..], DEFAULT_PALETTE[1..])
+    }
+
+    #[test]
+    fn pal12_sets_palettes_1_and_2() {
+        let mut sgb = Sgb::default();
+        sgb.send(&pal(
+            PAL12,
+            [0x0000, 0x0011, 0x0012, 0x0013, 0x0021, 0x0022, 0x0023],
+        ));
+        assert_eq!(sgb.palettes[1][1..], [0x0011, 0x0012, 0x0013]);
+        assert_eq!(sgb.palettes[2][1..], [0x0021, 0x0022, 0x0023])
+    }
+
+    #[test]
+    fn screen_is_colorized_through_palette_0() {
+        let mut sgb = Sgb::default();
+        sgb.send(&pal(PAL01, [0x1111, 0x2222, 0x3333, 0x4444, 0, 0, 0]));
+        sgb.vblank(&screen_of(2));
+        assert_eq!(sgb.pixel(0, 0), 0x3333);
+        assert_eq!(sgb.pixel(159, 143), 0x3333);
+        assert_eq!(sgb.screen()[0], 0x1111)
+    }
+
+    #[test]
+    fn attr_blk_colors_inside_border_and_outside() {
+        let mut sgb = Sgb::default();
+        sgb.send(&pal(PAL23, [0x0000, 0, 0, 0x2000, 0, 0, 0x3000]));
+        sgb.send(&pal(PAL01, [0x0000, 0, 0, 0x0000, 0, 0, 0x1000]));
+        sgb.send(&command(ATTR_BLK, 1, &[1, 0x07, 0b01_10_11, 2, 2, 6, 6]));
+        sgb.vblank(&screen_of(3));
+        assert_eq!(sgb.pixel(4 * 8, 4 * 8), 0x3000);
+        assert_eq!(sgb.pixel(2 * 8, 4 * 8), 0x2000);
+        assert_eq!(sgb.pixel(6 * 8 + 7, 6 * 8 + 7), 0x2000);
+        assert_eq!(sgb.pixel(7 * 8, 4 * 8), 0x1000)
+    }
+
+    #[test]
+    fn attr_blk_with_only_inside_flag_also_colors_border() {
+        let mut sgb = Sgb::default();
+        sgb.send(&command(ATTR_BLK, 1, &[1, 0x01, 0x02, 0, 0, 2, 2]));
+        assert_eq!(sgb.attributes[0], 2);
+        assert_eq!(sgb.attributes[COLUMNS + 1], 2);
+        assert_eq!(sgb.attributes[3], 0)
+    }
+
+    #[test]
+    fn mask_en_blanks_or_freezes_screen() {
+        let mut sgb = Sgb::default();
+        sgb.vblank(&screen_of(0));
+        sgb.send(&command(MASK_EN, 1, &[0x01]));
+        sgb.vblank(&screen_of(3));
+        assert_eq!(sgb.pixel(0, 0), DEFAULT_PALETTE[0]);
+        sgb.send(&command(MASK_EN, 1, &[0x02]));
+        sgb.vblank(&screen_of(0));
+        assert_eq!(sgb.pixel(0, 0), 0x0000);
+        sgb.send(&command(MASK_EN, 1, &[0x00]));
+        sgb.vblank(&screen_of(3));
+        assert_eq!(sgb.mask(), Mask::None);
+        assert_eq!(sgb.pixel(0, 0), DEFAULT_PALETTE[3])
+    }
+
+    #[test]
+    fn mlt_req_exposes_player_id_on_p1() {
+        let mut sgb = Sgb::default();
+        sgb.send(&command(MLT_REQ, 1, &[0x01]));
+        assert_eq!(sgb.read_p1(0xff), 0xff);
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.player(), 1);
+        assert_eq!(sgb.read_p1(0xff), 0xfe);
+        sgb.write_p1(0x20);
+        assert_eq!(sgb.read_p1(0xd0), 0xdf);
+        sgb.write_p1(0x10);
+        sgb.write_p1(0x30);
+        assert_eq!(sgb.player(), 0)
+    }
+
+    #[test]
+    fn chr_trn_captures_screen_as_tile_data() {
+        let mut sgb = Sgb::default();
+        sgb.send(&command(CHR_TRN, 1, &[0x01]));
+        let mut shades = screen_of(0);
+        shades[0] = 3;
+        shades[8] = 1;
+        sgb.vblank(&shades);
+        let tiles = &sgb.border_tiles[TRANSFER_SIZE..];
+        assert_eq!(tiles[..2], [0x80, 0x80]);
+        assert_eq!(tiles[16..18], [0x80, 0x00])
+    }
+
+    #[test]
+    fn captured_transfer_round_trips() {
+        let data: Vec<_> = (0..TRANSFER_SIZE).map(|i| (i * 7) as u8).collect();
+        assert_eq!(capture_transfer(&shades_for(&data)), data)
+    }
+
+    #[test]
+    fn pct_trn_loads_border_map_and_palettes() {
+        let mut sgb = Sgb::default();
+        sgb.border_tiles[BORDER_TILE_SIZE..2 * BORDER_TILE_SIZE].copy_from_slice(&[0xff; 32]);
+        sgb.send(&command(PCT_TRN, 1, &[]));
+        let mut data = vec![0x00; TRANSFER_SIZE];
+        data[..2].copy_from_slice(&0x1001u16.to_le_bytes());
+        data[0x800 + 2 * 15..][..2].copy_from_slice(&0x1234u16.to_le_bytes());
+        sgb.vblank(&shades_for(&data));
+        assert_eq!(sgb.border_map[0], 0x1001);
+        assert_eq!(sgb.border_palettes[0][15], 0x1234);
+        assert_eq!(sgb.screen()[0], 0x1234);
+        assert_eq!(sgb.screen()[8], DEFAULT_PALETTE[0])
+    }
+}
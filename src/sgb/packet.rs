@@ -0,0 +1,127 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub(super) const PACKET_SIZE: usize = 16;
+
+const PACKET_BITS: usize = 8 * PACKET_SIZE;
+
+pub(super) struct PacketReceiver {
+    lines: u8,
+    bit: Option<usize>,
+    packet: [u8; PACKET_SIZE],
+}
+
+impl Default for PacketReceiver {
+    fn default() -> Self {
+        Self {
+            lines: 0x30,
+            bit: None,
+            packet: [0x00; PACKET_SIZE],
+        }
+    }
+}
+
+impl PacketReceiver {
+    pub(super) fn write(&mut self, data: u8) -> Option<[u8; PACKET_SIZE]> {
+        let lines = data & 0x30;
+        let previous = self.lines;
+        self.lines = lines;
+        match lines {
+            0x00 => {
+                self.bit = Some(0);
+                self.packet = [0x00; PACKET_SIZE];
+                None
+            }
+            0x10 | 0x20 if previous == 0x30 => self.receive_bit(lines == 0x10),
+            _ => None,
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) -> Option<[u8; PACKET_SIZE]> {
+        let index = self.bit?;
+        if index == PACKET_BITS {
+            self.bit = None;
+            return if bit { None } else { Some(self.packet) };
+        }
+        if bit {
+            self.packet[index / 8] |= 1 << (index % 8)
+        }
+        self.bit = Some(index + 1);
+        None
+    }
+}
+
+impl SaveState for PacketReceiver {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u8(self.lines);
+        writer.option_u8(self.bit.map(|bit| bit as u8));
+        writer.bytes(&self.packet)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.lines = reader.u8()?;
+        self.bit = match reader.option_u8()? {
+            Some(bit) if usize::from(bit) > PACKET_BITS => {
+                return Err(StateError::InvalidValue("SGB packet bit", bit.into()))
+            }
+            bit => bit.map(usize::from),
+        };
+        reader.fill(&mut self.packet)
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    pub(in crate::sgb) fn pulses(packet: &[u8; PACKET_SIZE]) -> Vec<u8> {
+        let mut pulses = vec![0x00, 0x30];
+        for index in 0..PACKET_BITS {
+            let bit = packet[index / 8] >> (index % 8) & 0x01 != 0;
+            pulses.push(if bit { 0x10 } else { 0x20 });
+            pulses.push(0x30)
+        }
+        pulses.extend_from_slice(&[0x20, 0x30]);
+        pulses
+    }
+
+    fn receive(receiver: &mut PacketReceiver, pulses: &[u8]) -> Vec<[u8; PACKET_SIZE]> {
+        pulses
+            .iter()
+            .filter_map(|&pulse| receiver.write(pulse))
+            .collect()
+    }
+
+    #[test]
+    fn packet_is_received_lsb_first() {
+        let mut packet = [0x00; PACKET_SIZE];
+        packet[0] = 0x89;
+        packet[15] = 0x42;
+        let mut receiver = PacketReceiver::default();
+        assert_eq!(receive(&mut receiver, &pulses(&packet)), vec![packet])
+    }
+
+    #[test]
+    fn bits_without_reset_pulse_are_ignored() {
+        let mut receiver = PacketReceiver::default();
+        let pulses = pulses(&[0xff; PACKET_SIZE]);
+        assert!(receive(&mut receiver, &pulses[2..]).is_empty())
+    }
+
+    #[test]
+    fn packet_without_stop_bit_is_dropped() {
+        let mut receiver = PacketReceiver::default();
+        let mut pulses = pulses(&[0x00; PACKET_SIZE]);
+        let len = pulses.len();
+        pulses[len - 2] = 0x10;
+        assert!(receive(&mut receiver, &pulses).is_empty())
+    }
+
+    #[test]
+    fn reset_pulse_restarts_packet() {
+        let mut receiver = PacketReceiver::default();
+        let mut pulses = pulses(&[0xff; PACKET_SIZE])[..20].to_vec();
+        let packet = [0x11; PACKET_SIZE];
+        pulses.extend(self::pulses(&packet));
+        assert_eq!(receive(&mut receiver, &pulses), vec![packet])
+    }
+}
@@ -0,0 +1,420 @@
+use std::fmt;
+use std::mem;
+
+pub const MAGIC: [u8; 4] = *b"GBST";
+pub const FORMAT_VERSION: u16 = MIGRATIONS.len() as u16 + 1;
+
+// MIGRATIONS[i] upgrades a container from version i + 1 to version i + 2.
+const MIGRATIONS: &[Migration] = &[];
+
+pub type Tag = [u8; 4];
+
+pub type Migration = fn(&mut Container) -> Result<(), StateError>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StateError {
+    UnexpectedEnd,
+    TrailingData,
+    BadMagic,
+    UnsupportedVersion(u16),
+    MissingSection(Tag),
+    InvalidValue(&'static str, u16),
+    RomMismatch,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::UnexpectedEnd => write!(f, "state ends unexpectedly"),
+            StateError::TrailingData => write!(f, "state has trailing data"),
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported state version {}", version)
+            }
+            StateError::MissingSection(tag) => {
+                write!(f, "missing section {:?}", String::from_utf8_lossy(tag))
+            }
+            StateError::InvalidValue(field, value) => {
+                write!(f, "invalid {} {:#04x}", field, value)
+            }
+            StateError::RomMismatch => write!(f, "state was saved with another ROM"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub trait SaveState {
+    fn save(&self, writer: &mut StateWriter);
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Container {
+    version: u16,
+    sections: Vec<(Tag, Vec<u8>)>,
+}
+
+impl Default for Container {
+    fn default() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            sections: Vec::new(),
+        }
+    }
+}
+
+impl Container {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, StateError> {
+        Self::from_bytes_with_migrations(bytes, MIGRATIONS)
+    }
+
+    fn from_bytes_with_migrations(
+        bytes: &[u8],
+        migrations: &[Migration],
+    ) -> Result<Self, StateError> {
+        let mut reader = StateReader::new(bytes);
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = reader.u16()?;
+        let current = migrations.len() as u16 + 1;
+        if version == 0 || version > current {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let mut container = Container {
+            version,
+            sections: Vec::new(),
+        };
+        while !reader.is_empty() {
+            let mut tag = [0; 4];
+            reader.fill(&mut tag)?;
+            let len = reader.u32()? as usize;
+            container.sections.push((tag, reader.bytes(len)?.to_vec()))
+        }
+        for migration in &migrations[usize::from(version - 1)..] {
+            migration(&mut container)?;
+            container.version += 1
+        }
+        Ok(container)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        self.write_bytes(&mut bytes);
+        bytes
+    }
+
+    // Like `to_bytes`, but reuses the allocation of `bytes`.
+    pub fn write_bytes(&self, bytes: &mut Vec<u8>) {
+        bytes.clear();
+        let mut writer = StateWriter {
+            bytes: mem::take(bytes),
+        };
+        writer.bytes(&MAGIC);
+        writer.u16(self.version);
+        for (tag, section) in &self.sections {
+            writer.bytes(tag);
+            writer.u32(section.len() as u32);
+            writer.bytes(section)
+        }
+        *bytes = writer.into_bytes()
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn section(&self, tag: Tag) -> Option<&[u8]> {
+        self.sections
+            .iter()
+            .find(|(section, _)| *section == tag)
+            .map(|(_, bytes)| bytes.as_slice())
+    }
+
+    pub fn set_section(&mut self, tag: Tag, bytes: Vec<u8>) {
+        match self
+            .sections
+            .iter_mut()
+            .find(|(section, _)| *section == tag)
+        {
+            Some((_, section)) => *section = bytes,
+            None => self.sections.push((tag, bytes)),
+        }
+    }
+
+    pub fn remove_section(&mut self, tag: Tag) -> Option<Vec<u8>> {
+        let index = self
+            .sections
+            .iter()
+            .position(|(section, _)| *section == tag)?;
+        Some(self.sections.remove(index).1)
+    }
+
+    // Overwrites the section in place, reusing its previous allocation.
+    pub fn save<T: SaveState>(&mut self, tag: Tag, component: &T) {
+        let index = match self
+            .sections
+            .iter()
+            .position(|(section, _)| *section == tag)
+        {
+            Some(index) => index,
+            None => {
+                self.sections.push((tag, Vec::new()));
+                self.sections.len() - 1
+            }
+        };
+        let bytes = &mut self.sections[index].1;
+        bytes.clear();
+        let mut writer = StateWriter {
+            bytes: mem::take(bytes),
+        };
+        component.save(&mut writer);
+        *bytes = writer.into_bytes()
+    }
+
+    pub fn load<T: SaveState>(&self, tag: Tag, component: &mut T) -> Result<(), StateError> {
+        let mut reader =
+            StateReader::new(self.section(tag).ok_or(StateError::MissingSection(tag))?);
+        component.load(&mut reader)?;
+        if reader.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::TrailingData)
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, data: u8) {
+        self.bytes.push(data)
+    }
+
+    pub fn u16(&mut self, data: u16) {
+        self.bytes.extend_from_slice(&data.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, data: u32) {
+        self.bytes.extend_from_slice(&data.to_le_bytes())
+    }
+
+    pub fn bool(&mut self, data: bool) {
+        self.u8(data as u8)
+    }
+
+    pub fn option_u8(&mut self, data: Option<u8>) {
+        self.bool(data.is_some());
+        self.u8(data.unwrap_or(0x00))
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.bytes.extend_from_slice(data)
+    }
+
+    pub fn u16s(&mut self, data: &[u16]) {
+        for &data in data {
+            self.u16(data)
+        }
+    }
+}
+
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0x00 => Ok(false),
+            0x01 => Ok(true),
+            value => Err(StateError::InvalidValue("bool", value.into())),
+        }
+    }
+
+    pub fn u8_below(&mut self, field: &'static str, limit: u8) -> Result<u8, StateError> {
+        match self.u8()? {
+            value if value < limit => Ok(value),
+            value => Err(StateError::InvalidValue(field, value.into())),
+        }
+    }
+
+    pub fn u16_below(&mut self, field: &'static str, limit: u16) -> Result<u16, StateError> {
+        match self.u16()? {
+            value if value < limit => Ok(value),
+            value => Err(StateError::InvalidValue(field, value)),
+        }
+    }
+
+    pub fn option_u8(&mut self) -> Result<Option<u8>, StateError> {
+        let is_some = self.bool()?;
+        let data = self.u8()?;
+        Ok(if is_some { Some(data) } else { None })
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.bytes.len() < len {
+            return Err(StateError::UnexpectedEnd);
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    pub fn fill(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        buffer.copy_from_slice(self.bytes(buffer.len())?);
+        Ok(())
+    }
+
+    pub fn fill_u16s(&mut self, buffer: &mut [u16]) -> Result<(), StateError> {
+        for data in buffer {
+            *data = self.u16()?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip() {
+        let mut writer = StateWriter::default();
+        writer.u8(0x12);
+        writer.u16(0x3456);
+        writer.u32(0x789a_bcde);
+        writer.bool(true);
+        writer.option_u8(None);
+        writer.option_u8(Some(0xf0));
+        let bytes = writer.into_bytes();
+        let mut reader = StateReader::new(&bytes);
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.u32(), Ok(0x789a_bcde));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.option_u8(), Ok(None));
+        assert_eq!(reader.option_u8(), Ok(Some(0xf0)));
+        assert!(reader.is_empty())
+    }
+
+    #[test]
+    fn reading_past_end_fails() {
+        let mut reader = StateReader::new(&[0x12]);
+        assert_eq!(reader.u16(), Err(StateError::UnexpectedEnd))
+    }
+
+    #[test]
+    fn container_round_trips_sections() {
+        let mut container = Container::default();
+        container.set_section(*b"AAAA", vec![0x01, 0x02]);
+        container.set_section(*b"BBBB", vec![]);
+        container.set_section(*b"AAAA", vec![0x03]);
+        let bytes = container.to_bytes();
+        assert_eq!(&bytes[..4], b"GBST");
+        let container = Container::from_bytes(&bytes).unwrap();
+        assert_eq!(container.version(), FORMAT_VERSION);
+        assert_eq!(container.section(*b"AAAA"), Some(&[0x03][..]));
+        assert_eq!(container.section(*b"BBBB"), Some(&[][..]));
+        assert_eq!(container.section(*b"CCCC"), None)
+    }
+
+    #[test]
+    fn container_rejects_bad_magic_and_newer_versions() {
+        let mut bytes = Container::default().to_bytes();
+        bytes[4] = 0xff;
+        assert_eq!(
+            Container::from_bytes(&bytes),
+            Err(StateError::UnsupportedVersion(0x00ff))
+        );
+        bytes[0] = b'X';
+        assert_eq!(Container::from_bytes(&bytes), Err(StateError::BadMagic))
+    }
+
+    #[test]
+    fn older_containers_are_migrated_in_order() {
+        fn split_section(container: &mut Container) -> Result<(), StateError> {
+            let old = container
+                .remove_section(*b"OLD ")
+                .ok_or(StateError::MissingSection(*b"OLD "))?;
+            container.set_section(*b"LOW ", old[..1].to_vec());
+            container.set_section(*b"HIGH", old[1..].to_vec());
+            Ok(())
+        }
+
+        fn double_low(container: &mut Container) -> Result<(), StateError> {
+            let low = container.section(*b"LOW ").unwrap()[0];
+            container.set_section(*b"LOW ", vec![2 * low]);
+            Ok(())
+        }
+
+        let mut container = Container {
+            version: 1,
+            sections: Vec::new(),
+        };
+        container.set_section(*b"OLD ", vec![0x01, 0x02]);
+        let bytes = container.to_bytes();
+        let migrations: &[Migration] = &[split_section, double_low];
+        let container = Container::from_bytes_with_migrations(&bytes, migrations).unwrap();
+        assert_eq!(container.version(), 3);
+        assert_eq!(container.section(*b"OLD "), None);
+        assert_eq!(container.section(*b"LOW "), Some(&[0x02][..]));
+        assert_eq!(container.section(*b"HIGH"), Some(&[0x02][..]))
+    }
+
+    #[test]
+    fn bool_must_be_zero_or_one() {
+        let mut reader = StateReader::new(&[0x02]);
+        assert_eq!(reader.bool(), Err(StateError::InvalidValue("bool", 0x02)))
+    }
+
+    #[test]
+    fn bounded_u8_must_be_below_limit() {
+        let mut reader = StateReader::new(&[0x03, 0x04]);
+        assert_eq!(reader.u8_below("duty", 4), Ok(0x03));
+        assert_eq!(
+            reader.u8_below("duty", 4),
+            Err(StateError::InvalidValue("duty", 0x04))
+        )
+    }
+
+    #[test]
+    fn bounded_u16_must_be_below_limit() {
+        let mut reader = StateReader::new(&[0xff, 0x07, 0x00, 0x08]);
+        assert_eq!(reader.u16_below("frequency", 0x800), Ok(0x07ff));
+        assert_eq!(
+            reader.u16_below("frequency", 0x800),
+            Err(StateError::InvalidValue("frequency", 0x0800))
+        )
+    }
+}
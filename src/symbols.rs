@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Location {
+    pub bank: u16,
+    pub addr: u16,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+#[derive(Default)]
+pub struct Symbols {
+    names: HashMap<String, Location>,
+    labels: BTreeMap<Location, String>,
+}
+
+impl Location {
+    fn region(&self) -> u16 {
+        match self.addr {
+            0x0000..=0x3fff => 0,
+            0x4000..=0x7fff => 1,
+            0x8000..=0x9fff => 2,
+            0xa000..=0xbfff => 3,
+            0xc000..=0xcfff => 4,
+            0xd000..=0xdfff => 5,
+            0xfe00..=0xfeff => 6,
+            0xff00..=0xff7f => 7,
+            0xff80..=0xfffe => 8,
+            _ => 9,
+        }
+    }
+}
+
+// Whether `addr` lies in a region whose contents depend on a bank register.
+pub fn is_banked(addr: u16) -> bool {
+    matches!(addr, 0x4000..=0xbfff | 0xd000..=0xdfff)
+}
+
+impl Symbols {
+    // Parses `bank:addr label` lines as written by `rgblink -n`.
+    pub fn from_sym(source: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Self::default();
+        for (index, line) in source.lines().enumerate() {
+            let line = strip_comment(line);
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| SymbolError {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            let mut fields = line.split_whitespace();
+            let location = fields.next().ok_or_else(|| error("missing address"))?;
+            let name = fields.next().ok_or_else(|| error("missing label"))?;
+            let (bank, addr) = location
+                .split_once(':')
+                .ok_or_else(|| error("expected bank:addr"))?;
+            let location = Location {
+                bank: u16::from_str_radix(bank, 16).map_err(|_| error("invalid bank"))?,
+                addr: u16::from_str_radix(addr, 16).map_err(|_| error("invalid address"))?,
+            };
+            symbols.insert(name, location)
+        }
+        Ok(symbols)
+    }
+
+    // Parses the symbol listings of a map file written by `rgblink -m`.
+    pub fn from_map(source: &str) -> Result<Self, SymbolError> {
+        let mut symbols = Self::default();
+        let mut bank = None;
+        for (index, line) in source.lines().enumerate() {
+            let line = strip_comment(line);
+            let error = |message: &str| SymbolError {
+                line: index + 1,
+                message: message.to_string(),
+            };
+            if let Some(position) = line.to_ascii_lowercase().find("bank #") {
+                let digits = &line[position + 6..];
+                let end = digits
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(digits.len());
+                bank = Some(digits[..end].parse().map_err(|_| error("invalid bank"))?);
+                continue;
+            }
+            let (addr, name) = match line.split_once('=') {
+                Some((addr, name)) if addr.trim().starts_with('$') => (addr.trim(), name.trim()),
+                _ => continue,
+            };
+            let bank = bank.ok_or_else(|| error("symbol outside of a bank"))?;
+            let addr = u16::from_str_radix(&addr[1..], 16).map_err(|_| error("invalid address"))?;
+            if name.is_empty() {
+                return Err(error("missing label"));
+            }
+            symbols.insert(name, Location { bank, addr })
+        }
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, name: &str, location: Location) {
+        self.names.insert(name.to_string(), location);
+        self.labels
+            .entry(location)
+            .or_insert_with(|| name.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Location> {
+        self.names.get(name).copied()
+    }
+
+    pub fn label(&self, location: Location) -> Option<&str> {
+        self.labels.get(&location).map(String::as_str)
+    }
+
+    // Accepts `label`, `label+offset`, `label-offset`, `bank:addr` or a bare
+    // `addr`, with numbers in `$hex`, `0xhex` or decimal. Only bare addresses
+    // resolve without a bank.
+    pub fn resolve(&self, expr: &str) -> Option<(Option<u16>, u16)> {
+        let expr = expr.trim();
+        if let Some((bank, addr)) = expr.split_once(':') {
+            return Some((Some(parse_number(bank)?), parse_number(addr)?));
+        }
+        if let Some(addr) = parse_number(expr) {
+            return Some((None, addr));
+        }
+        let (name, offset) = match expr.rfind(['+', '-']) {
+            Some(position) if position > 0 => {
+                let offset = parse_number(&expr[position + 1..])?;
+                let offset = if expr.as_bytes()[position] == b'-' {
+                    offset.wrapping_neg()
+                } else {
+                    offset
+                };
+                (expr[..position].trim(), offset)
+            }
+            _ => (expr, 0),
+        };
+        let location = self.get(name)?;
+        Some((Some(location.bank), location.addr.wrapping_add(offset)))
+    }
+
+    // Names `location` after the closest label at or before it in the same
+    // bank and memory region, e.g. `Main+$3`.
+    pub fn symbolize(&self, location: Location) -> Option<String> {
+        let (label_location, name) = self.labels.range(..=location).next_back()?;
+        if label_location.bank != location.bank || label_location.region() != location.region() {
+            return None;
+        }
+        Some(match location.addr - label_location.addr {
+            0 => name.clone(),
+            offset => format!("{}+${:X}", name, offset),
+        })
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    line.split(';').next().unwrap_or("").trim()
+}
+
+fn parse_number(string: &str) -> Option<u16> {
+    let string = string.trim();
+    if let Some(hex) = string.strip_prefix('$') {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = string
+        .strip_prefix("0x")
+        .or_else(|| string.strip_prefix("0X"))
+    {
+        u16::from_str_radix(hex, 16).ok()
+    } else if string.starts_with(|c: char| c.is_ascii_digit()) {
+        string.parse().ok()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "\
+; File generated by rgblink
+00:0150 Main
+00:0158 Main.loop
+01:4000 UpdateSprites
+02:4000 PlaySound
+00:c000 wCounter
+00:ff80 hFrame
+";
+
+    const MAP: &str = "\
+SUMMARY:
+\tROM0: 384 bytes used / 16000 free
+
+ROM0 bank #0:
+\tSECTION: $0150-$015f ($0010 bytes) [\"Main\"]
+\t         $0150 = Main
+\t         $0158 = Main.loop
+\tEMPTY: $3ea0 bytes
+
+ROMX bank #1:
+\tSECTION: $4000-$40ff ($0100 bytes) [\"Sprites\"]
+\t         $4000 = UpdateSprites
+
+WRAM0 bank #0:
+\tSECTION: $c000-$c000 ($0001 byte) [\"Vars\"]
+\t         $c000 = wCounter
+";
+
+    fn location(bank: u16, addr: u16) -> Location {
+        Location { bank, addr }
+    }
+
+    #[test]
+    fn sym_file_is_parsed() {
+        let symbols = Symbols::from_sym(SYM).unwrap();
+        assert_eq!(symbols.len(), 6);
+        assert_eq!(symbols.get("PlaySound"), Some(location(2, 0x4000)));
+        assert_eq!(symbols.label(location(1, 0x4000)), Some("UpdateSprites"))
+    }
+
+    #[test]
+    fn map_file_is_parsed() {
+        let symbols = Symbols::from_map(MAP).unwrap();
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.get("Main.loop"), Some(location(0, 0x0158)));
+        assert_eq!(symbols.get("UpdateSprites"), Some(location(1, 0x4000)));
+        assert_eq!(symbols.get("wCounter"), Some(location(0, 0xc000)))
+    }
+
+    #[test]
+    fn malformed_sym_line_is_reported() {
+        assert_eq!(
+            Symbols::from_sym("00:0150 Main\n0150\n").err(),
+            Some(SymbolError {
+                line: 2,
+                message: "missing label".into()
+            })
+        )
+    }
+
+    #[test]
+    fn expressions_resolve_to_addresses() {
+        let symbols = Symbols::from_sym(SYM).unwrap();
+        assert_eq!(symbols.resolve("Main"), Some((Some(0), 0x0150)));
+        assert_eq!(symbols.resolve("Main.loop+2"), Some((Some(0), 0x015a)));
+        assert_eq!(symbols.resolve("PlaySound + $10"), Some((Some(2), 0x4010)));
+        assert_eq!(symbols.resolve("hFrame-1"), Some((Some(0), 0xff7f)));
+        assert_eq!(symbols.resolve("$c123"), Some((None, 0xc123)));
+        assert_eq!(symbols.resolve("3:0x4567"), Some((Some(3), 0x4567)));
+        assert_eq!(symbols.resolve("Missing"), None)
+    }
+
+    #[test]
+    fn symbolize_respects_banks_and_regions() {
+        let symbols = Symbols::from_sym(SYM).unwrap();
+        assert_eq!(symbols.symbolize(location(0, 0x0158)).unwrap(), "Main.loop");
+        assert_eq!(
+            symbols.symbolize(location(0, 0x015b)).unwrap(),
+            "Main.loop+$3"
+        );
+        assert_eq!(
+            symbols.symbolize(location(2, 0x4020)).unwrap(),
+            "PlaySound+$20"
+        );
+        assert_eq!(symbols.symbolize(location(3, 0x4020)), None);
+        assert_eq!(symbols.symbolize(location(0, 0x4000)), None);
+        assert_eq!(symbols.symbolize(location(0, 0x8000)), None)
+    }
+}
@@ -0,0 +1,1050 @@
+use crate::apu::Resampler;
+use crate::cartridge::Cartridge;
+use crate::cpu::bus_trace::BusTraceWriter;
+use crate::cpu::trace::DoctorTrace;
+use crate::cpu::{BusActivity, Cpu, Input, Output};
+use crate::dma::{OamDma, DMA};
+use crate::interrupt;
+use crate::joypad::Buttons;
+use crate::memory::{MemoryMap, IE};
+use crate::ppu::Mode;
+use crate::profiler::Profiler;
+use crate::rewind::RewindBuffer;
+use crate::serial::Serial;
+use crate::sgb::Sgb;
+use crate::state::{Container, StateError, StateReader, StateWriter, Tag};
+use crate::symbols::Symbols;
+use crate::timer::DIV;
+use crate::Model;
+
+use std::io::{self, Write};
+
+pub const M_CYCLES_PER_FRAME: usize = 17556;
+
+const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+const SERIAL_DIV_BIT: u16 = 0x0100;
+const FAST_SERIAL_DIV_BIT: u16 = 0x0008;
+const APU_DIV_BIT: u16 = 0x1000;
+const DOUBLE_SPEED_APU_DIV_BIT: u16 = 0x2000;
+
+const MODEL_SECTION: Tag = *b"MODL";
+const ROM_SECTION: Tag = *b"ROM ";
+const CPU_SECTION: Tag = *b"CPU ";
+const MEMORY_SECTION: Tag = *b"MEM ";
+const CARTRIDGE_SECTION: Tag = *b"CART";
+const PPU_SECTION: Tag = *b"PPU ";
+const APU_SECTION: Tag = *b"APU ";
+const TIMER_SECTION: Tag = *b"TIMR";
+const SERIAL_SECTION: Tag = *b"SERL";
+const JOYPAD_SECTION: Tag = *b"JOYP";
+const HDMA_SECTION: Tag = *b"HDMA";
+const OAM_DMA_SECTION: Tag = *b"ODMA";
+const SGB_SECTION: Tag = *b"SGB ";
+const SYSTEM_SECTION: Tag = *b"SYS ";
+
+pub struct GameBoy {
+    model: Model,
+    cpu: Cpu,
+    memory: MemoryMap,
+    dma: OamDma,
+    resampler: Resampler,
+    audio: Vec<i16>,
+    rewind: Option<Rewind>,
+    trace: Option<DoctorTrace>,
+    bus_trace: Option<BusTraceWriter>,
+    profiler: Option<Profiler>,
+    bus_activity: Vec<BusActivity>,
+    at_boundary: bool,
+    split_at_boundary: bool,
+    deferred_cpu_cycle: Option<bool>,
+}
+
+struct Rewind {
+    buffer: RewindBuffer,
+    // The last snapshot; sections that can't have changed are kept as they are.
+    sections: Container,
+    cartridge_revision: Option<u64>,
+    state: Vec<u8>,
+}
+
+pub struct Frame<'a> {
+    pub video: &'a [u8],
+    pub rgb555: &'a [u16],
+    pub sgb: Option<&'a [u16]>,
+    pub audio: &'a [i16],
+}
+
+impl GameBoy {
+    pub fn new(cartridge: Cartridge) -> Self {
+        let model = if cartridge.supports_cgb() {
+            Model::Cgb
+        } else {
+            Model::Dmg
+        };
+        Self::with_model(cartridge, model)
+    }
+
+    pub fn with_model(cartridge: Cartridge, model: Model) -> Self {
+        let cpu = Cpu::post_boot(model, cartridge.header_checksum());
+        Self::with_parts(model, cpu, MemoryMap::post_boot(cartridge, model))
+    }
+
+    pub fn with_boot_rom(cartridge: Cartridge, model: Model, boot_rom: Vec<u8>) -> Self {
+        let memory = MemoryMap::with_boot_rom(cartridge, model, boot_rom);
+        Self::with_parts(model, Cpu::new(model), memory)
+    }
+
+    fn with_parts(model: Model, cpu: Cpu, memory: MemoryMap) -> Self {
+        Self {
+            model,
+            cpu,
+            memory,
+            dma: Default::default(),
+            resampler: Resampler::new(DEFAULT_SAMPLE_RATE),
+            audio: Vec::new(),
+            rewind: None,
+            trace: None,
+            bus_trace: None,
+            profiler: None,
+            bus_activity: Vec::new(),
+            at_boundary: false,
+            split_at_boundary: false,
+            deferred_cpu_cycle: None,
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> &MemoryMap {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut MemoryMap {
+        &mut self.memory
+    }
+
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            DMA => self.dma.register(),
+            IE => self.cpu.data.ie,
+            _ => self.memory.peek(addr),
+        }
+    }
+
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            DMA => self.dma.poke(data),
+            IE => self.cpu.data.ie = data,
+            _ => self.memory.poke(addr, data),
+        }
+    }
+
+    pub fn serial_mut(&mut self) -> &mut Serial {
+        &mut self.memory.serial
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.memory.joypad.set_buttons(buttons)
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.save_container().to_bytes()
+    }
+
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let container = Container::from_bytes(state)?;
+        let backup = self.save_container();
+        let result = self.load_container(&container);
+        if result.is_err() {
+            self.load_container(&backup).unwrap()
+        }
+        result
+    }
+
+    pub fn enable_rewind(&mut self, budget: usize) {
+        self.rewind = Some(Rewind {
+            buffer: RewindBuffer::new(budget),
+            sections: Container::default(),
+            cartridge_revision: None,
+            state: Vec::new(),
+        })
+    }
+
+    pub fn rewind(&mut self, frames: usize) -> Result<usize, StateError> {
+        let buffer = match &self.rewind {
+            Some(rewind) => &rewind.buffer,
+            None => return Ok(0),
+        };
+        let frames = frames.min(buffer.len().saturating_sub(1));
+        // Newer states are only dropped once the target has loaded.
+        if let Some(state) = buffer.nth_last(frames) {
+            self.load_state(&state)?
+        }
+        self.rewind.as_mut().unwrap().buffer.discard(frames);
+        Ok(frames)
+    }
+
+    // Records the state for `rewind` and returns how many bytes of it were
+    // serialized anew.
+    fn push_rewind_state(&mut self) -> usize {
+        let mut rewind = match self.rewind.take() {
+            Some(rewind) => rewind,
+            None => return 0,
+        };
+        let revision = self.memory.cartridge.revision();
+        let cartridge_changed = rewind.cartridge_revision != Some(revision);
+        self.save_sections(&mut rewind.sections, cartridge_changed);
+        rewind.cartridge_revision = Some(revision);
+        rewind.sections.write_bytes(&mut rewind.state);
+        rewind.buffer.push(&rewind.state);
+        let kept = match rewind.sections.section(CARTRIDGE_SECTION) {
+            Some(section) if !cartridge_changed => section.len(),
+            _ => 0,
+        };
+        let serialized = rewind.state.len() - kept;
+        self.rewind = Some(rewind);
+        serialized
+    }
+
+    fn save_container(&self) -> Container {
+        let mut container = Container::default();
+        self.save_sections(&mut container, true);
+        container
+    }
+
+    // Leaves the cartridge section as it is unless `cartridge` is set.
+    fn save_sections(&self, container: &mut Container, cartridge: bool) {
+        container.set_section(MODEL_SECTION, vec![self.model.into()]);
+        container.set_section(ROM_SECTION, self.rom_identity());
+        container.save(CPU_SECTION, &self.cpu);
+        container.save(MEMORY_SECTION, &self.memory);
+        if cartridge {
+            container.save(CARTRIDGE_SECTION, &self.memory.cartridge)
+        }
+        container.save(PPU_SECTION, &self.memory.ppu);
+        container.save(APU_SECTION, &self.memory.apu);
+        container.save(TIMER_SECTION, &self.memory.timer);
+        container.save(SERIAL_SECTION, &self.memory.serial);
+        container.save(JOYPAD_SECTION, &self.memory.joypad);
+        container.save(HDMA_SECTION, &self.memory.hdma);
+        container.save(OAM_DMA_SECTION, &self.dma);
+        if let Some(sgb) = &self.memory.sgb {
+            container.save(SGB_SECTION, sgb)
+        }
+        let mut writer = StateWriter::default();
+        writer.bool(self.deferred_cpu_cycle.is_some());
+        writer.bool(self.deferred_cpu_cycle.unwrap_or(false));
+        container.set_section(SYSTEM_SECTION, writer.into_bytes())
+    }
+
+    fn rom_identity(&self) -> Vec<u8> {
+        let cartridge = &self.memory.cartridge;
+        let mut writer = StateWriter::default();
+        writer.u16(cartridge.global_checksum());
+        writer.bytes(cartridge.title().as_bytes());
+        writer.into_bytes()
+    }
+
+    fn load_container(&mut self, container: &Container) -> Result<(), StateError> {
+        match container.section(MODEL_SECTION) {
+            Some(&[model]) if model == u8::from(self.model) => (),
+            Some(&[model]) => return Err(StateError::InvalidValue("model", model.into())),
+            _ => return Err(StateError::MissingSection(MODEL_SECTION)),
+        }
+        // States written before the ROM section existed are trusted.
+        match container.section(ROM_SECTION) {
+            Some(rom) if rom != &self.rom_identity()[..] => return Err(StateError::RomMismatch),
+            _ => (),
+        }
+        container.load(CPU_SECTION, &mut self.cpu)?;
+        container.load(MEMORY_SECTION, &mut self.memory)?;
+        container.load(CARTRIDGE_SECTION, &mut self.memory.cartridge)?;
+        container.load(PPU_SECTION, &mut self.memory.ppu)?;
+        container.load(APU_SECTION, &mut self.memory.apu)?;
+        container.load(TIMER_SECTION, &mut self.memory.timer)?;
+        container.load(SERIAL_SECTION, &mut self.memory.serial)?;
+        container.load(JOYPAD_SECTION, &mut self.memory.joypad)?;
+        container.load(HDMA_SECTION, &mut self.memory.hdma)?;
+        container.load(OAM_DMA_SECTION, &mut self.dma)?;
+        if let Some(sgb) = &mut self.memory.sgb {
+            container.load(SGB_SECTION, sgb)?
+        }
+        // States written before the system section existed never split a step.
+        self.deferred_cpu_cycle = match container.section(SYSTEM_SECTION) {
+            Some(section) => {
+                let mut reader = StateReader::new(section);
+                let deferred = reader.bool()?;
+                let stalled = reader.bool()?;
+                if !reader.is_empty() {
+                    return Err(StateError::TrailingData);
+                }
+                if deferred {
+                    Some(stalled)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    pub fn start_trace(&mut self, writer: impl Write + 'static) {
+        self.trace = Some(DoctorTrace::new(writer))
+    }
+
+    pub fn start_trace_with_symbols(&mut self, writer: impl Write + 'static, symbols: Symbols) {
+        self.trace = Some(DoctorTrace::new(writer).with_symbols(symbols))
+    }
+
+    pub fn finish_trace(&mut self) -> io::Result<()> {
+        self.trace.take().map_or(Ok(()), DoctorTrace::finish)
+    }
+
+    pub fn start_bus_trace(&mut self, writer: impl Write + 'static) {
+        self.bus_trace = Some(BusTraceWriter::new(writer, &self.cpu))
+    }
+
+    pub fn finish_bus_trace(&mut self) -> io::Result<()> {
+        self.bus_trace.take().map_or(Ok(()), BusTraceWriter::finish)
+    }
+
+    pub fn start_profiling(&mut self) {
+        self.profiler = Some(Profiler::default())
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn finish_profiling(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.resampler = Resampler::new(sample_rate)
+    }
+
+    pub fn run_frame(&mut self) -> Frame<'_> {
+        for _ in 0..M_CYCLES_PER_FRAME {
+            let vblank = self.step();
+            // The second CPU cycle of a split step belongs to the same M-cycle.
+            if self.deferred_cpu_cycle.is_some() {
+                self.step();
+            }
+            if vblank {
+                break;
+            }
+        }
+        let available = 2 * self.resampler.available();
+        self.audio.resize(available, 0);
+        let len = self.resampler.fill_samples(&mut self.audio);
+        self.audio.truncate(len);
+        self.push_rewind_state();
+        Frame {
+            video: self.memory.ppu.framebuffer(),
+            rgb555: self.memory.ppu.rgb555_framebuffer(),
+            sgb: self.memory.sgb.as_ref().map(Sgb::screen),
+            audio: &self.audio,
+        }
+    }
+
+    // Bus activity the CPU emitted during the last `step`.
+    pub fn bus_activity(&self) -> &[BusActivity] {
+        &self.bus_activity
+    }
+
+    // Whether the last `step` ended with the CPU entering a new instruction.
+    pub fn reached_instruction_boundary(&self) -> bool {
+        self.at_boundary
+    }
+
+    // In double speed, end a `step` early when its first CPU cycle reaches an
+    // instruction boundary; the next `step` runs only the remaining CPU cycle.
+    pub fn set_split_at_boundary(&mut self, split: bool) {
+        self.split_at_boundary = split
+    }
+
+    pub fn step(&mut self) -> bool {
+        self.bus_activity.clear();
+        self.at_boundary = false;
+        if let Some(stalled) = self.deferred_cpu_cycle.take() {
+            self.cpu_cycle(stalled);
+            return false;
+        }
+        let mode = self.memory.ppu.mode();
+        let interrupts = self.memory.ppu.step();
+        if mode != Mode::HBlank && self.memory.ppu.mode() == Mode::HBlank {
+            self.memory.hdma.start_hblank_block()
+        }
+        self.memory.r#if |= interrupts;
+        if interrupts & interrupt::VBLANK != 0 {
+            if let Some(sgb) = &mut self.memory.sgb {
+                sgb.vblank(self.memory.ppu.framebuffer())
+            }
+        }
+        let stalled = self.memory.hdma.is_transferring();
+        self.memory.step_hdma();
+        self.step_apu();
+        let double_speed = self.memory.is_double_speed();
+        self.cpu_cycle(stalled);
+        if double_speed {
+            if self.split_at_boundary && self.at_boundary {
+                self.deferred_cpu_cycle = Some(stalled)
+            } else {
+                self.cpu_cycle(stalled)
+            }
+        }
+        interrupts & interrupt::VBLANK != 0
+    }
+
+    fn cpu_cycle(&mut self, stalled: bool) {
+        if stalled {
+            self.dma.cycle(None, &mut self.memory);
+            self.memory.r#if |= self.step_cpu_peripherals();
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record_cycle(&self.cpu, &self.memory, false)
+            }
+            return;
+        }
+        let output = self.step_cpu(None);
+        let data = self.dma.cycle(output.bus.as_ref(), &mut self.memory);
+        self.memory.r#if |= self.step_cpu_peripherals();
+        self.step_cpu(data);
+        self.at_boundary = self.cpu.status().at_instruction_boundary;
+        if let Some(trace) = &mut self.trace {
+            trace.record(&self.cpu, &self.memory)
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_cycle(&self.cpu, &self.memory, self.at_boundary)
+        }
+        if self.cpu.is_stopped() && self.memory.switch_speed() {
+            self.memory.timer.write(DIV, 0x00);
+            self.cpu.resume()
+        }
+    }
+
+    fn step_cpu(&mut self, data: Option<u8>) -> Output {
+        let input = Input {
+            data,
+            r#if: self.memory.r#if,
+        };
+        let output = self.cpu.step(&input);
+        if let Some(trace) = &mut self.bus_trace {
+            trace.record(&input, &output)
+        }
+        if let Some(bus) = &output.bus {
+            self.bus_activity.push(bus.clone())
+        }
+        self.memory.r#if &= !output.ack;
+        output
+    }
+
+    fn step_cpu_peripherals(&mut self) -> u8 {
+        let memory = &mut self.memory;
+        let mut interrupts = 0x00;
+        if memory.timer.step() {
+            interrupts |= interrupt::TIMER
+        }
+        let serial_div_bit = if memory.serial.is_fast_clock() {
+            FAST_SERIAL_DIV_BIT
+        } else {
+            SERIAL_DIV_BIT
+        };
+        if memory.serial.step(memory.timer.div() & serial_div_bit != 0) {
+            interrupts |= interrupt::SERIAL
+        }
+        if memory.joypad.take_interrupt() {
+            interrupts |= interrupt::JOYPAD
+        }
+        interrupts
+    }
+
+    fn step_apu(&mut self) {
+        let memory = &mut self.memory;
+        let div_bit = if memory.is_double_speed() {
+            DOUBLE_SPEED_APU_DIV_BIT
+        } else {
+            APU_DIV_BIT
+        };
+        for _ in 0..2 {
+            let amplitudes = memory.apu.step(memory.timer.div() & div_bit != 0);
+            self.resampler
+                .push(&amplitudes, memory.apu.nr50(), memory.apu.nr51())
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::cartridge::tests::rom;
+    use crate::cpu::asm::assemble;
+    use crate::cpu::bus_trace::BusTrace;
+    use crate::cpu::trace::tests::SharedBuffer;
+    use crate::hdma::{HDMA1, HDMA2, HDMA3, HDMA4, HDMA5};
+    use crate::joypad::P1;
+    use crate::memory::{Memory, BOOT, IF, KEY1};
+    use crate::ppu::{LCDC, LY, SCREEN_HEIGHT, SCREEN_WIDTH};
+    use crate::serial::Capture;
+    use crate::sgb::{SGB_SCREEN_HEIGHT, SGB_SCREEN_WIDTH};
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const JR_LOOP: [u8; 2] = [0x18, 0xfe];
+
+    pub(crate) fn rom_with_program(program: &[u8]) -> Vec<u8> {
+        let mut rom = rom(0x00, 2, 0);
+        rom[0x0100..0x0103].copy_from_slice(&[0xc3, 0x50, 0x01]);
+        rom[0x0150..0x0150 + program.len()].copy_from_slice(program);
+        rom
+    }
+
+    pub(crate) fn rom_with_source(source: &str) -> Vec<u8> {
+        rom_with_program(&assemble(source, 0x0150).unwrap())
+    }
+
+    pub(crate) fn cgb_rom(mut rom: Vec<u8>) -> Vec<u8> {
+        rom[0x0143] = 0x80;
+        rom
+    }
+
+    fn game_boy(program: &[u8]) -> GameBoy {
+        GameBoy::new(Cartridge::new(rom_with_program(program)).unwrap())
+    }
+
+    #[test]
+    fn frame_contains_screen_and_audio() {
+        let mut game_boy = game_boy(&[
+            0xaf, // XOR A
+            0xe0, 0x40, // LDH (LCDC), A
+            0x18, 0xfe, // JR -2
+        ]);
+        let frame = game_boy.run_frame();
+        assert_eq!(frame.video.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert_eq!(frame.rgb555.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert_eq!(frame.audio.len(), 2 * 803)
+    }
+
+    #[test]
+    fn program_stores_to_wram() {
+        let mut game_boy = game_boy(&[
+            0x3e, 0x42, // LD A, 0x42
+            0xea, 0x00, 0xc0, // LD (0xc000), A
+            0x18, 0xfe, // JR -2
+        ]);
+        game_boy.run_frame();
+        assert_eq!(game_boy.memory.read(0xc000), 0x42);
+        assert_eq!(game_boy.memory.read(0xe000), 0x42)
+    }
+
+    #[test]
+    fn timer_interrupt_wakes_cpu_from_halt() {
+        let mut rom = rom_with_program(&[
+            0x3e, 0x05, // LD A, 0x05
+            0xe0, 0x07, // LDH (TAC), A
+            0x3e, 0x04, // LD A, 0x04
+            0xea, 0xff, 0xff, // LD (IE), A
+            0x76, // HALT
+            0x18, 0xfe, // JR -2
+        ]);
+        rom[0x0050..0x0057].copy_from_slice(&[
+            0x3e, 0x99, // LD A, 0x99
+            0xea, 0x00, 0xc0, // LD (0xc000), A
+            0x18, 0xfe, // JR -2
+        ]);
+        let mut game_boy = GameBoy::new(Cartridge::new(rom).unwrap());
+        game_boy.cpu.data.ime = true;
+        game_boy.memory.r#if = 0x00;
+        game_boy.run_frame();
+        assert_eq!(game_boy.memory.read(0xc000), 0x99);
+        assert!(!game_boy.cpu.data.ime)
+    }
+
+    #[test]
+    fn frame_ends_at_vblank_when_lcd_is_on() {
+        let mut game_boy = game_boy(&JR_LOOP);
+        game_boy.run_frame();
+        assert_eq!(game_boy.memory.read(LY), SCREEN_HEIGHT as u8)
+    }
+
+    #[test]
+    fn serial_output_reaches_peer() {
+        let capture = Rc::new(RefCell::new(Capture::default()));
+        let mut game_boy = game_boy(&[
+            0x3e, b'H', // LD A, 'H'
+            0xe0, 0x01, // LDH (SB), A
+            0x3e, 0x81, // LD A, 0x81
+            0xe0, 0x02, // LDH (SC), A
+            0x18, 0xfe, // JR -2
+        ]);
+        game_boy.serial_mut().connect(Box::new(capture.clone()));
+        game_boy.run_frame();
+        assert_eq!(capture.borrow().bytes(), b"H");
+    }
+
+    #[test]
+    fn skipping_boot_initializes_registers() {
+        let mut rom = rom_with_program(&JR_LOOP);
+        rom[0x014d] = 0x42;
+        let mut game_boy = GameBoy::new(Cartridge::new(rom).unwrap());
+        let data = &game_boy.cpu.data;
+        assert_eq!(data.pc, 0x0100);
+        assert_eq!(data.sp, 0xfffe);
+        assert_eq!((data.a, u8::from(data.f)), (0x01, 0xb0));
+        assert_eq!((data.b, data.c, data.d, data.e), (0x00, 0x13, 0x00, 0xd8));
+        assert_eq!((data.h, data.l), (0x01, 0x4d));
+        assert_eq!(game_boy.memory.read(LCDC), 0x91);
+        assert_eq!(game_boy.memory.read(DIV), 0xab);
+        assert_eq!(game_boy.memory.read(IF), 0xe1);
+        assert_eq!(game_boy.memory.read(0xff26), 0xf1);
+        assert_eq!(game_boy.memory.read(0xff47), 0xfc);
+        assert!(!game_boy.memory.is_boot_rom_mapped())
+    }
+
+    #[test]
+    fn post_boot_accumulator_identifies_model() {
+        for &(model, a) in &[
+            (Model::Dmg0, 0x01),
+            (Model::Dmg, 0x01),
+            (Model::Mgb, 0xff),
+            (Model::Sgb, 0x01),
+            (Model::Sgb2, 0xff),
+            (Model::Cgb, 0x11),
+            (Model::Agb, 0x11),
+        ] {
+            let cartridge = Cartridge::new(rom_with_program(&JR_LOOP)).unwrap();
+            assert_eq!(GameBoy::with_model(cartridge, model).cpu.data.a, a)
+        }
+    }
+
+    #[test]
+    fn sgb_post_boot_state() {
+        let cartridge = Cartridge::new(rom_with_program(&JR_LOOP)).unwrap();
+        let game_boy = GameBoy::with_model(cartridge, Model::Sgb);
+        let data = &game_boy.cpu.data;
+        assert_eq!((data.a, u8::from(data.f)), (0x01, 0x00));
+        assert_eq!((data.b, data.c, data.d, data.e), (0x00, 0x14, 0x00, 0x00));
+        assert_eq!((data.h, data.l), (0xc0, 0x60));
+        assert!(game_boy.memory.sgb.is_some())
+    }
+
+    #[test]
+    fn model_is_detected_from_cgb_flag() {
+        let mut rom = rom_with_program(&JR_LOOP);
+        assert_eq!(
+            GameBoy::new(Cartridge::new(rom.clone()).unwrap()).model(),
+            Model::Dmg
+        );
+        rom[0x0143] = 0x80;
+        assert_eq!(
+            GameBoy::new(Cartridge::new(rom).unwrap()).model(),
+            Model::Cgb
+        )
+    }
+
+    #[test]
+    fn stop_with_armed_key1_switches_to_double_speed() {
+        let rom = rom_with_program(&[
+            0x3e, 0x01, // LD A, 0x01
+            0xe0, 0x4d, // LDH (KEY1), A
+            0x10, 0x00, // STOP
+            0x3e, 0x42, // LD A, 0x42
+            0xea, 0x00, 0xc0, // LD (0xc000), A
+            0x18, 0xfe, // JR -2
+        ]);
+        let mut game_boy = GameBoy::with_model(Cartridge::new(cgb_rom(rom)).unwrap(), Model::Cgb);
+        game_boy.run_frame();
+        assert!(game_boy.memory.is_double_speed());
+        assert_eq!(game_boy.memory.read(KEY1), 0xfe);
+        assert_eq!(game_boy.memory.read(0xc000), 0x42)
+    }
+
+    #[test]
+    fn double_speed_runs_two_cpu_cycles_per_step() {
+        let mut game_boy = GameBoy::with_model(
+            Cartridge::new(cgb_rom(rom_with_program(&JR_LOOP))).unwrap(),
+            Model::Cgb,
+        );
+        game_boy.memory.write(KEY1, 0x01);
+        game_boy.memory.switch_speed();
+        game_boy.memory.timer.write(DIV, 0x00);
+        for _ in 0..128 {
+            game_boy.step();
+        }
+        assert_eq!(game_boy.memory.read(DIV), 0x04)
+    }
+
+    fn double_speed_game_boy() -> GameBoy {
+        let mut game_boy = GameBoy::with_model(
+            Cartridge::new(cgb_rom(rom_with_program(&JR_LOOP))).unwrap(),
+            Model::Cgb,
+        );
+        game_boy.memory.write(KEY1, 0x01);
+        game_boy.memory.switch_speed();
+        game_boy
+    }
+
+    #[test]
+    fn split_steps_do_not_shorten_frames() {
+        let mut split = double_speed_game_boy();
+        split.set_split_at_boundary(true);
+        let mut whole = double_speed_game_boy();
+        for game_boy in &mut [&mut split, &mut whole] {
+            game_boy.memory.write(LCDC, 0x00)
+        }
+        split.run_frame();
+        whole.run_frame();
+        assert_eq!(split.save_state(), whole.save_state())
+    }
+
+    #[test]
+    fn save_state_keeps_deferred_cpu_cycle() {
+        let mut game_boy = double_speed_game_boy();
+        game_boy.set_split_at_boundary(true);
+        while game_boy.deferred_cpu_cycle.is_none() {
+            game_boy.step();
+        }
+        let state = game_boy.save_state();
+        let mut restored = double_speed_game_boy();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.deferred_cpu_cycle, Some(false));
+        game_boy.step();
+        restored.step();
+        assert_eq!(restored.save_state(), game_boy.save_state());
+        game_boy
+            .load_state(&double_speed_game_boy().save_state())
+            .unwrap();
+        assert_eq!(game_boy.deferred_cpu_cycle, None)
+    }
+
+    #[test]
+    fn general_purpose_dma_copies_to_vram_while_cpu_is_stalled() {
+        let mut game_boy = GameBoy::with_model(
+            Cartridge::new(cgb_rom(rom_with_program(&JR_LOOP))).unwrap(),
+            Model::Cgb,
+        );
+        game_boy.memory.write(LCDC, 0x00);
+        for i in 0..0x20 {
+            game_boy.memory.write(0xc000 + i, i as u8)
+        }
+        for &(addr, data) in &[(HDMA1, 0xc0), (HDMA2, 0x00), (HDMA3, 0x01), (HDMA4, 0x00)] {
+            game_boy.memory.write(addr, data)
+        }
+        game_boy.memory.write(HDMA5, 0x01);
+        let pc = game_boy.cpu.data.pc;
+        for _ in 0..16 {
+            game_boy.step();
+            assert_eq!(game_boy.cpu.data.pc, pc)
+        }
+        assert_eq!(game_boy.memory.read(HDMA5), 0xff);
+        assert_eq!(game_boy.memory.read(0x8100), 0x00);
+        assert_eq!(game_boy.memory.read(0x811f), 0x1f);
+        game_boy.step();
+        assert_ne!(game_boy.cpu.data.pc, pc)
+    }
+
+    #[test]
+    fn hblank_dma_copies_one_block_per_line() {
+        let mut game_boy = GameBoy::with_model(
+            Cartridge::new(cgb_rom(rom_with_program(&JR_LOOP))).unwrap(),
+            Model::Cgb,
+        );
+        game_boy.run_frame();
+        for i in 0..0x30 {
+            game_boy.memory.write(0xc000 + i, 0x80 | i as u8)
+        }
+        for &(addr, data) in &[(HDMA1, 0xc0), (HDMA2, 0x00), (HDMA3, 0x00), (HDMA4, 0x00)] {
+            game_boy.memory.write(addr, data)
+        }
+        game_boy.memory.write(HDMA5, 0x82);
+        while game_boy.memory.ppu.ly() != 1 {
+            game_boy.step();
+        }
+        assert_eq!(game_boy.memory.read(HDMA5), 0x01);
+        while game_boy.memory.read(HDMA5) != 0xff {
+            game_boy.step();
+        }
+        while game_boy.memory.ppu.mode() != Mode::OamScan {
+            game_boy.step();
+        }
+        assert_eq!(game_boy.memory.ppu.ly(), 3);
+        assert_eq!(game_boy.memory.read(0x802f), 0xaf)
+    }
+
+    #[test]
+    fn sgb_screen_is_output_when_enabled() {
+        let mut game_boy = game_boy(&JR_LOOP);
+        assert!(game_boy.run_frame().sgb.is_none());
+        let mut game_boy = GameBoy::with_model(
+            Cartridge::new(rom_with_program(&JR_LOOP)).unwrap(),
+            Model::Sgb,
+        );
+        let frame = game_boy.run_frame();
+        assert_eq!(
+            frame.sgb.unwrap().len(),
+            SGB_SCREEN_WIDTH * SGB_SCREEN_HEIGHT
+        )
+    }
+
+    #[test]
+    fn sgb_packets_are_sent_through_p1() {
+        let mut game_boy = GameBoy::with_model(
+            Cartridge::new(rom_with_program(&JR_LOOP)).unwrap(),
+            Model::Sgb2,
+        );
+        let mlt_req = [0x89, 0x01];
+        let bits = (0..128).map(|i| {
+            mlt_req
+                .get(i / 8)
+                .is_some_and(|byte| byte >> (i % 8) & 1 != 0)
+        });
+        game_boy.memory.write(P1, 0x00);
+        game_boy.memory.write(P1, 0x30);
+        for bit in bits.chain(std::iter::once(false)) {
+            game_boy.memory.write(P1, if bit { 0x10 } else { 0x20 });
+            game_boy.memory.write(P1, 0x30)
+        }
+        assert_eq!(game_boy.memory.read(P1), 0xff);
+        game_boy.memory.write(P1, 0x10);
+        game_boy.memory.write(P1, 0x30);
+        assert_eq!(game_boy.memory.read(P1), 0xfe)
+    }
+
+    #[test]
+    fn save_state_restores_emulation_exactly() {
+        let rom = cgb_rom(rom_with_source(
+            "
+            loop:
+                inc a
+                ldh [$ff42], a ; SCY
+                ld [$c000], a
+                jr loop
+            ",
+        ));
+        let mut game_boy = GameBoy::with_model(Cartridge::new(rom.clone()).unwrap(), Model::Cgb);
+        game_boy.run_frame();
+        for _ in 0..1234 {
+            game_boy.step();
+        }
+        let state = game_boy.save_state();
+        let expected = (0..3)
+            .map(|_| game_boy.run_frame().rgb555.to_vec())
+            .collect::<Vec<_>>();
+        let mut restored = GameBoy::with_model(Cartridge::new(rom).unwrap(), Model::Cgb);
+        restored.load_state(&state).unwrap();
+        let frames = (0..3)
+            .map(|_| restored.run_frame().rgb555.to_vec())
+            .collect::<Vec<_>>();
+        assert!(frames == expected);
+        assert_eq!(restored.cpu.save_state(), game_boy.cpu.save_state());
+        assert_eq!(restored.save_state(), game_boy.save_state())
+    }
+
+    #[test]
+    fn trace_logs_each_executed_instruction() {
+        let mut game_boy = game_boy(&[
+            0xaf, // XOR A
+            0x3c, // INC A
+            0x18, 0xfe, // JR -2
+        ]);
+        let buffer = SharedBuffer::default();
+        game_boy.start_trace(buffer.clone());
+        for _ in 0..12 {
+            game_boy.step();
+        }
+        game_boy.finish_trace().unwrap();
+        assert_eq!(
+            buffer.lines()[..5],
+            [
+                "A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:C3,50,01,00",
+                "A:01 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:AF,3C,18,FE",
+                "A:00 F:80 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0151 PCMEM:3C,18,FE,00",
+                "A:01 F:00 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:18,FE,00,00",
+                "A:01 F:00 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:18,FE,00,00",
+            ]
+        )
+    }
+
+    #[test]
+    fn trace_skips_the_nop_that_ends_interrupt_dispatch() {
+        let mut rom = rom_with_program(&[0x18, 0xfe]); // JR -2
+        rom[0x0040] = 0xc9; // RET
+        let mut game_boy = GameBoy::new(Cartridge::new(rom).unwrap());
+        game_boy.poke(IE, 0x01);
+        game_boy.poke(IF, 0x01);
+        game_boy.cpu_mut().data.ime = true;
+        let buffer = SharedBuffer::default();
+        game_boy.start_trace(buffer.clone());
+        for _ in 0..16 {
+            game_boy.step();
+        }
+        game_boy.finish_trace().unwrap();
+        let pcs: Vec<_> = buffer
+            .lines()
+            .iter()
+            .map(|line| line[line.find("PC:").unwrap()..][3..7].to_string())
+            .collect();
+        assert_eq!(pcs, ["0040", "0100", "0150"])
+    }
+
+    #[test]
+    fn bus_trace_of_a_frame_replays_on_a_fresh_cpu() {
+        let mut game_boy = GameBoy::new(
+            Cartridge::new(rom_with_source(
+                "
+            loop:
+                inc a
+                ld [$c000], a
+                jr loop
+            ",
+            ))
+            .unwrap(),
+        );
+        let buffer = SharedBuffer::default();
+        game_boy.start_bus_trace(buffer.clone());
+        game_boy.run_frame();
+        game_boy.finish_bus_trace().unwrap();
+        let trace = BusTrace::from_bytes(&buffer.bytes()).unwrap();
+        assert!(!trace.steps().is_empty());
+        assert_eq!(trace.replay(), Ok(()))
+    }
+
+    #[test]
+    fn peek_and_poke_reach_ie_and_rom() {
+        let mut game_boy = game_boy(&JR_LOOP);
+        game_boy.poke(0xffff, 0x05);
+        game_boy.poke(0x0150, 0x00);
+        assert_eq!(game_boy.cpu.data.ie, 0x05);
+        assert_eq!(game_boy.peek(0xffff), 0x05);
+        assert_eq!(game_boy.peek(0x0150), 0x00);
+        game_boy.poke(DMA, 0xc0);
+        game_boy.step();
+        game_boy.step();
+        assert_eq!(game_boy.peek(DMA), 0xc0);
+        assert!(!game_boy.dma.is_active())
+    }
+
+    #[test]
+    fn rewind_restores_earlier_frames() {
+        let rom = rom_with_source(
+            "
+            loop:
+                inc a
+                ld [$c000], a
+                jr loop
+            ",
+        );
+        let mut game_boy = GameBoy::new(Cartridge::new(rom).unwrap());
+        assert_eq!(game_boy.rewind(1), Ok(0));
+        game_boy.enable_rewind(1 << 20);
+        let mut values = Vec::new();
+        for _ in 0..100 {
+            game_boy.run_frame();
+            values.push(game_boy.memory.read(0xc000))
+        }
+        let used = game_boy.rewind.as_ref().unwrap().buffer.used();
+        assert!(used < 3 * game_boy.save_state().len());
+        assert_eq!(game_boy.rewind(10), Ok(10));
+        assert_eq!(game_boy.memory.read(0xc000), values[89]);
+        assert_eq!(game_boy.rewind(1000), Ok(89));
+        assert_eq!(game_boy.memory.read(0xc000), values[0]);
+        game_boy.run_frame();
+        assert_eq!(game_boy.memory.read(0xc000), values[1])
+    }
+
+    #[test]
+    fn failed_rewind_keeps_history() {
+        let mut game_boy = game_boy(&JR_LOOP);
+        game_boy.enable_rewind(1 << 20);
+        for _ in 0..5 {
+            game_boy.run_frame();
+        }
+        // The system section ends with a bool.
+        let mut corrupt = game_boy.save_state();
+        *corrupt.last_mut().unwrap() = 0x02;
+        game_boy.rewind.as_mut().unwrap().buffer.push(&corrupt);
+        game_boy.run_frame();
+        let state = game_boy.save_state();
+        assert_eq!(
+            game_boy.rewind(1),
+            Err(StateError::InvalidValue("bool", 0x02))
+        );
+        assert_eq!(game_boy.rewind.as_ref().unwrap().buffer.len(), 7);
+        assert!(game_boy.save_state() == state);
+        assert_eq!(game_boy.rewind(2), Ok(2))
+    }
+
+    #[test]
+    fn rewind_serializes_the_cartridge_only_after_it_changes() {
+        let mut rom = rom_with_program(&JR_LOOP);
+        rom[0x0147] = 0x03; // MBC1+RAM+BATTERY
+        rom[0x0149] = 0x03; // 32 KiB of RAM
+        let mut game_boy = GameBoy::new(Cartridge::new(rom).unwrap());
+        game_boy.enable_rewind(1 << 20);
+        let len = game_boy.save_state().len();
+        assert_eq!(game_boy.push_rewind_state(), len);
+        game_boy.run_frame();
+        assert!(game_boy.push_rewind_state() < len - 0x8000);
+        game_boy.poke(0xa000, 0x42);
+        assert_eq!(game_boy.push_rewind_state(), len)
+    }
+
+    #[test]
+    fn state_for_another_model_is_rejected() {
+        let state = game_boy(&JR_LOOP).save_state();
+        let mut game_boy = GameBoy::with_model(
+            Cartridge::new(cgb_rom(rom_with_program(&JR_LOOP))).unwrap(),
+            Model::Cgb,
+        );
+        game_boy.memory.write(0xc000, 0x42);
+        assert_eq!(
+            game_boy.load_state(&state),
+            Err(StateError::InvalidValue(
+                "model",
+                u8::from(Model::Dmg).into()
+            ))
+        );
+        assert_eq!(game_boy.memory.read(0xc000), 0x42)
+    }
+
+    #[test]
+    fn state_for_another_rom_is_rejected() {
+        let state = game_boy(&JR_LOOP).save_state();
+        let mut rom = rom_with_program(&JR_LOOP);
+        rom[0x014f] ^= 0x01;
+        let mut game_boy = GameBoy::new(Cartridge::new(rom).unwrap());
+        assert_eq!(game_boy.load_state(&state), Err(StateError::RomMismatch))
+    }
+
+    #[test]
+    fn boot_rom_is_unmapped_by_writing_boot_register() {
+        let rom = rom_with_program(&JR_LOOP);
+        let boot_rom = vec![
+            0x3e, 0x01, // LD A, 0x01
+            0xe0, 0x50, // LDH (BOOT), A
+        ];
+        let mut game_boy =
+            GameBoy::with_boot_rom(Cartridge::new(rom).unwrap(), Model::Dmg, boot_rom);
+        assert_eq!(game_boy.memory.read(0x0000), 0x3e);
+        while game_boy.memory.is_boot_rom_mapped() {
+            game_boy.step();
+        }
+        assert_eq!(game_boy.memory.read(0x0000), 0x00);
+        assert_eq!(game_boy.memory.read(0x0100), 0xc3);
+        game_boy.memory.write(BOOT, 0x00);
+        assert!(!game_boy.memory.is_boot_rom_mapped())
+    }
+}
@@ -0,0 +1,210 @@
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+pub const DIV: u16 = 0xff04;
+pub const TIMA: u16 = 0xff05;
+pub const TMA: u16 = 0xff06;
+pub const TAC: u16 = 0xff07;
+
+#[derive(Default)]
+pub struct Timer {
+    div: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reload: bool,
+}
+
+impl Timer {
+    pub fn with_div(div: u16) -> Self {
+        Self {
+            div,
+            ..Default::default()
+        }
+    }
+
+    pub fn div(&self) -> u16 {
+        self.div
+    }
+
+    pub fn step(&mut self) -> bool {
+        let interrupt = if self.reload {
+            self.reload = false;
+            self.tima = self.tma;
+            true
+        } else {
+            false
+        };
+        self.set_div(self.div.wrapping_add(4));
+        interrupt
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            DIV => (self.div >> 8) as u8,
+            TIMA => self.tima,
+            TMA => self.tma,
+            TAC => 0xf8 | self.tac,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            DIV => self.set_div(0),
+            TIMA => {
+                self.tima = data;
+                self.reload = false
+            }
+            TMA => self.tma = data,
+            TAC => {
+                let input = self.input();
+                self.tac = data & 0x07;
+                if input && !self.input() {
+                    self.increment()
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Like `write`, but DIV takes the value instead of resetting, and TIMA is
+    // never clocked nor its pending reload cancelled.
+    pub fn poke(&mut self, addr: u16, data: u8) {
+        match addr {
+            DIV => self.div = self.div & 0x00ff | u16::from(data) << 8,
+            TIMA => self.tima = data,
+            TAC => self.tac = data & 0x07,
+            _ => self.write(addr, data),
+        }
+    }
+
+    fn set_div(&mut self, div: u16) {
+        let input = self.input();
+        self.div = div;
+        if input && !self.input() {
+            self.increment()
+        }
+    }
+
+    fn input(&self) -> bool {
+        let bit = match self.tac & 0x03 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            0b11 => 7,
+            _ => unreachable!(),
+        };
+        self.tac & 0x04 != 0 && self.div & 1 << bit != 0
+    }
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.reload = overflow
+    }
+}
+
+impl SaveState for Timer {
+    fn save(&self, writer: &mut StateWriter) {
+        writer.u16(self.div);
+        writer.u8(self.tima);
+        writer.u8(self.tma);
+        writer.u8(self.tac);
+        writer.bool(self.reload)
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.div = reader.u16()?;
+        self.tima = reader.u8()?;
+        self.tma = reader.u8()?;
+        self.tac = reader.u8()?;
+        self.reload = reader.bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Timer {
+        fn run(&mut self, m_cycles: usize) -> usize {
+            (0..m_cycles).filter(|_| self.step()).count()
+        }
+    }
+
+    #[test]
+    fn div_increments_every_64_m_cycles() {
+        let mut timer = Timer::default();
+        timer.run(63);
+        assert_eq!(timer.read(DIV), 0x00);
+        timer.run(1);
+        assert_eq!(timer.read(DIV), 0x01)
+    }
+
+    #[test]
+    fn writing_div_resets_it() {
+        let mut timer = Timer::default();
+        timer.run(1000);
+        timer.write(DIV, 0x42);
+        assert_eq!(timer.div(), 0x0000)
+    }
+
+    #[test]
+    fn disabled_timer_does_not_increment() {
+        let mut timer = Timer::default();
+        timer.write(TAC, 0x01);
+        timer.run(1000);
+        assert_eq!(timer.read(TIMA), 0x00)
+    }
+
+    #[test]
+    fn tima_increments_at_selected_rate() {
+        for &(tac, m_cycles) in &[(0x04, 256), (0x05, 4), (0x06, 16), (0x07, 64)] {
+            let mut timer = Timer::default();
+            timer.write(TAC, tac);
+            timer.run(10 * m_cycles);
+            assert_eq!(timer.read(TIMA), 10)
+        }
+    }
+
+    #[test]
+    fn overflow_reloads_tma_one_cycle_later_and_requests_interrupt() {
+        let mut timer = Timer::default();
+        timer.write(TMA, 0xab);
+        timer.write(TIMA, 0xff);
+        timer.write(TAC, 0x05);
+        timer.run(4);
+        assert_eq!(timer.read(TIMA), 0x00);
+        assert!(timer.step());
+        assert_eq!(timer.read(TIMA), 0xab)
+    }
+
+    #[test]
+    fn writing_tima_during_reload_delay_cancels_reload() {
+        let mut timer = Timer::default();
+        timer.write(TMA, 0xab);
+        timer.write(TIMA, 0xff);
+        timer.write(TAC, 0x05);
+        timer.run(4);
+        timer.write(TIMA, 0x12);
+        assert!(!timer.step());
+        assert_eq!(timer.read(TIMA), 0x12)
+    }
+
+    #[test]
+    fn resetting_div_on_falling_edge_increments_tima() {
+        let mut timer = Timer::default();
+        timer.write(TAC, 0x05);
+        timer.run(2);
+        timer.write(DIV, 0x00);
+        assert_eq!(timer.read(TIMA), 0x01)
+    }
+
+    #[test]
+    fn tac_reads_unused_bits_as_set() {
+        let mut timer = Timer::default();
+        timer.write(TAC, 0x05);
+        assert_eq!(timer.read(TAC), 0xfd)
+    }
+}
@@ -0,0 +1,169 @@
+use gbemu_core::cartridge::Cartridge;
+use gbemu_core::cpu::asm::assemble;
+use gbemu_core::debugger::Debugger;
+use gbemu_core::gdb::{Connection, GdbServer, Stdio};
+use gbemu_core::GameBoy;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+const PROGRAM: &str = "
+        ld hl, $c000
+    loop:
+        inc a
+        ld [hl], a
+        jr loop
+";
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    fn send(&mut self, packet: &str) -> String {
+        let checksum = packet.bytes().fold(0u8, u8::wrapping_add);
+        write!(self.stream, "${}#{:02x}", packet, checksum).unwrap();
+        assert_eq!(self.read_byte(), b'+');
+        self.receive()
+    }
+
+    fn receive(&mut self) -> String {
+        assert_eq!(self.read_byte(), b'$');
+        let mut packet = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => packet.push(byte),
+            }
+        }
+        let mut checksum = [0x00; 2];
+        self.stream.read_exact(&mut checksum).unwrap();
+        let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+        assert_eq!(
+            packet.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)),
+            checksum
+        );
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(packet).unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0x00];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+}
+
+fn session(script: impl FnOnce(&mut Client) + Send + 'static) -> GdbServer {
+    session_with(PROGRAM, script)
+}
+
+fn session_with(source: &str, script: impl FnOnce(&mut Client) + Send + 'static) -> GdbServer {
+    let mut rom = vec![0x00; 0x8000];
+    let program = assemble(source, 0x0150).unwrap();
+    rom[0x0100..0x0103].copy_from_slice(&[0xc3, 0x50, 0x01]);
+    rom[0x0150..0x0150 + program.len()].copy_from_slice(&program);
+    let game_boy = GameBoy::new(Cartridge::new(rom).unwrap());
+    let mut server = GdbServer::new(Debugger::new(game_boy));
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let client = thread::spawn(move || {
+        let mut client = Client {
+            stream: TcpStream::connect(addr).unwrap(),
+        };
+        script(&mut client);
+        assert_eq!(client.send("D"), "OK")
+    });
+    let (mut stream, _) = listener.accept().unwrap();
+    server.serve(&mut stream).unwrap();
+    client.join().unwrap();
+    server
+}
+
+#[test]
+fn client_queries_target_and_registers() {
+    session(|client| {
+        assert!(client
+            .send("qSupported:swbreak+")
+            .contains("qXfer:features:read+"));
+        assert_eq!(client.send("?"), "S05");
+        let xml = client.send("qXfer:features:read:target.xml:0,1000");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("name=\"pc\""));
+        let tail = client.send("qXfer:features:read:target.xml:10,ffffffffffffffff");
+        assert!(tail.starts_with('l') && xml.ends_with(&tail[1..]));
+        assert_eq!(client.send("vMustReplyEmpty"), "");
+        assert_eq!(client.send("p4"), "feff");
+        assert_eq!(client.send("p9"), "E01")
+    });
+}
+
+#[test]
+fn breakpoint_and_single_step() {
+    session(|client| {
+        assert_eq!(client.send("Z0,153,1"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p5"), "5301");
+        assert_eq!(client.send("p3"), "00c0");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p5"), "5401");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(&client.send("g")[..4], "0002");
+        assert_eq!(client.send("z0,153,1"), "OK");
+        assert_eq!(client.send("z0,153,1"), "E01")
+    });
+}
+
+#[test]
+fn memory_and_register_writes() {
+    let server = session(|client| {
+        assert_eq!(client.send("m150,3"), "2100c0");
+        assert_eq!(client.send("Mc100,2:abcd"), "OK");
+        assert_eq!(client.send("mc100,2"), "abcd");
+        assert_eq!(client.send("M4000,1:99"), "OK");
+        assert_eq!(client.send("m3fff,2"), "0099");
+        assert_eq!(client.send("P0=8042"), "OK");
+        assert_eq!(client.send("P3=00c0"), "OK");
+        assert_eq!(client.send("P5=5301"), "OK");
+        assert_eq!(client.send("p5"), "5301");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p0"), "0043");
+        assert_eq!(client.send("Z2,c000,1"), "OK");
+        assert_eq!(client.send("c"), "T05watch:c000;");
+        assert_eq!(client.send("p5"), "5501")
+    });
+    assert_eq!(server.debugger().game_boy().cpu().data.a, 0x43)
+}
+
+#[test]
+fn access_watchpoint_is_reported_as_awatch() {
+    session(|client| {
+        assert_eq!(client.send("Z4,c000,1"), "OK");
+        assert_eq!(client.send("c"), "T05awatch:c000;")
+    });
+}
+
+#[test]
+fn ctrl_c_interrupts_halted_cpu() {
+    // With IE clear, nothing ever wakes the CPU from HALT.
+    session_with("halt", |client| {
+        assert_eq!(client.send("Z0,150,1"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        for command in &["s", "c"] {
+            let checksum = command.bytes().fold(0u8, u8::wrapping_add);
+            write!(client.stream, "${}#{:02x}", command, checksum).unwrap();
+            assert_eq!(client.read_byte(), b'+');
+            client.stream.write_all(&[0x03]).unwrap();
+            assert_eq!(client.receive(), "S02")
+        }
+    });
+}
+
+#[test]
+fn stdio_run_stops_as_if_interrupted() {
+    let mut stdio = Stdio::default();
+    let polls = std::iter::repeat_with(|| stdio.poll_interrupt().unwrap())
+        .position(|interrupted| interrupted);
+    assert_eq!(polls, Some(599))
+}